@@ -1,24 +1,313 @@
-use crate::zfs::SnapshotMetadata;
-use std::collections::HashSet;
+//! The retention engine behind zfs-autosnap, and the zfs(8) plumbing it runs on.
+//!
+//! What's meant for use outside of the command line tool:
+//!
+//! - [`RetentionPolicy`] (parsed from a snapkeep value) and [`evaluate`], which decide
+//!   which snapshots to keep, as an [`AgeCheckResult`];
+//! - [`SnapshotMetadata`], the snapshots these work on;
+//! - [`Properties`] and the `PROPERTY_*` names, for the properties we look at;
+//! - the [`zfs`] module, which runs zfs commands through a [`Runner`];
+//! - the parsers for the property values and command line options (sizes, durations,
+//!   UTC offsets, anchors).
+//!
+//! The [`prelude`] has the most commonly used of these, for a single glob import.
+
+use byte_unit::Byte;
+use chrono::{DateTime, Datelike, Duration, FixedOffset, TimeZone, Timelike, Utc};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
+use std::fmt;
 use std::str::FromStr;
 
 pub mod zfs;
 
+pub use crate::zfs::{Runner, SnapshotMetadata};
+
+// Helpers for testing the retention engine from outside the crate; with the testing
+// feature only, since these aren't part of the stable API.
+#[cfg(feature = "testing")]
+pub mod testing {
+    use crate::zfs::{self, SnapshotMetadata};
+    use crate::{Result, RetentionPolicy};
+    use byte_unit::Byte;
+    use chrono::{DateTime, Duration, Utc};
+
+    // Parse zfs list -Hp -t snapshot -o name,creation,used,<snapkeep>[,<protect>] output,
+    // as list_snapshots does.
+    pub fn parse_snapshots(text: &str, lenient_used: bool) -> Result<Vec<SnapshotMetadata>> {
+        zfs::parse_snapshots(zfs::parse_table(text), lenient_used)
+    }
+
+    // Parse a size, as zfs list shows it (with or without -p).
+    pub fn parse_used(x: &str) -> Result<Byte> {
+        zfs::parse_used(x)
+    }
+
+    // The policy's rules: a strftime(3) pattern for each period (hourly first), and how
+    // many of those periods to keep.
+    pub fn rules(policy: &RetentionPolicy) -> Vec<(String, Option<u32>)> {
+        policy
+            .rules()
+            .iter()
+            .map(|(pattern, count)| (pattern.to_string(), *count))
+            .collect()
+    }
+
+    // Fixtures: count snapshots of the dataset (named dataset@0, @1, ...), one every
+    // interval, oldest (at start) first.
+    pub fn snapshots_every(
+        dataset: &str,
+        start: DateTime<Utc>,
+        interval: Duration,
+        count: i32,
+    ) -> Vec<SnapshotMetadata> {
+        (0..count)
+            .map(|i| {
+                let created = start + interval * i;
+                SnapshotMetadata::new(&format!("{}@{}", dataset, i), created, 0)
+            })
+            .collect()
+    }
+}
+
+// use zfs_autosnap::prelude::*;
+pub mod prelude {
+    pub use crate::zfs::{Runner, SnapshotMetadata};
+    pub use crate::{evaluate, AgeCheckResult, Properties, Result, RetentionPolicy, WeekStart};
+}
+
+// All of our properties live in this namespace (the part before the colon); see
+// Properties for using a different one.
+pub const DEFAULT_NAMESPACE: &str = "at.rollc.at";
+
 // We use this property to control the retention policy.  Check readme.md, but also
 // check_age, ZFS::list_snapshots, and ZFS::list_datasets_for_snapshot.
 pub const PROPERTY_SNAPKEEP: &str = "at.rollc.at:snapkeep";
 
+// Whether a snapkeep value means "not managed": unset (which zfs shows as "-"), or set
+// explicitly to "off" (or nothing), e.g. on a child of a managed dataset.
+pub fn is_unmanaged(snapkeep: &str) -> bool {
+    matches!(snapkeep, "-" | "off" | "")
+}
+
+// Optional per-dataset UTC offset (e.g. "+02:00") used to decide where days, weeks,
+// etc. begin and end for the purposes of the retention policy. Defaults to UTC.
+pub const PROPERTY_TZ: &str = "at.rollc.at:tz";
+
+// Set to "on" to temporarily stop taking new snapshots of a dataset, while still managing
+// (i.e. collecting) its existing ones.
+pub const PROPERTY_SNAPDISABLE: &str = "at.rollc.at:snapdisable";
+
+// Set (e.g. to "on") on a snapshot that we should never touch, e.g. because some other
+// tool manages it. Like snapkeep=-, but without overloading snapkeep.
+pub const PROPERTY_PROTECT: &str = "at.rollc.at:protect";
+
+// How often to snapshot a dataset (e.g. "1d"), for datasets that need fewer snapshots than
+// snap is run for.
+pub const PROPERTY_SNAPEVERY: &str = "at.rollc.at:snapevery";
+
+// Free-form text about a snapshot, e.g. "pre kernel upgrade"; see snap --comment.
+pub const PROPERTY_COMMENT: &str = "at.rollc.at:comment";
+
+// What occasion a snapshot was taken for (e.g. "pre-deploy"), see snap --type; gc keeps
+// the types given to --keep-type.
+pub const PROPERTY_SNAPTYPE: &str = "at.rollc.at:snaptype";
+
+// How old a dataset's snapshots may get (e.g. "7y"), whatever its policy would keep.
+pub const PROPERTY_MAXAGE: &str = "at.rollc.at:maxage";
+
 pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
+// The names of the properties we look at, as above, but possibly in a different namespace
+// (e.g. "com.example:snapkeep"), for forks or for running several installations side by side.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Properties {
+    pub snapkeep: String,
+    pub tz: String,
+    pub snapdisable: String,
+    pub protect: String,
+    pub comment: String,
+    pub snapevery: String,
+    pub snaptype: String,
+    pub maxage: String,
+}
+
+impl Properties {
+    pub fn new(namespace: &str) -> Result<Self> {
+        // See "User Properties" in zfsprops(7).
+        let valid =
+            |ch: char| ch.is_ascii_lowercase() || ch.is_ascii_digit() || "+._-".contains(ch);
+        if namespace.is_empty() || !namespace.chars().all(valid) {
+            return Err(format!("invalid property namespace '{}'", namespace).into());
+        }
+        Ok(Properties {
+            snapkeep: format!("{}:snapkeep", namespace),
+            tz: format!("{}:tz", namespace),
+            snapdisable: format!("{}:snapdisable", namespace),
+            protect: format!("{}:protect", namespace),
+            comment: format!("{}:comment", namespace),
+            snapevery: format!("{}:snapevery", namespace),
+            snaptype: format!("{}:snaptype", namespace),
+            maxage: format!("{}:maxage", namespace),
+        })
+    }
+}
+
+impl Default for Properties {
+    fn default() -> Self {
+        Properties {
+            snapkeep: PROPERTY_SNAPKEEP.to_string(),
+            tz: PROPERTY_TZ.to_string(),
+            snapdisable: PROPERTY_SNAPDISABLE.to_string(),
+            protect: PROPERTY_PROTECT.to_string(),
+            comment: PROPERTY_COMMENT.to_string(),
+            snapevery: PROPERTY_SNAPEVERY.to_string(),
+            snaptype: PROPERTY_SNAPTYPE.to_string(),
+            maxage: PROPERTY_MAXAGE.to_string(),
+        }
+    }
+}
+
+// Named policies, for use as e.g. at.rollc.at:snapkeep=@daily-backup.
+const PRESETS: &[(&str, &str)] = &[
+    ("default", "h24d30w8m6y1"),
+    ("hourly-workstation", "h48d14w4m3"),
+    ("daily-backup", "d30w8m12y3"),
+    ("archive", "m24y10"),
+];
+
 // Describes the number of snapshots to keep for each period.
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
 pub struct RetentionPolicy {
     pub yearly: Option<i32>,
     pub monthly: Option<u32>,
     pub weekly: Option<u32>,
     pub daily: Option<u32>,
     pub hourly: Option<u32>,
+    // Hard cap on the total number of snapshots kept, regardless of period.
+    pub max: Option<u32>,
+    // Which day the weekly periods begin with.
+    pub week_start: WeekStart,
+    // For each of these points in time (e.g. system boots), also keep the newest snapshot
+    // taken before it.
+    pub anchors: Vec<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum WeekStart {
+    // ISO 8601 weeks.
+    #[default]
+    Monday,
+    Sunday,
+}
+
+impl FromStr for WeekStart {
+    type Err = String;
+
+    fn from_str(x: &str) -> std::result::Result<Self, Self::Err> {
+        match x.to_ascii_lowercase().as_str() {
+            "mon" | "monday" => Ok(WeekStart::Monday),
+            "sun" | "sunday" => Ok(WeekStart::Sunday),
+            _ => Err(format!("invalid start of the week '{}'", x)),
+        }
+    }
+}
+
+// One of a policy's periods, shortest first; for gc --only.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Period {
+    Hourly,
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+impl FromStr for Period {
+    type Err = String;
+
+    fn from_str(x: &str) -> std::result::Result<Self, Self::Err> {
+        match x {
+            "hourly" => Ok(Period::Hourly),
+            "daily" => Ok(Period::Daily),
+            "weekly" => Ok(Period::Weekly),
+            "monthly" => Ok(Period::Monthly),
+            "yearly" => Ok(Period::Yearly),
+            _ => Err(format!("invalid period '{}'", x)),
+        }
+    }
+}
+
+impl Period {
+    // The date format pattern (see strftime(3)) that tells the periods apart, e.g. the
+    // year and month for monthly; see RetentionPolicy.rules().
+    fn pattern(self, week_start: WeekStart) -> &'static str {
+        match self {
+            Period::Hourly => "%Y-%m-%d %H",
+            Period::Daily => "%Y-%m-%d",
+            Period::Weekly => match week_start {
+                // ISO week-based year and week number; weeks begin on Monday.
+                WeekStart::Monday => "%G-W%V",
+                // Weeks begin on Sunday; see period_of.
+                WeekStart::Sunday => SUNDAY_WEEKS,
+            },
+            Period::Monthly => "%Y-%m",
+            Period::Yearly => "%Y",
+        }
+    }
+
+    // Which of these periods (e.g. 2021-W39) a point in time falls in.
+    fn of(self, t: DateTime<Utc>, week_start: WeekStart, tz: &FixedOffset) -> String {
+        period_of(&t.with_timezone(tz), self.pattern(week_start))
+    }
+}
+
+// Weeks that begin on Sunday go by the date of their Sunday; %U would number the days
+// before a year's first Sunday as week 00, splitting the week that spans New Year in two.
+const SUNDAY_WEEKS: &str = "%Y-%m-%d (Sunday)";
+
+// Which period t falls in, with one of the patterns from Period.pattern().
+fn period_of(t: &DateTime<FixedOffset>, pattern: &str) -> String {
+    match pattern {
+        SUNDAY_WEEKS => {
+            let date = t.naive_local().date();
+            let sunday = date - Duration::days(t.weekday().num_days_from_sunday() as i64);
+            sunday.format(pattern).to_string()
+        }
+        _ => t.format(pattern).to_string(),
+    }
+}
+
+// For each of the periods that are over by now, the snapshot that stands for it, as
+// check_age would keep for it: the newest one. Periods that already have one of the pinned
+// snapshots in them are left out, so pinning the result again and again adds nothing.
+// Oldest first.
+pub fn pin_candidates(
+    period: Period,
+    snapshots: &[SnapshotMetadata],
+    pinned: &[SnapshotMetadata],
+    now: DateTime<Utc>,
+    week_start: WeekStart,
+    tz: &FixedOffset,
+) -> Vec<SnapshotMetadata> {
+    let of = |t| period.of(t, week_start, tz);
+    let mut done: HashSet<String> = pinned.iter().map(|s| of(s.created)).collect();
+    done.insert(of(now));
+    let mut newest = BTreeMap::<String, &SnapshotMetadata>::new();
+    for s in snapshots {
+        let key = of(s.created);
+        if done.contains(&key) {
+            continue;
+        }
+        let entry = newest.entry(key).or_insert(s);
+        if newest_first(s, entry) == Ordering::Less {
+            *entry = s;
+        }
+    }
+    let mut candidates: Vec<SnapshotMetadata> = newest.into_values().cloned().collect();
+    candidates.sort_by(|a, b| newest_first(b, a));
+    candidates
 }
 
 #[derive(Debug)]
@@ -27,15 +316,79 @@ pub struct AgeCheckResult {
     pub delete: Vec<SnapshotMetadata>,
 }
 
+impl AgeCheckResult {
+    // Make sure the newest snapshot is kept, whatever the policy says; e.g. as a safety net
+    // against a policy that would leave a dataset with no snapshots at all.
+    pub fn keep_latest(&mut self) {
+        let newest_kept = self.keep.iter().map(|s| s.created).max();
+        let newest_deleted = (0..self.delete.len()).max_by_key(|&i| self.delete[i].created);
+        if let Some(i) = newest_deleted {
+            if newest_kept.is_none_or(|created| created < self.delete[i].created) {
+                let snapshot = self.delete.remove(i);
+                self.keep.insert(0, snapshot);
+            }
+        }
+    }
+
+    // Delete the kept snapshots created before the cutoff, whatever the policy says (see
+    // PROPERTY_MAXAGE). Returns the names of those.
+    pub fn delete_older_than(&mut self, cutoff: DateTime<Utc>) -> Vec<String> {
+        let (expired, keep): (Vec<_>, Vec<_>) = std::mem::take(&mut self.keep)
+            .into_iter()
+            .partition(|s| s.created < cutoff);
+        self.keep = keep;
+        let names = expired.iter().map(|s| s.name.clone()).collect();
+        if !expired.is_empty() {
+            self.delete.extend(expired);
+            self.delete.sort_unstable_by(newest_first);
+        }
+        names
+    }
+
+    // Keep the to-be-deleted snapshots taken for one of these types (gc --keep-type), as
+    // found in types (snapshot name to its snaptype). Returns the names of those.
+    pub fn keep_types(&mut self, types: &HashMap<String, String>, keep: &[String]) -> Vec<String> {
+        self.keep_matching(|s| types.get(&s.name).is_some_and(|t| keep.contains(t)))
+    }
+
+    // Keep the to-be-deleted snapshots that match, whatever the policy says, e.g. because
+    // they can't be destroyed anyway. Returns the names of those.
+    pub fn keep_matching<P>(&mut self, mut matches: P) -> Vec<String>
+    where
+        P: FnMut(&SnapshotMetadata) -> bool,
+    {
+        let (rescued, delete): (Vec<_>, Vec<_>) = std::mem::take(&mut self.delete)
+            .into_iter()
+            .partition(|s| matches(s));
+        self.delete = delete;
+        let names = rescued.iter().map(|s| s.name.clone()).collect();
+        if !rescued.is_empty() {
+            self.keep.extend(rescued);
+            self.keep.sort_unstable_by(newest_first);
+        }
+        names
+    }
+}
+
+// How switching from one policy to another would change the fate of some snapshots.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PolicyDiff {
+    // Kept by the old policy, deleted by the new one.
+    pub now_deleted: Vec<SnapshotMetadata>,
+    // Deleted by the old policy, kept by the new one.
+    pub now_kept: Vec<SnapshotMetadata>,
+}
+
 impl RetentionPolicy {
     fn rules(&self) -> [(&str, Option<u32>); 5] {
+        let pattern = |period: Period| period.pattern(self.week_start);
         [
-            ("%Y-%m-%d %H", self.hourly),
-            ("%Y-%m-%d", self.daily),
-            ("%Y w%w", self.weekly),
-            ("%Y-%m", self.monthly),
+            (pattern(Period::Hourly), self.hourly),
+            (pattern(Period::Daily), self.daily),
+            (pattern(Period::Weekly), self.weekly),
+            (pattern(Period::Monthly), self.monthly),
             (
-                "%Y",
+                pattern(Period::Yearly),
                 // NOTE: chrono keeps years as i32 (signed); however there were no ZFS
                 // deployments before ca (+)2006, so I guess it's safe to cast to u32.
                 self.yearly.map(|y| y as u32),
@@ -44,26 +397,288 @@ impl RetentionPolicy {
     }
 
     pub fn check_age(&self, snapshots: &mut [SnapshotMetadata]) -> AgeCheckResult {
-        let mut to_keep = HashSet::<&SnapshotMetadata>::new();
-        // Sort newest snapshots first, so when we consider which ones to retain, the oldest
+        self.check_age_in(snapshots, &utc())
+    }
+
+    // Like check_age, but the periods (days, weeks, etc) are in the given timezone.
+    pub fn check_age_in(
+        &self,
+        snapshots: &mut [SnapshotMetadata],
+        tz: &FixedOffset,
+    ) -> AgeCheckResult {
+        // Sort newest snapshots first, so the result comes out in the same order plan() uses.
+        // They usually are already (see zfs::list_snapshots), which the sort is quick to
+        // notice; but not always, e.g. when read from a saved listing.
+        snapshots.sort_unstable_by(newest_first);
+        let (keep, delete) = self.plan_in(snapshots, tz);
+        AgeCheckResult {
+            keep: keep.into_iter().map(|i| snapshots[i].clone()).collect(),
+            delete: delete.into_iter().map(|i| snapshots[i].clone()).collect(),
+        }
+    }
+
+    // Decide which snapshots to keep and which to delete, without reordering or cloning
+    // anything; returns indices into snapshots (newest first) as (keep, delete).
+    pub fn plan(&self, snapshots: &[SnapshotMetadata]) -> (Vec<usize>, Vec<usize>) {
+        self.plan_in(snapshots, &utc())
+    }
+
+    // Like plan, but the periods (days, weeks, etc) are in the given timezone.
+    pub fn plan_in(
+        &self,
+        snapshots: &[SnapshotMetadata],
+        tz: &FixedOffset,
+    ) -> (Vec<usize>, Vec<usize>) {
+        // Visit newest snapshots first, so when we consider which ones to retain, the oldest
         // come last (and fall off the keep-set).
-        snapshots.sort_unstable_by_key(|s| -s.created.timestamp());
-        'next_rule: for (pattern, rule) in self.rules() {
+        let mut order: Vec<usize> = (0..snapshots.len()).collect();
+        order.sort_unstable_by(|&a, &b| newest_first(&snapshots[a], &snapshots[b]));
+        let mut to_keep: HashSet<usize> = self
+            .kept_by_rules(snapshots, &order, tz)
+            .into_iter()
+            .map(|(_, i)| i)
+            .collect();
+
+        if let Some(max) = self.max {
+            // Final pass: out of everything the rules wanted to keep, retain only the
+            // newest ones, up to the cap.
+            let mut kept = 0;
+            for i in order.iter() {
+                if to_keep.contains(i) {
+                    if kept < max {
+                        kept += 1;
+                    } else {
+                        to_keep.remove(i);
+                    }
+                }
+            }
+        }
+
+        for anchor in self.anchors.iter() {
+            // Not subject to the cap: these are kept on top of whatever the rules say.
+            if let Some(&i) = order.iter().find(|&&i| snapshots[i].created < *anchor) {
+                to_keep.insert(i);
+            }
+        }
+
+        order.into_iter().partition(|i| to_keep.contains(i))
+    }
+
+    // Like check_age_in, but a snapshot may also carry its own policy (overrides has one
+    // entry per snapshot), which adds to this one: the snapshot is also kept if its own
+    // policy keeps it, among the snapshots that carry the same policy. E.g. a single
+    // snapshot marked "y1" stays around until another one marked "y1" comes a year later.
+    pub fn check_age_with_overrides(
+        &self,
+        snapshots: &[SnapshotMetadata],
+        overrides: &[Option<RetentionPolicy>],
+        tz: &FixedOffset,
+    ) -> AgeCheckResult {
+        let (keep, _) = self.plan_in(snapshots, tz);
+        let mut to_keep: HashSet<usize> = keep.into_iter().collect();
+        let mut by_policy = HashMap::<&RetentionPolicy, Vec<usize>>::new();
+        for (i, policy) in overrides.iter().enumerate() {
+            if let Some(policy) = policy {
+                by_policy.entry(policy).or_default().push(i);
+            }
+        }
+        for (policy, indices) in by_policy {
+            let subset: Vec<SnapshotMetadata> =
+                indices.iter().map(|&i| snapshots[i].clone()).collect();
+            to_keep.extend(
+                policy
+                    .plan_in(&subset, tz)
+                    .0
+                    .into_iter()
+                    .map(|j| indices[j]),
+            );
+        }
+        let mut order: Vec<usize> = (0..snapshots.len()).collect();
+        order.sort_unstable_by(|&a, &b| newest_first(&snapshots[a], &snapshots[b]));
+        let (keep, delete): (Vec<usize>, Vec<usize>) =
+            order.into_iter().partition(|i| to_keep.contains(i));
+        AgeCheckResult {
+            keep: keep.into_iter().map(|i| snapshots[i].clone()).collect(),
+            delete: delete.into_iter().map(|i| snapshots[i].clone()).collect(),
+        }
+    }
+
+    // Narrow down what check_age would delete to what falls out of one period's rule, so
+    // that e.g. the hourly snapshots can be pruned more often than the rest. Each period
+    // reaches back as far as the oldest snapshot it keeps; a snapshot falls out of the
+    // period whose reach it's past, but is still within the next longer one's (the
+    // shortest period also takes the ones within its reach, the longest all the rest).
+    // So pruning each period once deletes just what check_age would have. Deletions
+    // that aren't the period's, or all of them, if the policy doesn't have it, are kept.
+    pub fn only_period(&self, period: Period, check: &mut AgeCheckResult, tz: &FixedOffset) {
+        let all: Vec<SnapshotMetadata> = check
+            .keep
+            .iter()
+            .chain(check.delete.iter())
+            .cloned()
+            .collect();
+        let mut order: Vec<usize> = (0..all.len()).collect();
+        order.sort_unstable_by(|&a, &b| newest_first(&all[a], &all[b]));
+        let kept_by = self.kept_by_rules(&all, &order, tz);
+        let reach = |r: usize| {
+            kept_by
+                .iter()
+                .filter(|&&(rule, _)| rule == r)
+                .map(|&(_, i)| all[i].created)
+                .min()
+        };
+        let set: Vec<usize> = self
+            .rules()
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, count))| count.unwrap_or(0) > 0)
+            .map(|(r, _)| r)
+            .collect();
+        let pos = match set.iter().position(|&r| r == period as usize) {
+            Some(pos) => pos,
+            None => {
+                check.keep_matching(|_| true);
+                return;
+            }
+        };
+        let from = set.get(pos + 1).and_then(|&r| reach(r));
+        let until = match pos {
+            0 => None,
+            _ => reach(set[pos]),
+        };
+        check.keep_matching(|s| {
+            !(from.is_none_or(|t| s.created >= t) && until.is_none_or(|t| s.created < t))
+        });
+    }
+
+    // A policy that keeps what either of the two would: as many of each period as the
+    // more generous one, and a cap only if both have one. Weeks begin as in self.
+    pub fn merge(&self, other: &RetentionPolicy) -> RetentionPolicy {
+        let either = |a: Option<u32>, b: Option<u32>| a.max(b);
+        let mut anchors = self.anchors.clone();
+        anchors.extend(other.anchors.iter().filter(|a| !self.anchors.contains(a)));
+        RetentionPolicy {
+            yearly: self.yearly.max(other.yearly),
+            monthly: either(self.monthly, other.monthly),
+            weekly: either(self.weekly, other.weekly),
+            daily: either(self.daily, other.daily),
+            hourly: either(self.hourly, other.hourly),
+            max: match (self.max, other.max) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                _ => None,
+            },
+            week_start: self.week_start,
+            anchors,
+        }
+    }
+
+    // The policy in the compact form (e.g. h24d30w8m6y1), whichever way it was written.
+    pub fn compact(&self) -> String {
+        let mut out = String::new();
+        let counts = [
+            ('h', self.hourly),
+            ('d', self.daily),
+            ('w', self.weekly),
+            ('m', self.monthly),
+            ('y', self.yearly.map(|y| y as u32)),
+            ('n', self.max),
+        ];
+        for (letter, count) in counts.iter() {
+            if let Some(count) = count {
+                out.push(*letter);
+                out.push_str(&count.to_string());
+            }
+        }
+        out
+    }
+
+    // Point out likely mistakes in the policy, as human-readable advice; no advice means
+    // the policy looks sensible.
+    pub fn lint(&self) -> Vec<String> {
+        // Each period, with its rough length in hours.
+        let periods = [
+            ("hourly", "hour", self.hourly, 1),
+            ("daily", "day", self.daily, 24),
+            ("weekly", "week", self.weekly, 24 * 7),
+            ("monthly", "month", self.monthly, 730),
+            ("yearly", "year", self.yearly.map(|y| y as u32), 8766),
+        ];
+        let mut advice = vec![];
+        for (name, _, count, _) in periods.iter() {
+            if *count == Some(0) {
+                advice.push(format!("{}=0 keeps nothing; leave it out instead", name));
+            }
+        }
+        let set: Vec<_> = periods
+            .iter()
+            .filter(|(_, _, count, _)| count.unwrap_or(0) > 0)
+            .collect();
+        for pair in set.windows(2) {
+            let (short, unit, short_count, short_hours) = pair[0];
+            let (long, _, long_count, long_hours) = pair[1];
+            let (short_count, long_count) = (short_count.unwrap(), long_count.unwrap());
+            if short_count as u64 * short_hours >= long_count as u64 * long_hours {
+                advice.push(format!(
+                    "{}={} adds nothing: {}={} already covers {} {}s",
+                    long, long_count, short, short_count, short_count, unit
+                ));
+            }
+        }
+        if let Some((name, unit, count, _)) = set.last() {
+            if *name != "yearly" {
+                let longer = periods[periods.iter().position(|p| p.0 == *name).unwrap() + 1].0;
+                advice.push(format!(
+                    "{} is set but not {}: nothing older than about {} {}s is kept",
+                    name,
+                    longer,
+                    count.unwrap(),
+                    unit
+                ));
+            }
+        }
+        if let Some(max) = self.max {
+            let total: u64 = set
+                .iter()
+                .map(|(_, _, count, _)| count.unwrap() as u64)
+                .sum();
+            if (max as u64) < total {
+                advice.push(format!(
+                    "max={} is below the {} snapshots the periods ask for; the oldest will go",
+                    max, total
+                ));
+            }
+        }
+        advice
+    }
+
+    // Which snapshots each of the rules wants to keep, as (index into rules(), index into
+    // snapshots) pairs; order has the snapshot indices, newest first.
+    fn kept_by_rules(
+        &self,
+        snapshots: &[SnapshotMetadata],
+        order: &[usize],
+        tz: &FixedOffset,
+    ) -> Vec<(usize, usize)> {
+        let mut kept_by = vec![];
+        'next_rule: for (r, (pattern, rule)) in self.rules().iter().enumerate() {
             // RetentionPolicy.rules() creates a set of date format patterns (see strftime(3)),
             // which are meant to be lossy/fuzzy (e.g. year-month-day; year-week, etc).
             let mut last = None;
-            match rule {
+            match *rule {
+                // Keeping zero of a period is the same as not keeping it at all; only the
+                // compact form allows it (policy lint warns about it), for compatibility.
                 Some(0) => {}
                 Some(number_to_keep) => {
                     let mut kept = 0;
-                    for snapshot in snapshots.iter() {
+                    for &i in order.iter() {
                         // We use these date patterns to format each snapshot's creation date, to
                         // put it in an ad-hoc bucket (last / period); then keep track of how many
                         // snapshots (kept) we've retained so far for the current bucket.
-                        let period = Some(snapshot.created.format(pattern).to_string());
+                        let created = snapshots[i].created.with_timezone(tz);
+                        let period = Some(period_of(&created, pattern));
                         if last != period {
                             last = period;
-                            to_keep.insert(snapshot);
+                            kept_by.push((r, i));
                             kept += 1;
                             if kept == number_to_keep {
                                 // This is as many snapshots as we wanted to
@@ -76,101 +691,2153 @@ impl RetentionPolicy {
                 None => {}
             }
         }
+        kept_by
+    }
 
-        let (keep, delete): (Vec<_>, Vec<_>) = snapshots
-            .iter()
-            .partition(|snapshot| to_keep.contains(snapshot));
-        AgeCheckResult {
-            keep: keep.into_iter().cloned().collect(),
-            delete: delete.into_iter().cloned().collect(),
+    // Roughly when each snapshot will stop being kept, assuming snapshots keep being taken
+    // (at least once per the shortest period): once as many newer periods as the rule
+    // keeps have begun, the snapshot's own period falls off. One entry per snapshot; None
+    // for those the rules don't keep now.
+    pub fn expiry_in(
+        &self,
+        snapshots: &[SnapshotMetadata],
+        tz: &FixedOffset,
+    ) -> Vec<Option<DateTime<Utc>>> {
+        let (keep, _) = self.plan_in(snapshots, tz);
+        let keep: HashSet<usize> = keep.into_iter().collect();
+        let mut order: Vec<usize> = (0..snapshots.len()).collect();
+        order.sort_unstable_by(|&a, &b| newest_first(&snapshots[a], &snapshots[b]));
+        let counts = self.rules();
+        let mut expiry = vec![None; snapshots.len()];
+        for (r, i) in self.kept_by_rules(snapshots, &order, tz) {
+            if !keep.contains(&i) {
+                // Over the cap.
+                continue;
+            }
+            let created = snapshots[i].created.with_timezone(tz);
+            let until = self.period_end(&created, r, counts[r].1.unwrap_or(0));
+            expiry[i] = expiry[i].max(until);
         }
+        expiry
     }
-}
 
-impl FromStr for RetentionPolicy {
-    type Err = ();
+    // The start of the count-th period after the one t is in, for the rule at index r of
+    // rules().
+    fn period_end(&self, t: &DateTime<FixedOffset>, r: usize, count: u32) -> Option<DateTime<Utc>> {
+        let date = t.date().naive_local();
+        let add_months = |year: i32, month: u32, months: u32| {
+            let total = year as i64 * 12 + (month as i64 - 1) + months as i64;
+            chrono::NaiveDate::from_ymd_opt((total / 12) as i32, (total % 12) as u32 + 1, 1)
+        };
+        let end = match r {
+            0 => date.and_hms(t.hour(), 0, 0) + Duration::hours(count as i64),
+            1 => date.and_hms(0, 0, 0) + Duration::days(count as i64),
+            2 => {
+                let into_week = match self.week_start {
+                    WeekStart::Monday => t.weekday().num_days_from_monday(),
+                    WeekStart::Sunday => t.weekday().num_days_from_sunday(),
+                };
+                (date - Duration::days(into_week as i64)).and_hms(0, 0, 0)
+                    + Duration::weeks(count as i64)
+            }
+            3 => add_months(t.year(), t.month(), count)?.and_hms(0, 0, 0),
+            _ => add_months(t.year(), 1, count.checked_mul(12)?)?.and_hms(0, 0, 0),
+        };
+        t.timezone()
+            .from_local_datetime(&end)
+            .single()
+            .map(|end| end.with_timezone(&Utc))
+    }
 
-    fn from_str(x: &str) -> std::result::Result<Self, Self::Err> {
-        fn digits_from(start: usize, s: &str) -> &str {
-            let s = &s[start..];
-            let end = s.chars().take_while(|ch| ch.is_ascii_digit()).count();
-            &s[..end]
+    // Treating the policy as a requirement (e.g. m84: one snapshot in each of the last 84
+    // months that are over; the current one may yet get its snapshot), which of the
+    // required periods, counted back from now, have no snapshot at all; as (rule name,
+    // period) pairs, newest first per rule.
+    pub fn gaps_in(
+        &self,
+        snapshots: &[SnapshotMetadata],
+        now: &DateTime<Utc>,
+        tz: &FixedOffset,
+    ) -> Vec<(&'static str, String)> {
+        let names = ["hourly", "daily", "weekly", "monthly", "yearly"];
+        let now = now.with_timezone(tz);
+        let mut gaps = vec![];
+        for (r, (pattern, rule)) in self.rules().iter().enumerate() {
+            let count = rule.unwrap_or(0);
+            let seen: HashSet<String> = snapshots
+                .iter()
+                .map(|s| period_of(&s.created.with_timezone(tz), pattern))
+                .collect();
+            for i in 1..=count {
+                // Some moment in the period i periods before now. Months and years vary in
+                // length, so step those on the calendar; the middle of the month keeps
+                // clear of offset trouble at the edges.
+                let back = i as i64;
+                let t = match r {
+                    0 => Some(now - Duration::hours(back)),
+                    1 => Some(now - Duration::days(back)),
+                    2 => Some(now - Duration::weeks(back)),
+                    3 => {
+                        let total = now.year() as i64 * 12 + now.month0() as i64 - back;
+                        tz.ymd_opt((total / 12) as i32, (total % 12) as u32 + 1, 15)
+                            .single()
+                            .map(|d| d.and_hms(12, 0, 0))
+                    }
+                    _ => tz
+                        .ymd_opt(now.year() - back as i32, 6, 15)
+                        .single()
+                        .map(|d| d.and_hms(12, 0, 0)),
+                };
+                if let Some(period) = t.map(|t| period_of(&t, pattern)) {
+                    if !seen.contains(&period) {
+                        gaps.push((names[r], period));
+                    }
+                }
+            }
         }
-        let mut policy = RetentionPolicy {
-            yearly: None,
-            monthly: None,
-            weekly: None,
-            daily: None,
-            hourly: None,
+        gaps
+    }
+
+    // Compare this policy to another one, over the same snapshots.
+    pub fn diff(
+        &self,
+        new: &RetentionPolicy,
+        snapshots: &[SnapshotMetadata],
+        tz: &FixedOffset,
+    ) -> PolicyDiff {
+        let (old_keep, old_delete) = self.plan_in(snapshots, tz);
+        let new_keep: HashSet<usize> = new.plan_in(snapshots, tz).0.into_iter().collect();
+        PolicyDiff {
+            now_deleted: old_keep
+                .into_iter()
+                .filter(|i| !new_keep.contains(i))
+                .map(|i| snapshots[i].clone())
+                .collect(),
+            now_kept: old_delete
+                .into_iter()
+                .filter(|i| new_keep.contains(i))
+                .map(|i| snapshots[i].clone())
+                .collect(),
+        }
+    }
+
+    fn from_explicit_str(x: &str) -> std::result::Result<Self, String> {
+        // The explicit form, e.g. "hourly=24,daily=30,weekly=8". Unlike the compact form,
+        // this one is strict: anything we don't understand is an error.
+        let mut policy = RetentionPolicy::default();
+        for item in x.split(',') {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got '{}'", item))?;
+            let (key, value) = (key.trim(), value.trim());
+            let count: u32 = value
+                .parse()
+                .map_err(|_| format!("invalid count '{}' for '{}'", value, key))?;
+            policy.set_count(key, count)?;
+        }
+        Ok(policy)
+    }
+
+    fn from_json_str(x: &str) -> std::result::Result<Self, String> {
+        // A JSON object, e.g. {"hourly": 24, "daily": 30}, for tools that write their
+        // properties as JSON; the same keys as the explicit form, and just as strict.
+        let fields = match Json::from_str(x).map_err(|e| e.to_string())? {
+            Json::Object(fields) if fields.is_empty() => {
+                return Err("the JSON policy has no periods".to_string())
+            }
+            Json::Object(fields) => fields,
+            _ => return Err("a JSON policy must be an object".to_string()),
         };
-        for (i, ch) in x.chars().enumerate() {
-            match ch {
-                'y' => policy.yearly = digits_from(i + 1, x).parse().ok(),
-                'm' => policy.monthly = digits_from(i + 1, x).parse().ok(),
-                'w' => policy.weekly = digits_from(i + 1, x).parse().ok(),
-                'd' => policy.daily = digits_from(i + 1, x).parse().ok(),
-                'h' => policy.hourly = digits_from(i + 1, x).parse().ok(),
-                _ => {}
+        let mut policy = RetentionPolicy::default();
+        for (key, value) in fields {
+            match value {
+                Json::Number(n) if n >= 0.0 && n.fract() == 0.0 && n <= u32::MAX as f64 => {
+                    policy.set_count(&key, n as u32)?
+                }
+                _ => return Err(format!("invalid count for '{}' in the JSON policy", key)),
+            }
+        }
+        Ok(policy)
+    }
+
+    // Keep count of the period named key, for the explicit and JSON forms; each period
+    // may only be given once.
+    fn set_count(&mut self, key: &str, count: u32) -> std::result::Result<(), String> {
+        if count == 0 && key != "max" {
+            // It would keep nothing, same as leaving it out; but it reads as if it meant
+            // something else (e.g. "delete all the dailies").
+            return Err(format!("{}=0 keeps nothing; leave it out instead", key));
+        }
+        fn once<T>(field: &mut Option<T>, value: T, key: &str) -> std::result::Result<(), String> {
+            match field.replace(value) {
+                Some(_) => Err(format!("'{}' is given twice", key)),
+                None => Ok(()),
             }
         }
+        match key {
+            // Years are kept as i32, as chrono has them; a bigger count is an error, rather
+            // than wrapped around.
+            "yearly" if count > i32::MAX as u32 => {
+                Err(format!("invalid count '{}' for '{}'", count, key))
+            }
+            "yearly" => once(&mut self.yearly, count as i32, key),
+            "monthly" => once(&mut self.monthly, count, key),
+            "weekly" => once(&mut self.weekly, count, key),
+            "daily" => once(&mut self.daily, count, key),
+            "hourly" => once(&mut self.hourly, count, key),
+            "max" => once(&mut self.max, count, key),
+            _ => Err(format!("unknown retention period '{}'", key)),
+        }
+    }
 
+    // Parse a policy, refusing anything from_str would let by in the compact form: stray
+    // characters, a letter without a count (or with more than one), and the empty policy.
+    pub fn parse_strict(x: &str) -> std::result::Result<Self, String> {
+        if !x.starts_with('@') && !x.contains('=') && !x.trim_start().starts_with('{') {
+            let mut seen = String::new();
+            let mut chars = x.chars().peekable();
+            while let Some(ch) = chars.next() {
+                if !"ymwdhn".contains(ch) {
+                    return Err(format!("unexpected '{}' in policy '{}'", ch, x));
+                }
+                if seen.contains(ch) {
+                    return Err(format!("'{}' given twice in policy '{}'", ch, x));
+                }
+                seen.push(ch);
+                let mut count = String::new();
+                while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                    count.push(digit);
+                }
+                match count.parse::<u32>() {
+                    Ok(0) if ch != 'n' => {
+                        return Err(format!("{}0 keeps nothing; leave it out instead", ch))
+                    }
+                    Ok(_) => {}
+                    Err(_) => return Err(format!("'{}' needs a count in policy '{}'", ch, x)),
+                }
+            }
+        }
+        let policy = RetentionPolicy::from_str(x)?;
+        if policy.compact().is_empty() {
+            return Err("the policy keeps nothing".to_string());
+        }
         Ok(policy)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Run the retention engine on its own, without touching ZFS: parse the policy (in any of
+/// the forms the snapkeep property accepts), and split the snapshots into those to keep
+/// and those to delete, both newest first.
+///
+/// ```
+/// use byte_unit::Byte;
+/// use chrono::{Duration, TimeZone, Utc};
+/// use zfs_autosnap::prelude::*;
+///
+/// let start = Utc.ymd(2021, 10, 2).and_hms(0, 0, 0);
+/// let snapshots = (0..48)
+///     .map(|i| SnapshotMetadata {
+///         name: format!("tank@{}", i),
+///         created: start + Duration::hours(i),
+///         used: Byte::from_bytes(0),
+///     })
+///     .collect();
+/// let check = evaluate("h24d7", snapshots).unwrap();
+/// assert_eq!(check.keep.len(), 25);
+/// assert_eq!(check.keep[0].name, "tank@47");
+/// assert_eq!(check.delete.len(), 23);
+/// ```
+pub fn evaluate(policy: &str, mut snapshots: Vec<SnapshotMetadata>) -> Result<AgeCheckResult> {
+    let policy = RetentionPolicy::from_str(policy)
+        .map_err(|e| format!("unable to parse retention policy: {}", e))?;
+    Ok(policy.check_age(&mut snapshots))
+}
 
-    #[test]
-    fn test_retention_policy_from_str() {
-        let actual = RetentionPolicy::from_str("h24d30w8m6y1").unwrap();
-        let expected = RetentionPolicy {
-            yearly: Some(1),
-            monthly: Some(6),
-            weekly: Some(8),
-            daily: Some(30),
-            hourly: Some(24),
-        };
-        assert_eq!(actual, expected);
+// The naming scheme a snapshot follows: its name (after the @) with every run of digits
+// (timestamps, counters) as a single #. Ours all come out as "autosnap", whatever their
+// tags; with compat (i.e. --zfs-auto-snapshot), so do zfs-auto-snapshot's, as we take
+// those too.
+fn naming_scheme(name: &str, compat: bool) -> String {
+    if zfs::is_autosnap_name(name) || (compat && zfs::is_zfs_auto_snap_name(name)) {
+        return String::from("autosnap");
+    }
+    let snapshot = name.split_once('@').map_or(name, |(_, snapshot)| snapshot);
+    let mut scheme = String::new();
+    for ch in snapshot.chars() {
+        match ch.is_ascii_digit() {
+            true if scheme.ends_with('#') => {}
+            true => scheme.push('#'),
+            false => scheme.push(ch),
+        }
     }
+    scheme
+}
 
-    #[test]
-    fn test_retention_policy_invalid() {
-        let actual = RetentionPolicy::from_str("y1d88a1b2c3m5").unwrap();
-        let expected = RetentionPolicy {
-            yearly: Some(1),
-            monthly: Some(5),
-            weekly: None,
-            daily: Some(88),
-            hourly: None,
-        };
-        assert_eq!(actual, expected);
+// The naming schemes of each dataset's snapshots that more than one of them follow (so,
+// more likely some scheduler's than a one-off snapshot by hand), with how many do; for
+// the datasets that have more than one such scheme.
+pub fn mixed_naming_schemes(
+    snapshots: &[SnapshotMetadata],
+    compat: bool,
+) -> BTreeMap<String, Vec<(String, usize)>> {
+    let mut counts = BTreeMap::<&str, BTreeMap<String, usize>>::new();
+    for s in snapshots {
+        let schemes = counts.entry(s.dataset()).or_default();
+        *schemes.entry(naming_scheme(&s.name, compat)).or_default() += 1;
     }
+    counts
+        .into_iter()
+        .map(|(dataset, schemes)| {
+            let schemes: Vec<(String, usize)> = schemes
+                .into_iter()
+                .filter(|&(_, count)| count > 1)
+                .collect();
+            (dataset.to_string(), schemes)
+        })
+        .filter(|(_, schemes)| schemes.len() > 1)
+        .collect()
+}
 
-    #[test]
-    fn test_retention_policy_empty() {
-        let actual = RetentionPolicy::from_str("").unwrap();
-        let expected = RetentionPolicy {
-            yearly: None,
-            monthly: None,
-            weekly: None,
-            daily: None,
-            hourly: None,
-        };
-        assert_eq!(actual, expected);
+// Count snapshots by how old they are at the given time, into brackets of increasing size:
+// less than an hour, a day, a week, a month (30d), a year, and anything older.
+pub fn age_histogram(
+    snapshots: &[SnapshotMetadata],
+    now: DateTime<Utc>,
+) -> Vec<(&'static str, usize)> {
+    let mut brackets = vec![
+        ("<1h", Duration::hours(1), 0),
+        ("<1d", Duration::days(1), 0),
+        ("<1w", Duration::weeks(1), 0),
+        ("<1m", Duration::days(30), 0),
+        ("<1y", Duration::days(365), 0),
+        (">1y", Duration::max_value(), 0),
+    ];
+    for snapshot in snapshots {
+        let age = now - snapshot.created;
+        if let Some(bracket) = brackets.iter_mut().find(|(_, limit, _)| age < *limit) {
+            bracket.2 += 1;
+        }
     }
+    brackets
+        .into_iter()
+        .map(|(label, _, count)| (label, count))
+        .collect()
+}
 
-    #[test]
-    fn test_retention_policy_truncated() {
-        let actual = RetentionPolicy::from_str("y").unwrap();
-        let expected = RetentionPolicy {
-            yearly: None,
-            monthly: None,
-            weekly: None,
-            daily: None,
-            hourly: None,
+// Parse a size, e.g. 500M, 1.5T, or 1G. Like the zfs(1) commandline tool, we take the
+// single-letter units to be binary: 1G means 1GiB.
+pub fn parse_size(x: &str) -> Result<Byte> {
+    // We do the math for binary units ourselves, since byte_unit takes e.g. 1TiB to mean
+    // 1GiB (as of 4.0.12); everything else is left to byte_unit.
+    let binary = x.strip_suffix("iB").unwrap_or(x);
+    let power = match binary.chars().last() {
+        Some('K') => 1,
+        Some('M') => 2,
+        Some('G') => 3,
+        Some('T') => 4,
+        Some('P') => 5,
+        Some('E') => 6,
+        Some('Z') => 7,
+        _ => return Ok(Byte::from_str(x)?),
+    };
+    let value: f64 = binary[..binary.len() - 1]
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", x))?;
+    if !value.is_finite() || value < 0.0 {
+        return Err(format!("invalid size '{}'", x).into());
+    }
+    Ok(Byte::from_bytes((value * 1024f64.powi(power)) as u128))
+}
+
+// Parse a duration, like 30s, 15m, 1h, 1d, 2w, 7y, or a combination such as 1h30m. Months
+// are too irregular to be supported; a year is 365 days.
+pub fn parse_duration(x: &str) -> Result<Duration> {
+    let invalid = || format!("invalid duration '{}'", x);
+    let mut seconds: i64 = 0;
+    let mut digits = String::new();
+    for ch in x.chars() {
+        if ch.is_ascii_digit() {
+            digits.push(ch);
+            continue;
+        }
+        let unit = match ch {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            'y' => 365 * 24 * 60 * 60,
+            _ => return Err(invalid().into()),
         };
-        assert_eq!(actual, expected);
+        let n: i64 = digits.parse().map_err(|_| invalid())?;
+        digits.clear();
+        seconds = n
+            .checked_mul(unit)
+            .and_then(|n| n.checked_add(seconds))
+            .filter(|&n| n <= i64::MAX / 1000)
+            .ok_or_else(invalid)?;
+    }
+    if x.is_empty() || !digits.is_empty() {
+        return Err(invalid().into());
+    }
+    Ok(Duration::seconds(seconds))
+}
+
+// The first time after now that is a whole multiple of the interval (counting from the
+// epoch), e.g. the top of the next hour for 1h, or the next midnight (UTC) for 1d.
+pub fn next_run(now: DateTime<Utc>, every: Duration) -> DateTime<Utc> {
+    let every = every.num_seconds().max(1);
+    Utc.timestamp((now.timestamp() / every + 1) * every, 0)
+}
+
+// Whether a dataset that's to be snapshotted every so often is due, given its newest
+// snapshot: time is cut into slots of that length (counting from the epoch, as for
+// next_run), and it's due once per slot. So an hourly cron job that runs a little early
+// or late doesn't skip a day's snapshot.
+pub fn is_due(newest: Option<DateTime<Utc>>, now: DateTime<Utc>, every: Duration) -> bool {
+    let every = every.num_seconds().max(1);
+    newest.is_none_or(|newest| {
+        newest.timestamp().div_euclid(every) < now.timestamp().div_euclid(every)
+    })
+}
+
+// Up to max of the datasets, in name order, starting after the cursor (the last one done
+// by the previous run) and wrapping around; so that over a few runs all get their turn.
+pub fn next_batch(datasets: &[String], cursor: Option<&str>, max: usize) -> Vec<String> {
+    let mut sorted: Vec<&String> = datasets.iter().collect();
+    sorted.sort_unstable();
+    let start = match cursor {
+        Some(cursor) => sorted.partition_point(|ds| ds.as_str() <= cursor),
+        None => 0,
+    };
+    sorted[start..]
+        .iter()
+        .chain(sorted[..start].iter())
+        .take(max)
+        .map(|ds| ds.to_string())
+        .collect()
+}
+
+// Order datasets by the name hierarchy, one path component at a time: each parent right
+// before its children (so tank, tank/a, tank/a/x, tank/b), or with children_first, right
+// after them (tank/a/x, tank/a, tank/b, tank).
+pub fn sort_by_hierarchy(datasets: &mut [String], children_first: bool) {
+    datasets.sort_by(|a, b| {
+        let (mut a, mut b) = (a.split('/'), b.split('/'));
+        loop {
+            match (a.next(), b.next()) {
+                (Some(x), Some(y)) if x == y => continue,
+                (Some(x), Some(y)) => return x.cmp(y),
+                (None, None) => return Ordering::Equal,
+                (None, Some(_)) if children_first => return Ordering::Greater,
+                (Some(_), None) if children_first => return Ordering::Less,
+                (None, Some(_)) => return Ordering::Less,
+                (Some(_), None) => return Ordering::Greater,
+            }
+        }
+    });
+}
+
+// Order snapshots newest first, to the nanosecond; snapshots taken at the very same time
+// are ordered by name (also descending), so that the outcome never depends on the order
+// zfs listed them in.
+fn newest_first(a: &SnapshotMetadata, b: &SnapshotMetadata) -> Ordering {
+    b.created.cmp(&a.created).then_with(|| b.name.cmp(&a.name))
+}
+
+// Order the snapshots gc is about to destroy: oldest first (the ones least likely to be in
+// use, e.g. by a clone), or with newest_first, the other way round; ties as above.
+pub fn sort_for_destroy(snapshots: &mut [SnapshotMetadata], newest_first: bool) {
+    match newest_first {
+        true => snapshots.sort_by(self::newest_first),
+        false => snapshots.sort_by(|a, b| self::newest_first(b, a)),
+    }
+}
+
+fn utc() -> FixedOffset {
+    FixedOffset::east(0)
+}
+
+pub fn parse_anchors(text: &str) -> Result<Vec<DateTime<Utc>>> {
+    // One point in time per line (see parse_time); blank lines and lines starting with #
+    // are ignored.
+    let mut anchors = vec![];
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        anchors.push(parse_time(line).map_err(|_| format!("invalid anchor '{}'", line))?);
+    }
+    Ok(anchors)
+}
+
+// Parse a point in time: RFC 3339, seconds since the epoch (e.g. from `date +%s`), or just
+// a date (e.g. 2022-06-01), for midnight UTC.
+pub fn parse_time(x: &str) -> Result<DateTime<Utc>> {
+    let time = match x.parse::<i64>() {
+        Ok(secs) => Utc.timestamp_opt(secs, 0).single(),
+        Err(_) => DateTime::parse_from_rfc3339(x)
+            .map(|t| t.with_timezone(&Utc))
+            .ok()
+            .or_else(|| {
+                chrono::NaiveDate::parse_from_str(x, "%Y-%m-%d")
+                    .ok()
+                    .and_then(|d| d.and_hms_opt(0, 0, 0))
+                    .map(|t| Utc.from_utc_datetime(&t))
+            }),
+    };
+    time.ok_or_else(|| format!("invalid time '{}'", x).into())
+}
+
+// Policies for the datasets that don't have snapkeep set, by dataset name pattern, e.g.
+// from a file with "tank/db/* => h24d30" lines; see PolicyMap::lookup.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PolicyMap {
+    rules: Vec<(String, String)>,
+}
+
+impl PolicyMap {
+    pub fn parse(text: &str) -> Result<Self> {
+        // One GLOB => POLICY per line (or separated by ;), where * matches anything
+        // (slashes too) and ? any one character; blank lines and lines starting with #
+        // are ignored.
+        let mut rules = vec![];
+        for rule in text.split(['\n', ';']).map(str::trim) {
+            if rule.is_empty() || rule.starts_with('#') {
+                continue;
+            }
+            let (pattern, policy) = rule
+                .split_once("=>")
+                .ok_or_else(|| format!("invalid policy map entry '{}'", rule))?;
+            let (pattern, policy) = (pattern.trim(), policy.trim());
+            RetentionPolicy::from_str(policy)
+                .map_err(|e| format!("policy map entry '{}': {}", rule, e))?;
+            rules.push((pattern.to_string(), policy.to_string()));
+        }
+        Ok(PolicyMap { rules })
+    }
+
+    // The policy of the most specific (i.e. longest) pattern that matches the dataset; of
+    // those as long, the first.
+    pub fn lookup(&self, dataset: &str) -> Option<&str> {
+        let mut best: Option<&(String, String)> = None;
+        for rule in self.rules.iter().filter(|(p, _)| glob_match(p, dataset)) {
+            if best.is_none_or(|(p, _)| rule.0.len() > p.len()) {
+                best = Some(rule);
+            }
+        }
+        best.map(|(_, policy)| policy.as_str())
+    }
+}
+
+fn glob_match(pattern: &str, name: &str) -> bool {
+    match pattern.chars().next() {
+        None => name.is_empty(),
+        Some('*') => {
+            let rest = &pattern[1..];
+            name.char_indices()
+                .map(|(i, _)| i)
+                .chain(std::iter::once(name.len()))
+                .any(|i| glob_match(rest, &name[i..]))
+        }
+        Some(p) => match name.chars().next() {
+            Some(n) if p == '?' || p == n => {
+                glob_match(&pattern[p.len_utf8()..], &name[n.len_utf8()..])
+            }
+            _ => false,
+        },
+    }
+}
+
+// Parse a UTC offset, as found in the at.rollc.at:tz property: "UTC", "Z", or e.g.
+// "+02:00", "-0500", "+01". An unset property ("-") also means UTC.
+pub fn parse_utc_offset(x: &str) -> Result<FixedOffset> {
+    let invalid = || format!("invalid UTC offset '{}'", x);
+    if x == "-" || x == "UTC" || x == "Z" {
+        return Ok(utc());
+    }
+    let sign = match x.chars().next() {
+        Some('+') => 1,
+        Some('-') => -1,
+        _ => return Err(invalid().into()),
+    };
+    let digits = x[1..].replace(':', "");
+    if !digits.chars().all(|ch| ch.is_ascii_digit()) {
+        return Err(invalid().into());
+    }
+    let (hours, minutes) = match digits.len() {
+        2 => (&digits[..], "0"),
+        4 => (&digits[..2], &digits[2..]),
+        _ => return Err(invalid().into()),
+    };
+    let seconds = hours.parse::<i32>()? * 3600 + minutes.parse::<i32>()? * 60;
+    FixedOffset::east_opt(sign * seconds).ok_or_else(|| invalid().into())
+}
+
+// The compact form, as parsed back by from_str; e.g. for setting it as snapkeep.
+impl fmt::Display for RetentionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.compact())
+    }
+}
+
+impl FromStr for RetentionPolicy {
+    type Err = String;
+
+    fn from_str(x: &str) -> std::result::Result<Self, Self::Err> {
+        fn digits_from(start: usize, s: &str) -> &str {
+            let s = &s[start..];
+            let end = s.chars().take_while(|ch| ch.is_ascii_digit()).count();
+            &s[..end]
+        }
+        if let Some(name) = x.strip_prefix('@') {
+            return match PRESETS.iter().find(|(preset, _)| *preset == name) {
+                Some((_, policy)) => RetentionPolicy::from_str(policy),
+                None => Err(format!("unknown policy preset '{}'", x)),
+            };
+        }
+        if x.trim_start().starts_with('{') {
+            return RetentionPolicy::from_json_str(x);
+        }
+        if x.contains('=') {
+            return RetentionPolicy::from_explicit_str(x);
+        }
+        let mut policy = RetentionPolicy::default();
+        for (i, ch) in x.chars().enumerate() {
+            match ch {
+                'y' => policy.yearly = digits_from(i + 1, x).parse().ok(),
+                'm' => policy.monthly = digits_from(i + 1, x).parse().ok(),
+                'w' => policy.weekly = digits_from(i + 1, x).parse().ok(),
+                'd' => policy.daily = digits_from(i + 1, x).parse().ok(),
+                'h' => policy.hourly = digits_from(i + 1, x).parse().ok(),
+                'n' => policy.max = digits_from(i + 1, x).parse().ok(),
+                _ => {}
+            }
+        }
+
+        Ok(policy)
+    }
+}
+
+// How snap or gc went for a dataset, for the summary at the end of a --keep-going run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    // Left alone, and why.
+    Skipped(String),
+    // What went wrong.
+    Failed(String),
+}
+
+// The outcome for each dataset a run went over.
+#[derive(Debug, Default)]
+pub struct RunSummary {
+    outcomes: BTreeMap<String, Outcome>,
+}
+
+impl RunSummary {
+    pub fn record(&mut self, dataset: &str, outcome: Outcome) {
+        // A dataset can come up more than once (e.g. once per snapshot destroyed); the
+        // worst of its outcomes stands, and of several failures, the first.
+        let rank = |o: &Outcome| match o {
+            Outcome::Skipped(_) => 0,
+            Outcome::Ok => 1,
+            Outcome::Failed(_) => 2,
+        };
+        match self.outcomes.get(dataset) {
+            Some(old) if rank(old) >= rank(&outcome) => {}
+            _ => {
+                self.outcomes.insert(dataset.to_string(), outcome);
+            }
+        }
+    }
+
+    pub fn extend(&mut self, other: RunSummary) {
+        for (dataset, outcome) in other.outcomes {
+            self.record(&dataset, outcome);
+        }
+    }
+
+    pub fn succeeded(&self) -> Vec<&str> {
+        self.outcomes
+            .iter()
+            .filter(|(_, o)| **o == Outcome::Ok)
+            .map(|(dataset, _)| dataset.as_str())
+            .collect()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.outcomes
+            .values()
+            .filter(|o| matches!(o, Outcome::Failed(_)))
+            .count()
+    }
+
+    // One line per dataset, by name: the dataset, ok/skipped/failed, and for the latter
+    // two, the reason (just its first line, and cut short).
+    pub fn lines(&self) -> Vec<String> {
+        let snippet = |x: &str| {
+            let line = x.lines().next().unwrap_or("");
+            match line.char_indices().nth(60) {
+                Some((i, _)) => format!("{}...", &line[..i]),
+                None => line.to_string(),
+            }
+        };
+        self.outcomes
+            .iter()
+            .map(|(dataset, outcome)| match outcome {
+                Outcome::Ok => format!("{}\tok", dataset),
+                Outcome::Skipped(why) => format!("{}\tskipped\t{}", dataset, snippet(why)),
+                Outcome::Failed(why) => format!("{}\tfailed\t{}", dataset, snippet(why)),
+            })
+            .collect()
+    }
+}
+
+// A JSON value; just enough of one to read back our own files.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    // In the order the keys came in.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Json {
+    type Err = Box<dyn Error>;
+
+    fn from_str(x: &str) -> Result<Self> {
+        let mut parser = JsonParser {
+            text: x,
+            chars: x.char_indices().peekable(),
+        };
+        let value = parser.value()?;
+        parser.skip_space();
+        match parser.chars.peek() {
+            Some(_) => Err(parser.error("trailing data")),
+            None => Ok(value),
+        }
+    }
+}
+
+struct JsonParser<'a> {
+    text: &'a str,
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+}
+
+impl JsonParser<'_> {
+    fn error(&mut self, what: &str) -> Box<dyn Error> {
+        match self.chars.peek() {
+            Some(&(i, _)) => format!("invalid JSON at offset {}: {}", i, what).into(),
+            None => format!("invalid JSON at the end: {}", what).into(),
+        }
+    }
+
+    fn skip_space(&mut self) {
+        while self
+            .chars
+            .next_if(|(_, ch)| ch.is_ascii_whitespace())
+            .is_some()
+        {}
+    }
+
+    fn expect(&mut self, want: char) -> Result<()> {
+        self.skip_space();
+        match self.chars.next_if(|&(_, ch)| ch == want) {
+            Some(_) => Ok(()),
+            None => Err(self.error(&format!("expected '{}'", want))),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        self.skip_space();
+        let start = match self.chars.peek() {
+            Some(&(i, ch)) => (i, ch),
+            None => return Err(self.error("expected a value")),
+        };
+        match start {
+            (_, '{') => {
+                self.chars.next();
+                let mut fields = vec![];
+                self.skip_space();
+                if self.chars.next_if(|&(_, ch)| ch == '}').is_some() {
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_space();
+                    let key = match self.value()? {
+                        Json::String(key) => key,
+                        _ => return Err("invalid JSON: keys must be strings".into()),
+                    };
+                    self.expect(':')?;
+                    fields.push((key, self.value()?));
+                    self.skip_space();
+                    if self.chars.next_if(|&(_, ch)| ch == '}').is_some() {
+                        return Ok(Json::Object(fields));
+                    }
+                    self.expect(',')?;
+                }
+            }
+            (_, '[') => {
+                self.chars.next();
+                let mut items = vec![];
+                self.skip_space();
+                if self.chars.next_if(|&(_, ch)| ch == ']').is_some() {
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_space();
+                    if self.chars.next_if(|&(_, ch)| ch == ']').is_some() {
+                        return Ok(Json::Array(items));
+                    }
+                    self.expect(',')?;
+                }
+            }
+            (_, '"') => {
+                self.chars.next();
+                let mut out = String::new();
+                loop {
+                    match self.chars.next() {
+                        Some((_, '"')) => return Ok(Json::String(out)),
+                        Some((_, '\\')) => match self.chars.next() {
+                            Some((_, 'n')) => out.push('\n'),
+                            Some((_, 't')) => out.push('\t'),
+                            Some((_, 'r')) => out.push('\r'),
+                            Some((_, 'b')) => out.push('\u{8}'),
+                            Some((_, 'f')) => out.push('\u{c}'),
+                            Some((_, 'u')) => {
+                                let hex: String = (0..4)
+                                    .filter_map(|_| self.chars.next())
+                                    .map(|(_, ch)| ch)
+                                    .collect();
+                                let ch = u32::from_str_radix(&hex, 16)
+                                    .ok()
+                                    .and_then(char::from_u32)
+                                    .ok_or_else(|| self.error("invalid \\u escape"))?;
+                                out.push(ch);
+                            }
+                            Some((_, ch)) if "\"\\/".contains(ch) => out.push(ch),
+                            _ => return Err(self.error("invalid escape")),
+                        },
+                        Some((_, ch)) => out.push(ch),
+                        None => return Err(self.error("unterminated string")),
+                    }
+                }
+            }
+            (i, _) => {
+                // A number, or true, false or null.
+                let mut end = i;
+                while let Some((j, ch)) = self
+                    .chars
+                    .next_if(|(_, ch)| ch.is_ascii_alphanumeric() || "+-.".contains(*ch))
+                {
+                    end = j + ch.len_utf8();
+                }
+                match &self.text[i..end] {
+                    "true" => Ok(Json::Bool(true)),
+                    "false" => Ok(Json::Bool(false)),
+                    "null" => Ok(Json::Null),
+                    x => match x.parse::<f64>() {
+                        Ok(n) if n.is_finite() => Ok(Json::Number(n)),
+                        _ => Err(format!("invalid JSON at offset {}: expected a value", i).into()),
+                    },
+                }
+            }
+        }
+    }
+}
+
+// When snap and gc last succeeded on each dataset, as kept in the state file, e.g.
+// {"version": 1, "datasets": {"tank": {"snap": "2021-10-02T09:59:00Z", "gc": null}}}
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct RunState {
+    pub datasets: BTreeMap<String, LastRun>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct LastRun {
+    pub snap: Option<DateTime<Utc>>,
+    pub gc: Option<DateTime<Utc>>,
+}
+
+impl RunState {
+    pub fn parse(text: &str) -> Result<RunState> {
+        let json = Json::from_str(text)?;
+        match json.get("version") {
+            Some(Json::Number(n)) if *n == 1.0 => {}
+            _ => return Err("unknown state file version".into()),
+        }
+        let mut state = RunState::default();
+        let datasets = match json.get("datasets") {
+            Some(Json::Object(datasets)) => datasets,
+            _ => return Err("state file has no datasets".into()),
+        };
+        for (dataset, runs) in datasets {
+            let time = |action: &str| -> Result<Option<DateTime<Utc>>> {
+                match runs.get(action) {
+                    None | Some(Json::Null) => Ok(None),
+                    Some(Json::String(t)) => Ok(Some(parse_time(t)?)),
+                    Some(_) => Err(format!("{}: invalid {} time", dataset, action).into()),
+                }
+            };
+            let last = LastRun {
+                snap: time("snap")?,
+                gc: time("gc")?,
+            };
+            state.datasets.insert(dataset.clone(), last);
+        }
+        Ok(state)
+    }
+
+    pub fn to_json(&self) -> String {
+        let time = |t: Option<DateTime<Utc>>| match t {
+            Some(t) => json_string(&t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+            None => String::from("null"),
+        };
+        let datasets: Vec<String> = self
+            .datasets
+            .iter()
+            .map(|(dataset, last)| {
+                format!(
+                    "    {}: {{\"snap\": {}, \"gc\": {}}}",
+                    json_string(dataset),
+                    time(last.snap),
+                    time(last.gc)
+                )
+            })
+            .collect();
+        match datasets.is_empty() {
+            true => String::from("{\"version\": 1, \"datasets\": {}}\n"),
+            false => format!(
+                "{{\"version\": 1, \"datasets\": {{\n{}\n}}}}\n",
+                datasets.join(",\n")
+            ),
+        }
+    }
+}
+
+// How many snapshots of a dataset are kept, and would be deleted, and how much space
+// they take; for metrics.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct DatasetTotals {
+    pub keep: usize,
+    pub keep_bytes: u128,
+    pub delete: usize,
+    pub delete_bytes: u128,
+}
+
+pub fn dataset_totals(check: &AgeCheckResult) -> BTreeMap<String, DatasetTotals> {
+    let mut totals = BTreeMap::<String, DatasetTotals>::new();
+    for s in check.keep.iter() {
+        let t = totals.entry(s.dataset().to_string()).or_default();
+        t.keep += 1;
+        t.keep_bytes += s.used.get_bytes();
+    }
+    for s in check.delete.iter() {
+        let t = totals.entry(s.dataset().to_string()).or_default();
+        t.delete += 1;
+        t.delete_bytes += s.used.get_bytes();
+    }
+    totals
+}
+
+// The totals, and when snap and gc last succeeded, in the Prometheus text exposition
+// format, for a scraper.
+pub fn render_metrics(totals: &BTreeMap<String, DatasetTotals>, state: &RunState) -> String {
+    // Label values are quoted; zfs names can't have newlines, but may have quotes.
+    let label = |x: &str| {
+        x.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    };
+    let mut out = String::new();
+    out.push_str(
+        "# HELP zfs_autosnap_snapshots Managed snapshots, by whether gc would keep them.\n",
+    );
+    out.push_str("# TYPE zfs_autosnap_snapshots gauge\n");
+    for (dataset, t) in totals.iter() {
+        for (action, n) in [("keep", t.keep), ("delete", t.delete)] {
+            out.push_str(&format!(
+                "zfs_autosnap_snapshots{{dataset=\"{}\",action=\"{}\"}} {}\n",
+                label(dataset),
+                action,
+                n
+            ));
+        }
+    }
+    out.push_str("# HELP zfs_autosnap_snapshot_bytes Space used by the managed snapshots.\n");
+    out.push_str("# TYPE zfs_autosnap_snapshot_bytes gauge\n");
+    for (dataset, t) in totals.iter() {
+        for (action, n) in [("keep", t.keep_bytes), ("delete", t.delete_bytes)] {
+            out.push_str(&format!(
+                "zfs_autosnap_snapshot_bytes{{dataset=\"{}\",action=\"{}\"}} {}\n",
+                label(dataset),
+                action,
+                n
+            ));
+        }
+    }
+    out.push_str(
+        "# HELP zfs_autosnap_last_success_timestamp_seconds When snap or gc last succeeded.\n",
+    );
+    out.push_str("# TYPE zfs_autosnap_last_success_timestamp_seconds gauge\n");
+    for (dataset, last) in state.datasets.iter() {
+        for (action, t) in [("snap", last.snap), ("gc", last.gc)] {
+            if let Some(t) = t {
+                out.push_str(&format!(
+                    "zfs_autosnap_last_success_timestamp_seconds{{dataset=\"{}\",action=\"{}\"}} {}\n",
+                    label(dataset),
+                    action,
+                    t.timestamp()
+                ));
+            }
+        }
+    }
+    out
+}
+
+// A duration, roughly, in its largest whole unit, e.g. 58m or 3d.
+pub fn show_duration(d: Duration) -> String {
+    let seconds = d.num_seconds().max(0);
+    let (n, unit) = match seconds {
+        s if s < 60 => (s, "s"),
+        s if s < 60 * 60 => (s / 60, "m"),
+        s if s < 24 * 60 * 60 => (s / (60 * 60), "h"),
+        s => (s / (24 * 60 * 60), "d"),
+    };
+    format!("{}{}", n, unit)
+}
+
+// x as a JSON string, quotes and all.
+pub fn json_string(x: &str) -> String {
+    let mut out = String::from("\"");
+    for ch in x.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json() {
+        let json =
+            Json::from_str(r#" {"a": [1, -2.5e1, true, null], "b\u0021": "x\"y\n"} "#).unwrap();
+        assert_eq!(
+            json,
+            Json::Object(vec![
+                (
+                    String::from("a"),
+                    Json::Array(vec![
+                        Json::Number(1.0),
+                        Json::Number(-25.0),
+                        Json::Bool(true),
+                        Json::Null
+                    ])
+                ),
+                (String::from("b!"), Json::String(String::from("x\"y\n")))
+            ])
+        );
+        assert_eq!(json.get("b!"), Some(&Json::String(String::from("x\"y\n"))));
+        assert!(json.get("c").is_none());
+        assert_eq!(Json::from_str("{}").unwrap(), Json::Object(vec![]));
+        for bad in [
+            "",
+            "{",
+            "{\"a\" 1}",
+            "[1,]",
+            "\"x",
+            "tru",
+            "{} {}",
+            "{1: 2}",
+        ] {
+            assert!(Json::from_str(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(
+            Json::from_str("[1, oops]").unwrap_err().to_string(),
+            "invalid JSON at offset 4: expected a value"
+        );
+    }
+
+    #[test]
+    fn test_run_state() {
+        let mut state = RunState::default();
+        assert_eq!(RunState::parse(&state.to_json()).unwrap(), state);
+        let t = Utc.ymd(2021, 10, 2).and_hms(9, 59, 0);
+        state.datasets.entry(String::from("tank")).or_default().snap = Some(t);
+        state
+            .datasets
+            .entry(String::from("tank/\"home\""))
+            .or_default()
+            .gc = Some(t);
+        let text = state.to_json();
+        assert!(text.contains(r#""tank": {"snap": "2021-10-02T09:59:00Z", "gc": null}"#));
+        assert_eq!(RunState::parse(&text).unwrap(), state);
+        assert!(RunState::parse(r#"{"version": 2, "datasets": {}}"#).is_err());
+        assert!(RunState::parse(r#"{"version": 1, "datasets": {"tank": {"snap": 1}}}"#).is_err());
+    }
+
+    #[test]
+    fn test_render_metrics() {
+        let t = Utc.ymd(2021, 10, 2).and_hms(9, 59, 0);
+        let check = AgeCheckResult {
+            keep: vec![SnapshotMetadata::new("tank@b", t, 2048)],
+            delete: vec![
+                SnapshotMetadata::new("tank@a", t, 1024),
+                SnapshotMetadata::new("tank/\"x\"@a", t, 0),
+            ],
+        };
+        let totals = dataset_totals(&check);
+        assert_eq!(
+            totals["tank"],
+            DatasetTotals {
+                keep: 1,
+                keep_bytes: 2048,
+                delete: 1,
+                delete_bytes: 1024
+            }
+        );
+        let mut state = RunState::default();
+        state.datasets.entry(String::from("tank")).or_default().snap = Some(t);
+        let metrics = render_metrics(&totals, &state);
+        let lines: Vec<&str> = metrics.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"zfs_autosnap_snapshots{dataset="tank",action="keep"} 1"#,
+                r#"zfs_autosnap_snapshots{dataset="tank",action="delete"} 1"#,
+                r#"zfs_autosnap_snapshots{dataset="tank/\"x\"",action="keep"} 0"#,
+                r#"zfs_autosnap_snapshots{dataset="tank/\"x\"",action="delete"} 1"#,
+                r#"zfs_autosnap_snapshot_bytes{dataset="tank",action="keep"} 2048"#,
+                r#"zfs_autosnap_snapshot_bytes{dataset="tank",action="delete"} 1024"#,
+                r#"zfs_autosnap_snapshot_bytes{dataset="tank/\"x\"",action="keep"} 0"#,
+                r#"zfs_autosnap_snapshot_bytes{dataset="tank/\"x\"",action="delete"} 0"#,
+                r#"zfs_autosnap_last_success_timestamp_seconds{dataset="tank",action="snap"} 1633168740"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_show_duration() {
+        assert_eq!(show_duration(Duration::seconds(42)), "42s");
+        assert_eq!(show_duration(Duration::minutes(58)), "58m");
+        assert_eq!(show_duration(Duration::minutes(61)), "1h");
+        assert_eq!(show_duration(Duration::days(3)), "3d");
+        assert_eq!(show_duration(Duration::seconds(-5)), "0s");
+    }
+
+    #[test]
+    fn test_retention_policy_from_str() {
+        let actual = RetentionPolicy::from_str("h24d30w8m6y1").unwrap();
+        let expected = RetentionPolicy {
+            yearly: Some(1),
+            monthly: Some(6),
+            weekly: Some(8),
+            daily: Some(30),
+            hourly: Some(24),
+            max: None,
+            week_start: WeekStart::Monday,
+            anchors: vec![],
+        };
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_retention_policy_invalid() {
+        let actual = RetentionPolicy::from_str("y1d88a1b2c3m5").unwrap();
+        let expected = RetentionPolicy {
+            yearly: Some(1),
+            monthly: Some(5),
+            weekly: None,
+            daily: Some(88),
+            hourly: None,
+            max: None,
+            week_start: WeekStart::Monday,
+            anchors: vec![],
+        };
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_retention_policy_empty() {
+        let actual = RetentionPolicy::from_str("").unwrap();
+        let expected = RetentionPolicy {
+            yearly: None,
+            monthly: None,
+            weekly: None,
+            daily: None,
+            hourly: None,
+            max: None,
+            week_start: WeekStart::Monday,
+            anchors: vec![],
+        };
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_retention_policy_truncated() {
+        let actual = RetentionPolicy::from_str("y").unwrap();
+        let expected = RetentionPolicy {
+            yearly: None,
+            monthly: None,
+            weekly: None,
+            daily: None,
+            hourly: None,
+            max: None,
+            week_start: WeekStart::Monday,
+            anchors: vec![],
+        };
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_retention_policy_strict() {
+        let compact = |x: &str| RetentionPolicy::parse_strict(x).map(|p| p.compact());
+        assert_eq!(compact("h24d30w8"), Ok("h24d30w8".to_string()));
+        assert_eq!(compact("w8h24"), Ok("h24w8".to_string()));
+        assert_eq!(compact("daily=7,hourly=24"), Ok("h24d7".to_string()));
+        assert_eq!(compact("@archive"), Ok("m24y10".to_string()));
+        assert!(compact("h24 d30").is_err());
+        assert!(compact("h24x").is_err());
+        assert!(compact("yd30").is_err());
+        assert!(compact("h24h12").is_err());
+        assert!(compact("d0").is_err());
+        assert!(compact("").is_err());
+        assert!(compact("hourly=24,dialy=7").is_err());
+    }
+
+    #[test]
+    fn test_retention_policy_max_from_str() {
+        let actual = RetentionPolicy::from_str("h24n100").unwrap();
+        assert_eq!(actual.hourly, Some(24));
+        assert_eq!(actual.max, Some(100));
+    }
+
+    #[test]
+    fn test_retention_policy_preset() {
+        assert_eq!(
+            RetentionPolicy::from_str("@default").unwrap(),
+            RetentionPolicy::from_str("h24d30w8m6y1").unwrap()
+        );
+        assert_eq!(
+            RetentionPolicy::from_str("@daily-backup").unwrap(),
+            RetentionPolicy::from_str("d30w8m12y3").unwrap()
+        );
+        for (name, _) in PRESETS {
+            RetentionPolicy::from_str(&format!("@{}", name)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_retention_policy_unknown_preset() {
+        let err = RetentionPolicy::from_str("@fortnightly").unwrap_err();
+        assert_eq!(err, "unknown policy preset '@fortnightly'");
+    }
+
+    #[test]
+    fn test_retention_policy_explicit() {
+        let actual = RetentionPolicy::from_str("hourly=24,daily=30,weekly=8").unwrap();
+        let expected = RetentionPolicy {
+            yearly: None,
+            monthly: None,
+            weekly: Some(8),
+            daily: Some(30),
+            hourly: Some(24),
+            max: None,
+            week_start: WeekStart::Monday,
+            anchors: vec![],
+        };
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_retention_policy_explicit_matches_compact() {
+        let explicit =
+            RetentionPolicy::from_str("hourly=24, daily=30, weekly=8, monthly=6, yearly=1")
+                .unwrap();
+        let compact = RetentionPolicy::from_str("h24d30w8m6y1").unwrap();
+        assert_eq!(explicit, compact);
+    }
+
+    #[test]
+    fn test_retention_policy_json() {
+        let json = RetentionPolicy::from_str(r#"{"hourly": 24, "daily": 30, "max": 100}"#);
+        assert_eq!(json, RetentionPolicy::from_str("h24d30n100"));
+        let strict = RetentionPolicy::parse_strict(r#" {"yearly":5}"#).unwrap();
+        assert_eq!(strict.compact(), "y5");
+        let err = |x: &str| RetentionPolicy::from_str(x).unwrap_err();
+        assert_eq!(
+            err(r#"{"hourly": 24"#),
+            "invalid JSON at the end: expected ','"
+        );
+        assert_eq!(err(r#"{"dialy": 7}"#), "unknown retention period 'dialy'");
+        assert_eq!(
+            err(r#"{"daily": "7"}"#),
+            "invalid count for 'daily' in the JSON policy"
+        );
+        assert_eq!(
+            err(r#"{"daily": 1.5}"#),
+            "invalid count for 'daily' in the JSON policy"
+        );
+        assert_eq!(
+            err(r#"{"daily": 0}"#),
+            "daily=0 keeps nothing; leave it out instead"
+        );
+        assert_eq!(err("{}"), "the JSON policy has no periods");
+        assert_eq!(
+            err(r#"{"daily": 7, "daily": 30}"#),
+            "'daily' is given twice"
+        );
+        // The keys are only ever keys; nothing in them can add periods of its own.
+        assert_eq!(
+            err(r#"{"daily=7,hourly": 24}"#),
+            "unknown retention period 'daily=7,hourly'"
+        );
+    }
+
+    #[test]
+    fn test_retention_policy_explicit_unknown_key() {
+        let err = RetentionPolicy::from_str("hourly=24,fortnightly=2").unwrap_err();
+        assert_eq!(err, "unknown retention period 'fortnightly'");
+    }
+
+    #[test]
+    fn test_retention_policy_explicit_invalid_count() {
+        let err = RetentionPolicy::from_str("hourly=24,daily=lots").unwrap_err();
+        assert_eq!(err, "invalid count 'lots' for 'daily'");
+        let err = RetentionPolicy::from_str("hourly=24,daily").unwrap_err();
+        assert_eq!(err, "expected key=value, got 'daily'");
+        assert_eq!(
+            RetentionPolicy::from_str("yearly=2147483647")
+                .unwrap()
+                .yearly,
+            Some(i32::MAX)
+        );
+        let err = RetentionPolicy::from_str("yearly=2147483648").unwrap_err();
+        assert_eq!(err, "invalid count '2147483648' for 'yearly'");
+        let err = RetentionPolicy::from_str("hourly=24,hourly=48").unwrap_err();
+        assert_eq!(err, "'hourly' is given twice");
+    }
+
+    #[test]
+    fn test_retention_policy_zero() {
+        // The explicit form rejects zero counts; the compact form takes them, as the same
+        // as leaving the period out.
+        let err = RetentionPolicy::from_str("hourly=24,daily=0").unwrap_err();
+        assert_eq!(err, "daily=0 keeps nothing; leave it out instead");
+        assert!(RetentionPolicy::from_str("hourly=24,max=0").is_ok());
+        let with_zero = RetentionPolicy::from_str("h24d0").unwrap();
+        assert_eq!(with_zero.daily, Some(0));
+        let without = RetentionPolicy::from_str("h24").unwrap();
+        let snapshots = hourly_snapshots(48);
+        assert_eq!(with_zero.plan(&snapshots), without.plan(&snapshots));
+    }
+
+    #[test]
+    fn test_merge() {
+        let ops = RetentionPolicy::from_str("h24d7n50").unwrap();
+        let compliance = RetentionPolicy::from_str("d30m84n100").unwrap();
+        let merged = ops.merge(&compliance);
+        assert_eq!(merged.compact(), "h24d30m84n100");
+        let uncapped = RetentionPolicy::from_str("w4").unwrap();
+        assert_eq!(merged.merge(&uncapped).compact(), "h24d30w4m84");
+        // Keeping what either would: the union of their keeps, here.
+        let snapshots = hourly_snapshots(48);
+        let (a, b) = (ops.plan(&snapshots).0, compliance.plan(&snapshots).0);
+        let union: HashSet<usize> = a.into_iter().chain(b).collect();
+        let merged: HashSet<usize> = merged.plan(&snapshots).0.into_iter().collect();
+        assert_eq!(merged, union);
+    }
+
+    #[test]
+    fn test_plan_matches_check_age() {
+        let mut snapshots = hourly_snapshots(30);
+        // plan() must not depend on the input order.
+        snapshots.reverse();
+        let policy = RetentionPolicy::from_str("h6d1").unwrap();
+        let (keep, delete) = policy.plan(&snapshots);
+        assert_eq!(keep.len() + delete.len(), snapshots.len());
+        let planned: Vec<_> = keep.iter().map(|&i| snapshots[i].clone()).collect();
+        let check = policy.check_age(&mut snapshots);
+        assert_eq!(planned, check.keep);
+    }
+
+    #[test]
+    fn test_parse_utc_offset() {
+        assert_eq!(parse_utc_offset("-").unwrap(), FixedOffset::east(0));
+        assert_eq!(parse_utc_offset("UTC").unwrap(), FixedOffset::east(0));
+        assert_eq!(parse_utc_offset("+02:00").unwrap(), FixedOffset::east(7200));
+        assert_eq!(parse_utc_offset("-0530").unwrap(), FixedOffset::west(19800));
+        assert_eq!(parse_utc_offset("+01").unwrap(), FixedOffset::east(3600));
+        let err = parse_utc_offset("Europe/Warsaw").unwrap_err();
+        assert_eq!(err.to_string(), "invalid UTC offset 'Europe/Warsaw'");
+        parse_utc_offset("+2").unwrap_err();
+        parse_utc_offset("+99:00").unwrap_err();
+    }
+
+    #[test]
+    fn test_check_age_in_timezone() {
+        // 21:30 and 22:30 UTC are the same day in UTC, but not at +02:00.
+        let start = Utc.ymd(2021, 10, 2).and_hms(21, 30, 0);
+        let mut snapshots: Vec<_> = (0..2)
+            .map(|i| SnapshotMetadata {
+                name: format!("tank@{}", i),
+                created: start + chrono::Duration::hours(i),
+                used: Byte::from_bytes(0),
+            })
+            .collect();
+        let policy = RetentionPolicy::from_str("d2").unwrap();
+        let check = policy.check_age(&mut snapshots);
+        assert_eq!(check.keep.len(), 1);
+        let check = policy.check_age_in(&mut snapshots, &FixedOffset::east(7200));
+        assert_eq!(check.keep.len(), 2);
+    }
+
+    #[test]
+    fn test_age_histogram() {
+        let snapshots = hourly_snapshots(30);
+        let now = Utc.ymd(2021, 10, 3).and_hms(5, 30, 0);
+        let histogram = age_histogram(&snapshots, now);
+        assert_eq!(
+            histogram,
+            vec![
+                ("<1h", 1),
+                ("<1d", 23),
+                ("<1w", 6),
+                ("<1m", 0),
+                ("<1y", 0),
+                (">1y", 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_properties_namespace() {
+        assert_eq!(
+            Properties::new(DEFAULT_NAMESPACE).unwrap(),
+            Properties::default()
+        );
+        let properties = Properties::new("com.example").unwrap();
+        assert_eq!(properties.snapkeep, "com.example:snapkeep");
+        assert_eq!(properties.tz, "com.example:tz");
+        assert_eq!(properties.snapdisable, "com.example:snapdisable");
+        assert_eq!(properties.protect, "com.example:protect");
+        assert_eq!(properties.maxage, "com.example:maxage");
+        let err = Properties::new("com.example:snapkeep").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid property namespace 'com.example:snapkeep'"
+        );
+        Properties::new("").unwrap_err();
+        Properties::new("Com.Example").unwrap_err();
+    }
+
+    #[test]
+    fn test_week_start_from_str() {
+        assert_eq!(WeekStart::from_str("mon").unwrap(), WeekStart::Monday);
+        assert_eq!(WeekStart::from_str("Sunday").unwrap(), WeekStart::Sunday);
+        WeekStart::from_str("fri").unwrap_err();
+    }
+
+    #[test]
+    fn test_check_age_week_start() {
+        // Saturday, Sunday and Monday; noon each day.
+        let mut snapshots: Vec<_> = [2, 3, 4]
+            .iter()
+            .map(|&day| SnapshotMetadata {
+                name: format!("tank@2021-10-{:02}", day),
+                created: Utc.ymd(2021, 10, day).and_hms(12, 0, 0),
+                used: Byte::from_bytes(0),
+            })
+            .collect();
+        let names = |check: AgeCheckResult| -> Vec<String> {
+            check.keep.into_iter().map(|s| s.name).collect()
+        };
+
+        // Monday begins a new week, so Sunday represents the previous week.
+        let mut policy = RetentionPolicy::from_str("w3").unwrap();
+        assert_eq!(
+            names(policy.check_age(&mut snapshots)),
+            vec!["tank@2021-10-04", "tank@2021-10-03"]
+        );
+
+        // Sunday begins a new week, so Saturday represents the previous week.
+        policy.week_start = WeekStart::Sunday;
+        assert_eq!(
+            names(policy.check_age(&mut snapshots)),
+            vec!["tank@2021-10-04", "tank@2021-10-02"]
+        );
+    }
+
+    #[test]
+    fn test_sunday_week_across_new_year() {
+        // The week from Sunday 2021-12-26 to Saturday 2022-01-01 is all one week; the next
+        // begins on Sunday 2022-01-02.
+        let mut snapshots: Vec<_> = [(2021, 12, 26), (2021, 12, 31), (2022, 1, 1), (2022, 1, 2)]
+            .iter()
+            .map(|&(y, m, d)| SnapshotMetadata {
+                name: format!("tank@{}-{:02}-{:02}", y, m, d),
+                created: Utc.ymd(y, m, d).and_hms(12, 0, 0),
+                used: Byte::from_bytes(0),
+            })
+            .collect();
+        let mut policy = RetentionPolicy::from_str("w3").unwrap();
+        policy.week_start = WeekStart::Sunday;
+        let check = policy.check_age(&mut snapshots);
+        let keep: Vec<&str> = check.keep.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(keep, vec!["tank@2022-01-02", "tank@2022-01-01"]);
+        let week = |y, m, d| {
+            Period::Weekly.of(Utc.ymd(y, m, d).and_hms(0, 0, 0), policy.week_start, &utc())
+        };
+        assert_eq!(week(2021, 12, 26), week(2022, 1, 1));
+        assert_ne!(week(2022, 1, 1), week(2022, 1, 2));
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(
+            parse_size("500M").unwrap(),
+            Byte::from(500u64 * 1024 * 1024)
+        );
+        assert_eq!(parse_size("1G").unwrap(), Byte::from(1024u64 * 1024 * 1024));
+        assert_eq!(
+            parse_size("1.5T").unwrap(),
+            Byte::from(1536u64 * 1024 * 1024 * 1024)
+        );
+        assert_eq!(parse_size("4096").unwrap(), Byte::from(4096u64));
+        assert_eq!(parse_size("2KiB").unwrap(), Byte::from(2048u64));
+        assert_eq!(parse_size("1KB").unwrap(), Byte::from(1000u64));
+        parse_size("lots").unwrap_err();
+        parse_size("").unwrap_err();
+        parse_size("G").unwrap_err();
+        parse_size("-1G").unwrap_err();
+    }
+
+    #[test]
+    fn test_policy_diff() {
+        let snapshots = hourly_snapshots(30);
+        let old = RetentionPolicy::from_str("h24").unwrap();
+        let new = RetentionPolicy::from_str("h6d2").unwrap();
+        let diff = old.diff(&new, &snapshots, &FixedOffset::east(0));
+        // h6 drops 18 of the hourlies, but d2 picks one of them up again: the last one
+        // on Oct 2nd, at 23:00.
+        let names =
+            |s: &[SnapshotMetadata]| -> Vec<String> { s.iter().map(|s| s.name.clone()).collect() };
+        assert_eq!(
+            names(&diff.now_deleted),
+            (6..=22)
+                .rev()
+                .map(|i| format!("tank@{}", i))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(names(&diff.now_kept), Vec::<String>::new());
+        let diff = new.diff(&old, &snapshots, &FixedOffset::east(0));
+        assert_eq!(diff.now_deleted, vec![]);
+        assert_eq!(diff.now_kept.len(), 17);
+    }
+
+    #[test]
+    fn test_keep_latest() {
+        let mut snapshots = hourly_snapshots(10);
+        let policy = RetentionPolicy::from_str("n0").unwrap();
+        let mut check = policy.check_age(&mut snapshots);
+        assert_eq!(check.keep.len(), 0);
+        check.keep_latest();
+        assert_eq!(check.keep.len(), 1);
+        assert_eq!(check.keep[0].name, "tank@9");
+        assert_eq!(check.delete.len(), 9);
+
+        // Nothing to do if the newest one is already kept.
+        let policy = RetentionPolicy::from_str("h2").unwrap();
+        let mut check = policy.check_age(&mut snapshots);
+        check.keep_latest();
+        assert_eq!(check.keep.len(), 2);
+        assert_eq!(check.keep[0].name, "tank@9");
+    }
+
+    #[test]
+    fn test_check_age_same_second() {
+        let created = Utc.ymd(2021, 10, 2).and_hms(9, 59, 0);
+        let snapshot = |name: &str| SnapshotMetadata {
+            name: name.to_string(),
+            created,
+            used: Byte::from_bytes(0),
+        };
+        let policy = RetentionPolicy::from_str("h1").unwrap();
+        let mut snapshots = vec![snapshot("tank@a"), snapshot("tank@b")];
+        let check = policy.check_age(&mut snapshots);
+        assert_eq!(check.keep, vec![snapshot("tank@b")]);
+        let mut snapshots = vec![snapshot("tank@b"), snapshot("tank@a")];
+        let check = policy.check_age(&mut snapshots);
+        assert_eq!(check.keep, vec![snapshot("tank@b")]);
+        assert_eq!(check.delete, vec![snapshot("tank@a")]);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30s").unwrap(), Duration::seconds(30));
+        assert_eq!(parse_duration("1h").unwrap(), Duration::hours(1));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::minutes(90));
+        assert_eq!(parse_duration("2w").unwrap(), Duration::weeks(2));
+        assert_eq!(parse_duration("7y").unwrap(), Duration::days(7 * 365));
+        parse_duration("").unwrap_err();
+        parse_duration("30").unwrap_err();
+        parse_duration("h").unwrap_err();
+        parse_duration("1 h").unwrap_err();
+        parse_duration("1M").unwrap_err();
+        let err = parse_duration("99999999999999y").unwrap_err();
+        assert_eq!(err.to_string(), "invalid duration '99999999999999y'");
+    }
+
+    #[test]
+    fn test_is_due() {
+        let now = Utc.ymd(2021, 10, 2).and_hms(10, 0, 2);
+        let day = Duration::days(1);
+        assert!(is_due(None, now, day));
+        assert!(!is_due(
+            Some(Utc.ymd(2021, 10, 2).and_hms(0, 0, 5)),
+            now,
+            day
+        ));
+        assert!(is_due(
+            Some(Utc.ymd(2021, 10, 1).and_hms(23, 59, 0)),
+            now,
+            day
+        ));
+        // Last run a little later in the hour than this one.
+        let last = Utc.ymd(2021, 10, 2).and_hms(9, 0, 30);
+        assert!(is_due(Some(last), now, Duration::hours(1)));
+    }
+
+    #[test]
+    fn test_naming_schemes() {
+        assert_eq!(
+            naming_scheme("tank@2021-10-02T09:59:00Z-autosnap-h24d30", false),
+            "autosnap"
+        );
+        assert_eq!(
+            naming_scheme("tank@zfs-auto-snap_hourly-2021-10-02-0959", false),
+            "zfs-auto-snap_hourly-#-#-#-#"
+        );
+        assert_eq!(
+            naming_scheme("tank@zfs-auto-snap_hourly-2021-10-02-0959", true),
+            "autosnap"
+        );
+        let t = Utc.ymd(2021, 10, 2).and_hms(9, 59, 0);
+        let snapshots: Vec<SnapshotMetadata> = [
+            "tank@2021-10-02T09:59:00Z-autosnap",
+            "tank@2021-10-02T10:59:00Z-autosnap-h24",
+            "tank@zfs-auto-snap_hourly-2021-10-02-0959",
+            "tank@zfs-auto-snap_hourly-2021-10-02-1059",
+            "tank@before-upgrade",
+            "tank/home@2021-10-02T09:59:00Z-autosnap",
+            "tank/home@2021-10-02T10:59:00Z-autosnap",
+            "tank/home@before-upgrade",
+            "tank/home@before-upgrade-2",
+        ]
+        .iter()
+        .map(|name| SnapshotMetadata::new(name, t, 0))
+        .collect();
+        let mixed = mixed_naming_schemes(&snapshots, false);
+        assert_eq!(
+            mixed.into_iter().collect::<Vec<_>>(),
+            vec![(
+                String::from("tank"),
+                vec![
+                    (String::from("autosnap"), 2),
+                    (String::from("zfs-auto-snap_hourly-#-#-#-#"), 2)
+                ]
+            )]
+        );
+        // Taking zfs-auto-snapshot's names too, those are no different from ours.
+        assert!(mixed_naming_schemes(&snapshots, true).is_empty());
+    }
+
+    #[test]
+    fn test_run_summary() {
+        let mut summary = RunSummary::default();
+        summary.record("tank/home", Outcome::Ok);
+        summary.record("tank/db", Outcome::Ok);
+        summary.record(
+            "tank/db",
+            Outcome::Failed("cannot destroy snapshot tank/db@a: dataset is busy\n".into()),
+        );
+        summary.record("tank/db", Outcome::Failed("stopped".into()));
+        summary.record("tank/db", Outcome::Ok);
+        summary.record("scratch", Outcome::Skipped("pool is DEGRADED".into()));
+        let mut more = RunSummary::default();
+        more.record("tank/home", Outcome::Skipped("not due".into()));
+        more.record("tank/media", Outcome::Failed("x".repeat(80)));
+        summary.extend(more);
+        assert_eq!(summary.failed(), 2);
+        assert_eq!(
+            summary.lines(),
+            vec![
+                "scratch\tskipped\tpool is DEGRADED".to_string(),
+                "tank/db\tfailed\tcannot destroy snapshot tank/db@a: dataset is busy".to_string(),
+                "tank/home\tok".to_string(),
+                format!("tank/media\tfailed\t{}...", "x".repeat(60)),
+            ]
+        );
+        assert_eq!(RunSummary::default().failed(), 0);
+    }
+
+    #[test]
+    fn test_policy_map() {
+        let map = PolicyMap::parse(
+            "# The defaults, by dataset.\n\
+             tank/* => h24d7\n\
+             tank/db/* => h24d30; tank/db/scratch => h6\n\
+             \n\
+             tank/media/* => w4m12\n\
+             tank/media/* => @default\n\
+             tank/home?/* => d7\n",
+        )
+        .unwrap();
+        assert_eq!(map.lookup("tank/db/main"), Some("h24d30"));
+        assert_eq!(map.lookup("tank/db/main/logs"), Some("h24d30"));
+        // More specific than tank/db/*, wherever it is in the file.
+        assert_eq!(map.lookup("tank/db/scratch"), Some("h6"));
+        assert_eq!(map.lookup("tank/db"), Some("h24d7"));
+        // Of two as specific, the first.
+        assert_eq!(map.lookup("tank/media/photos"), Some("w4m12"));
+        assert_eq!(map.lookup("tank/home2/alice"), Some("d7"));
+        assert_eq!(map.lookup("tank/home/alice"), Some("h24d7"));
+        assert_eq!(map.lookup("tank"), None);
+        assert_eq!(map.lookup("backup/db/main"), None);
+        assert_eq!(PolicyMap::parse("").unwrap(), PolicyMap::default());
+        assert!(PolicyMap::parse("tank/* h24").is_err());
+        assert!(PolicyMap::parse("tank/* => @nope").is_err());
+    }
+
+    #[test]
+    fn test_next_batch() {
+        let datasets: Vec<String> = ["tank/c", "tank/a", "tank/b", "tank/d"]
+            .iter()
+            .map(|ds| ds.to_string())
+            .collect();
+        assert_eq!(
+            next_batch(&datasets, None, 3),
+            vec!["tank/a", "tank/b", "tank/c"]
+        );
+        assert_eq!(
+            next_batch(&datasets, Some("tank/c"), 3),
+            vec!["tank/d", "tank/a", "tank/b"]
+        );
+        // The cursor's dataset may be gone since.
+        assert_eq!(next_batch(&datasets, Some("tank/bb"), 1), vec!["tank/c"]);
+        assert_eq!(next_batch(&datasets, Some("tank/a"), 10).len(), 4);
+    }
+
+    #[test]
+    fn test_sort_by_hierarchy() {
+        let mut datasets: Vec<String> =
+            ["tank/a/x", "pool", "tank", "tank/b", "tank/a b", "tank/a"]
+                .iter()
+                .map(|ds| ds.to_string())
+                .collect();
+        sort_by_hierarchy(&mut datasets, false);
+        assert_eq!(
+            datasets,
+            vec!["pool", "tank", "tank/a", "tank/a/x", "tank/a b", "tank/b"]
+        );
+        sort_by_hierarchy(&mut datasets, true);
+        assert_eq!(
+            datasets,
+            vec!["pool", "tank/a/x", "tank/a", "tank/a b", "tank/b", "tank"]
+        );
+    }
+
+    #[test]
+    fn test_next_batch_then_sort_by_hierarchy() {
+        // snap --max --children-first: the cursor is the batch's last before sorting; after
+        // it, tank would come last, and the next run would start over from tank/a.
+        let datasets: Vec<String> = ["tank/b", "tank/a/x", "tank", "tank/a"]
+            .iter()
+            .map(|ds| ds.to_string())
+            .collect();
+        let mut batch = next_batch(&datasets, None, 2);
+        let cursor = batch.last().cloned();
+        sort_by_hierarchy(&mut batch, true);
+        assert_eq!(batch, vec!["tank/a", "tank"]);
+        assert_eq!(cursor.as_deref(), Some("tank/a"));
+        assert_eq!(
+            next_batch(&datasets, cursor.as_deref(), 2),
+            vec!["tank/a/x", "tank/b"]
+        );
+    }
+
+    #[test]
+    fn test_sort_for_destroy() {
+        let start = Utc.ymd(2021, 10, 2).and_hms(0, 0, 0);
+        let mut snapshots: Vec<SnapshotMetadata> = [("tank@b", 1), ("tank@c", 2), ("pool@a", 0)]
+            .iter()
+            .map(|(name, hours)| SnapshotMetadata {
+                name: name.to_string(),
+                created: start + Duration::hours(*hours),
+                used: Byte::from_bytes(0),
+            })
+            .collect();
+        snapshots.push(SnapshotMetadata {
+            name: "tank@a".to_string(),
+            ..snapshots[2].clone()
+        });
+        let names = |snapshots: &[SnapshotMetadata]| -> Vec<String> {
+            snapshots.iter().map(|s| s.name.clone()).collect()
+        };
+        sort_for_destroy(&mut snapshots, false);
+        assert_eq!(names(&snapshots), ["pool@a", "tank@a", "tank@b", "tank@c"]);
+        sort_for_destroy(&mut snapshots, true);
+        assert_eq!(names(&snapshots), ["tank@c", "tank@b", "tank@a", "pool@a"]);
+    }
+
+    #[test]
+    fn test_next_run() {
+        let now = Utc.ymd(2021, 10, 2).and_hms(9, 59, 30);
+        assert_eq!(
+            next_run(now, Duration::hours(1)),
+            Utc.ymd(2021, 10, 2).and_hms(10, 0, 0)
+        );
+        assert_eq!(
+            next_run(now, Duration::days(1)),
+            Utc.ymd(2021, 10, 3).and_hms(0, 0, 0)
+        );
+        assert_eq!(
+            next_run(now, Duration::minutes(15)),
+            Utc.ymd(2021, 10, 2).and_hms(10, 0, 0)
+        );
+        // Right on the boundary, it's the next one.
+        let now = Utc.ymd(2021, 10, 2).and_hms(10, 0, 0);
+        assert_eq!(
+            next_run(now, Duration::hours(1)),
+            Utc.ymd(2021, 10, 2).and_hms(11, 0, 0)
+        );
+    }
+
+    fn hourly_snapshots(count: i64) -> Vec<SnapshotMetadata> {
+        let start = Utc.ymd(2021, 10, 2).and_hms(0, 0, 0);
+        (0..count)
+            .map(|i| SnapshotMetadata {
+                name: format!("tank@{}", i),
+                created: start + chrono::Duration::hours(i),
+                used: Byte::from_bytes(0),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pin_candidates() {
+        // A snapshot every 100 days, from 2019 on: 2019 (@0..@3), 2020 (@4..@7), 2021
+        // (@8..@10), 2022 (@11..).
+        let start = Utc.ymd(2019, 1, 1).and_hms(12, 0, 0);
+        let snapshots: Vec<SnapshotMetadata> = (0..13)
+            .map(|i| {
+                let created = start + Duration::days(100 * i);
+                SnapshotMetadata::new(&format!("tank@{}", i), created, 0)
+            })
+            .collect();
+        let now = Utc.ymd(2022, 6, 1).and_hms(0, 0, 0);
+        let pin = |pinned: &[SnapshotMetadata]| -> Vec<String> {
+            pin_candidates(
+                Period::Yearly,
+                &snapshots,
+                pinned,
+                now,
+                WeekStart::Monday,
+                &utc(),
+            )
+            .into_iter()
+            .map(|s| s.name)
+            .collect()
+        };
+        // The last of each year that's over; not yet 2022's.
+        assert_eq!(pin(&[]), vec!["tank@3", "tank@7", "tank@10"]);
+        // A year that has one pinned already is done, whichever one it is.
+        assert_eq!(pin(&snapshots[5..6]), vec!["tank@3", "tank@10"]);
+        // The newest one of each period is what check_age would keep.
+        let policy = RetentionPolicy::from_str("y10").unwrap();
+        let kept: Vec<String> = policy
+            .check_age(&mut snapshots.clone())
+            .keep
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(kept, vec!["tank@12", "tank@10", "tank@7", "tank@3"]);
+    }
+
+    #[test]
+    fn test_only_period() {
+        // Ten days of hourly snapshots: hourly keeps the last day's (216..239), daily one
+        // from each of three days (239, 215, 191).
+        let mut snapshots = hourly_snapshots(240);
+        let policy = RetentionPolicy::from_str("h24d3").unwrap();
+        let full = policy.check_age(&mut snapshots);
+        let names = |check: &AgeCheckResult| -> Vec<String> {
+            let mut names: Vec<String> = check.delete.iter().map(|s| s.name.clone()).collect();
+            names.sort();
+            names
+        };
+        let only = |period| {
+            let mut check = policy.check_age(&mut snapshots.clone());
+            policy.only_period(period, &mut check, &utc());
+            assert_eq!(check.keep.len() + check.delete.len(), 240);
+            check
+        };
+        // What fell out of hourly, but is still within daily's reach.
+        let hourly = only(Period::Hourly);
+        let mut expected: Vec<String> = (192..215).map(|i| format!("tank@{}", i)).collect();
+        expected.sort();
+        assert_eq!(names(&hourly), expected);
+        // The rest, past daily's reach.
+        let daily = only(Period::Daily);
+        let reach = hourly_snapshots(240)[191].created;
+        assert_eq!(daily.delete.len(), 191);
+        assert!(daily.delete.iter().all(|s| s.created < reach));
+        // Between them, just what the whole policy deletes.
+        let mut both: Vec<String> = names(&hourly);
+        both.extend(names(&daily));
+        both.sort();
+        assert_eq!(both, names(&full));
+        // A period the policy doesn't have deletes nothing.
+        assert!(only(Period::Weekly).delete.is_empty());
+        assert_eq!(Period::from_str("daily"), Ok(Period::Daily));
+        assert!(Period::from_str("dialy").is_err());
+    }
+
+    #[test]
+    fn test_check_age_max() {
+        let mut snapshots = hourly_snapshots(60);
+        let uncapped = RetentionPolicy::from_str("h50").unwrap();
+        let check = uncapped.check_age(&mut snapshots);
+        assert_eq!(check.keep.len(), 50);
+
+        let capped = RetentionPolicy::from_str("h50n20").unwrap();
+        let check = capped.check_age(&mut snapshots);
+        assert_eq!(check.keep.len(), 20);
+        assert_eq!(check.delete.len(), 40);
+        // The newest snapshots are the ones that survive the cap.
+        let names: Vec<_> = check.keep.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names.first(), Some(&"tank@59"));
+        assert_eq!(names.last(), Some(&"tank@40"));
+    }
+
+    #[test]
+    fn test_check_age_with_overrides() {
+        let snapshots = hourly_snapshots(48);
+        let policy = RetentionPolicy::from_str("h2").unwrap();
+        let pinned = RetentionPolicy::from_str("y1").unwrap();
+        let mut overrides = vec![None; 48];
+        overrides[3] = Some(pinned.clone());
+        overrides[10] = Some(pinned);
+        let check = policy.check_age_with_overrides(&snapshots, &overrides, &utc());
+        let keep: Vec<&str> = check.keep.iter().map(|s| s.name.as_str()).collect();
+        // The dataset keeps the two newest; of the two pinned, only the newest one is
+        // kept, as they share the yearly bucket.
+        assert_eq!(keep, vec!["tank@47", "tank@46", "tank@10"]);
+        assert_eq!(check.delete.len(), 45);
+    }
+
+    #[test]
+    fn test_parse_anchors() {
+        let text = "# boots\n1633136400\n\n2021-10-02T12:30:00+02:00\n";
+        assert_eq!(
+            parse_anchors(text).unwrap(),
+            vec![
+                Utc.ymd(2021, 10, 2).and_hms(1, 0, 0),
+                Utc.ymd(2021, 10, 2).and_hms(10, 30, 0)
+            ]
+        );
+        assert_eq!(
+            parse_anchors("yesterday").unwrap_err().to_string(),
+            "invalid anchor 'yesterday'"
+        );
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let t = Utc.ymd(2022, 6, 1).and_hms(0, 0, 0);
+        assert_eq!(parse_time("2022-06-01").unwrap(), t);
+        assert_eq!(parse_time("2022-06-01T02:00:00+02:00").unwrap(), t);
+        assert_eq!(parse_time("1654041600").unwrap(), t);
+        assert_eq!(
+            parse_time("2022-06-31").unwrap_err().to_string(),
+            "invalid time '2022-06-31'"
+        );
+    }
+
+    #[test]
+    fn test_check_age_anchors() {
+        let mut snapshots = hourly_snapshots(48);
+        let mut policy = RetentionPolicy::from_str("h2").unwrap();
+        policy.anchors = vec![
+            Utc.ymd(2021, 10, 2).and_hms(10, 30, 0),
+            // Before all snapshots: nothing to keep.
+            Utc.ymd(2021, 10, 1).and_hms(0, 0, 0),
+        ];
+        let check = policy.check_age(&mut snapshots);
+        let keep: Vec<&str> = check.keep.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(keep, vec!["tank@47", "tank@46", "tank@10"]);
+    }
+
+    #[test]
+    fn test_lint() {
+        let lint = |x: &str| RetentionPolicy::from_str(x).unwrap().lint();
+        assert!(lint("h24d30w8m6y1").is_empty());
+        assert_eq!(
+            lint("h24d0w8m6y1"),
+            vec!["daily=0 keeps nothing; leave it out instead"]
+        );
+        assert_eq!(
+            lint("h48"),
+            vec!["hourly is set but not daily: nothing older than about 48 hours is kept"]
+        );
+        assert_eq!(
+            lint("d60w4m6y1"),
+            vec!["weekly=4 adds nothing: daily=60 already covers 60 days"]
+        );
+        assert_eq!(
+            lint("h24d30y1n10"),
+            vec!["max=10 is below the 55 snapshots the periods ask for; the oldest will go"]
+        );
+    }
+
+    #[test]
+    fn test_keep_matching() {
+        let mut snapshots = hourly_snapshots(5);
+        let mut check = RetentionPolicy::from_str("h2")
+            .unwrap()
+            .check_age(&mut snapshots);
+        let rescued = check.keep_matching(|s| s.name == "tank@1");
+        assert_eq!(rescued, vec!["tank@1"]);
+        let keep: Vec<&str> = check.keep.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(keep, vec!["tank@4", "tank@3", "tank@1"]);
+        assert_eq!(check.delete.len(), 2);
+    }
+
+    #[test]
+    fn test_keep_types() {
+        let mut snapshots = hourly_snapshots(5);
+        let mut check = RetentionPolicy::from_str("h2")
+            .unwrap()
+            .check_age(&mut snapshots);
+        let types: HashMap<String, String> = [("tank@0", "pre-deploy"), ("tank@1", "nightly")]
+            .iter()
+            .map(|(name, t)| (name.to_string(), t.to_string()))
+            .collect();
+        assert!(check.keep_types(&types, &[]).is_empty());
+        let rescued = check.keep_types(&types, &[String::from("pre-deploy")]);
+        assert_eq!(rescued, vec!["tank@0"]);
+        let keep: Vec<&str> = check.keep.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(keep, vec!["tank@4", "tank@3", "tank@0"]);
+        let delete: Vec<&str> = check.delete.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(delete, vec!["tank@2", "tank@1"]);
+    }
+
+    #[test]
+    fn test_delete_older_than() {
+        // One snapshot at the start of each year, 2012 to 2021; y10 keeps them all.
+        let mut snapshots: Vec<SnapshotMetadata> = (2012..2022)
+            .map(|year| SnapshotMetadata {
+                name: format!("tank@{}", year),
+                created: Utc.ymd(year, 1, 1).and_hms(0, 0, 0),
+                used: Byte::from_bytes(0),
+            })
+            .collect();
+        let mut check = RetentionPolicy::from_str("y10")
+            .unwrap()
+            .check_age(&mut snapshots);
+        assert_eq!(check.keep.len(), 10);
+        // In mid-2021, maxage=7y cuts off the yearly ones from before 2015.
+        let now = Utc.ymd(2021, 6, 1).and_hms(0, 0, 0);
+        let expired = check.delete_older_than(now - parse_duration("7y").unwrap());
+        assert_eq!(expired, vec!["tank@2014", "tank@2013", "tank@2012"]);
+        assert_eq!(check.keep.len(), 7);
+        assert_eq!(check.keep.last().unwrap().name, "tank@2015");
+        assert_eq!(check.delete[0].name, "tank@2014");
+        // Nothing's older than that now.
+        assert!(check
+            .delete_older_than(Utc.ymd(2012, 1, 1).and_hms(0, 0, 0))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_compact() {
+        let compact = |x: &str| RetentionPolicy::from_str(x).unwrap().compact();
+        assert_eq!(compact("y1m6w8d30h24"), "h24d30w8m6y1");
+        assert_eq!(compact("hourly=24,max=10"), "h24n10");
+        assert_eq!(compact("@archive"), "m24y10");
+        let policy = RetentionPolicy::from_str("daily=30,hourly=24").unwrap();
+        assert_eq!(policy.to_string(), "h24d30");
+        assert_eq!(
+            RetentionPolicy::from_str(&policy.to_string()).unwrap(),
+            policy
+        );
+    }
+
+    #[test]
+    fn test_expiry() {
+        // 48 hourly snapshots, from 2021-10-02 00:00 to 2021-10-03 23:00.
+        let snapshots = hourly_snapshots(48);
+        let policy = RetentionPolicy::from_str("h3d2").unwrap();
+        let expiry = policy.expiry_in(&snapshots, &utc());
+        // Kept as hourly: gone once 3 newer hours began.
+        assert_eq!(expiry[45], Some(Utc.ymd(2021, 10, 4).and_hms(0, 0, 0)));
+        // Kept as both hourly and daily: the daily one lasts longer.
+        assert_eq!(expiry[47], Some(Utc.ymd(2021, 10, 5).and_hms(0, 0, 0)));
+        // Kept as daily: the newest of 2021-10-02.
+        assert_eq!(expiry[23], Some(Utc.ymd(2021, 10, 4).and_hms(0, 0, 0)));
+        assert_eq!(expiry[0], None);
+    }
+
+    #[test]
+    fn test_gaps() {
+        // 48 hourly snapshots, from 2021-10-02 00:00 to 2021-10-03 23:00.
+        let snapshots = hourly_snapshots(48);
+        let now = Utc.ymd(2021, 10, 4).and_hms(12, 0, 0);
+        // Today (2021-10-04), this month and this year aren't over yet, so aren't counted.
+        let required = RetentionPolicy::from_str("d4m2").unwrap();
+        assert_eq!(
+            required.gaps_in(&snapshots, &now, &utc()),
+            vec![
+                ("daily", "2021-10-01".to_string()),
+                ("daily", "2021-09-30".to_string()),
+                ("monthly", "2021-09".to_string()),
+                ("monthly", "2021-08".to_string()),
+            ]
+        );
+        let required = RetentionPolicy::from_str("d2").unwrap();
+        assert!(required.gaps_in(&snapshots, &now, &utc()).is_empty());
+        let required = RetentionPolicy::from_str("y2").unwrap();
+        assert_eq!(
+            required.gaps_in(&snapshots, &now, &utc()),
+            vec![
+                ("yearly", "2020".to_string()),
+                ("yearly", "2019".to_string())
+            ]
+        );
     }
 }
@@ -1,152 +1,2435 @@
 use byte_unit::Byte;
+use chrono::format::{Item, StrftimeItems};
 use chrono::prelude::*;
-use std::collections::HashMap;
+use chrono::{Duration, FixedOffset};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{BufRead, BufReader, IsTerminal, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 
-use zfs_autosnap::zfs::SnapshotMetadata;
-use zfs_autosnap::{zfs, AgeCheckResult, Result, RetentionPolicy, PROPERTY_SNAPKEEP};
+use zfs_autosnap::{
+    age_histogram, dataset_totals, is_due, is_unmanaged, mixed_naming_schemes, next_batch,
+    next_run, parse_anchors, parse_duration, parse_size, parse_time, parse_utc_offset,
+    pin_candidates, render_metrics, show_duration, sort_by_hierarchy, sort_for_destroy, zfs,
+    AgeCheckResult, DatasetTotals, LastRun, Outcome, Period, PolicyMap, Properties, Result,
+    RetentionPolicy, RunState, RunSummary, SnapshotMetadata, WeekStart, DEFAULT_NAMESPACE,
+};
+
+mod output;
+
+use crate::output::{Cell, Formatter, OutputFormat};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-fn gc_find() -> Result<AgeCheckResult> {
-    // List all snapshots we're interested in, group them by dataset, check them against
-    // their parent dataset's retention policy, and aggregate them into the final result,
-    // which can be presented to the user (do_status()) or the garbage collector (do_gc()).
-    let snapshots = zfs::list_snapshots()?;
+// Where snap --max keeps track of the last dataset it took a snapshot of.
+const DEFAULT_CURSOR_FILE: &str = "/var/lib/zfs-autosnap/snap-cursor";
+
+// Where snap and gc keep track of when they last succeeded on each dataset.
+const DEFAULT_STATE_FILE: &str = "/var/lib/zfs-autosnap/state.json";
+
+// How many HTTP requests the daemon answers in between two looks at its schedule.
+const MAX_REQUESTS_PER_TICK: usize = 8;
+
+struct GcOptions {
+    assume_yes: bool,
+    stream: bool,
+    // Stop once (about) this much space is reclaimed.
+    limit_bytes: Option<Byte>,
+    // With limit_bytes, destroy the snapshots with the most written first.
+    by_written: bool,
+    // Before destroying, show how many files each snapshot changed since the one before.
+    show_diff_summary: bool,
+    // Count a snapshot that's gone by the time we destroy it (e.g. some other tool got to
+    // it first) as done, rather than failing.
+    ignore_missing: bool,
+    // Refuse to destroy more than this many snapshots at once (unless forced), since so
+    // many usually means a policy has gone wrong.
+    max_destroy: Option<usize>,
+    force: bool,
+    // Destroy the newest snapshots first, rather than the oldest.
+    newest_first: bool,
+    // Stop destroying once this long has passed, leaving the rest for next time.
+    deadline: Option<std::time::Duration>,
+}
+
+struct StatsOptions {
+    // Add each dataset's usedbysnapshots, next to the sum of its snapshots' used.
+    used_by_snapshots: bool,
+    // Leave out the datasets that have no snapshots, or list only those.
+    exclude_empty: bool,
+    only_empty: bool,
+}
+
+struct SnapOptions {
+    group: Option<String>,
+    property_source: Vec<String>,
+    // End the snapshot names with the datasets' policies, e.g. ...-autosnap-h24d30.
+    tag_policy: bool,
+    // Set on the new snapshots, as the comment property.
+    comment: Option<String>,
+    // What the snapshots are for, e.g. pre-deploy: set as the snaptype property, and
+    // ending their names, e.g. ...-autosnap-pre-deploy.
+    snap_type: Option<String>,
+    // Skip the datasets on pools that aren't ONLINE.
+    require_healthy: bool,
+    // Take at most this many snapshots per run, carrying on next time from where the
+    // cursor file says this run stopped.
+    max: Option<usize>,
+    cursor_file: String,
+    // Snapshot children before their parents, rather than after (see sort_by_hierarchy).
+    children_first: bool,
+}
+
+// What snap did, for the caller to report.
+struct SnapSummary {
+    created: Vec<SnapshotMetadata>,
+    // How it went for each dataset, for --keep-going.
+    outcomes: RunSummary,
+}
+
+// What gc did, for the caller to report.
+#[derive(Default)]
+struct GcSummary {
+    // Just how many were destroyed, and where: with --stream, there may be no end of them.
+    deleted: usize,
+    deleted_in: BTreeSet<String>,
+    // Where gc stopped, and why.
+    failed: Vec<(SnapshotMetadata, String)>,
+    bytes_reclaimed: u128,
+    // Left for next time, once past the deadline.
+    deferred: usize,
+    deferred_bytes: u128,
+    deferred_in: BTreeSet<String>,
+    // With --stream, whether some datasets were left unchecked, past the deadline.
+    unchecked: bool,
+}
+
+impl GcSummary {
+    fn extend(&mut self, other: GcSummary) {
+        self.deleted += other.deleted;
+        self.deleted_in.extend(other.deleted_in);
+        self.failed.extend(other.failed);
+        self.bytes_reclaimed += other.bytes_reclaimed;
+        self.deferred += other.deferred;
+        self.deferred_bytes += other.deferred_bytes;
+        self.deferred_in.extend(other.deferred_in);
+        self.unchecked |= other.unchecked;
+    }
+
+    fn defer(&mut self, s: &SnapshotMetadata) {
+        self.deferred += 1;
+        self.deferred_bytes += s.used.get_bytes();
+        self.deferred_in.insert(s.dataset().to_string());
+    }
+}
+
+struct StatusOptions {
+    histogram: bool,
+    show_empty: bool,
+    from_stdin: bool,
+    output_file: Option<String>,
+    // Just the snapshot names, each followed by a NUL byte, e.g. for xargs -0.
+    null: bool,
+    // Only list the snapshots to keep, or to delete.
+    keep_only: bool,
+    delete_only: bool,
+    // Add roughly when each kept snapshot will be deleted.
+    show_expiry: bool,
+    // Add each snapshot's comment, if it has one (see snap --comment).
+    show_comment: bool,
+    // Only list the snapshots at least (or at most) this old.
+    min_age: Option<Duration>,
+    max_age: Option<Duration>,
+    // Sum up the snapshots by this property's value (e.g. a backup tier), along with the
+    // usual listing.
+    group_by: Option<String>,
+    // Add when snap and gc last succeeded on each dataset (see RunState).
+    show_last_run: bool,
+    // Add what deleting would really reclaim, as zfs destroy -nv works it out.
+    estimate_reclaim: bool,
+    // List at most this many snapshots to keep, and as many to delete.
+    limit: Option<usize>,
+}
+
+// Settings common to all actions.
+struct Context {
+    runner: zfs::Runner,
+    properties: Properties,
+    // The policies for the datasets where snapkeep isn't set.
+    policy_map: PolicyMap,
+    week_start: WeekStart,
+    // Let the policy delete the newest snapshot of a dataset.
+    allow_delete_latest: bool,
+    // See RetentionPolicy.anchors.
+    anchors: Vec<DateTime<Utc>>,
+    // Print sizes as exact numbers of bytes, like zfs list -p, rather than e.g. 13.0 GiB.
+    parseable: bool,
+    // Carry on past a failure on one dataset, and sum up at the end (see RunSummary).
+    keep_going: bool,
+    // Keep the snapshots of these types (see snap --type), whatever the policy says.
+    keep_types: Vec<String>,
+    // Keep the snapshots that have a bookmark of the same name (see zfs::list_bookmarks).
+    keep_bookmarked: bool,
+    // Lay out status and stats as a table, CSV, etc., rather than our usual lines.
+    output: Option<OutputFormat>,
+    // Further snapkeep-like properties (e.g. at.rollc.at:snapkeep-compliance) whose
+    // policies are merged into the dataset's own, where set.
+    policy_properties: Vec<String>,
+    // See RunState.
+    state_file: PathBuf,
+    // Only delete what falls out of this period (see RetentionPolicy::only_period).
+    only_period: Option<Period>,
+    // Never take snapshots, only gc them; e.g. on a backup host, where the datasets are
+    // received, snapkeep property and all.
+    gc_only: bool,
+}
+
+fn group_by_dataset(snapshots: Vec<SnapshotMetadata>) -> HashMap<String, Vec<SnapshotMetadata>> {
     let mut by_dataset = HashMap::<String, Vec<SnapshotMetadata>>::new();
     for snapshot in snapshots {
-        if let Some(dataset_name) = snapshot.name.split('@').next() {
-            let group = by_dataset
-                .entry(dataset_name.to_string())
-                .or_insert_with(Vec::new);
-            group.push(snapshot);
+        let group = by_dataset
+            .entry(snapshot.dataset().to_string())
+            .or_default();
+        group.push(snapshot);
+    }
+    by_dataset
+}
+
+fn parse_policy(ctx: &Context, x: &str) -> Result<RetentionPolicy> {
+    let mut policy = RetentionPolicy::from_str(x)
+        .map_err(|e| format!("unable to parse retention policy: {}", e))?;
+    policy.week_start = ctx.week_start;
+    policy.anchors = ctx.anchors.clone();
+    Ok(policy)
+}
+
+fn parse_property_source(x: &str) -> Result<Vec<String>> {
+    // A comma-separated list of the property sources zfs get reports.
+    x.split(',')
+        .map(|source| match source {
+            "local" | "received" | "inherited" => Ok(source.to_string()),
+            _ => Err(format!("invalid property source '{}'", source).into()),
+        })
+        .collect()
+}
+
+fn dataset_policy(ctx: &Context, dataset: &str) -> Result<RetentionPolicy> {
+    parse_policy(
+        ctx,
+        &zfs::get_snapkeep(&ctx.runner, &ctx.properties, &ctx.policy_map, dataset)?,
+    )
+}
+
+fn dataset_tz(ctx: &Context, dataset: &str) -> Result<FixedOffset> {
+    parse_utc_offset(&zfs::get_property(
+        &ctx.runner,
+        dataset,
+        &ctx.properties.tz,
+    )?)
+}
+
+// The properties gc_find_each looks at on each dataset, besides snapkeep: fetched for all
+// of the datasets at once, rather than with a zfs get (each) per dataset.
+#[derive(Default)]
+struct DatasetValues {
+    tz: HashMap<String, String>,
+    maxage: HashMap<String, String>,
+    // One for each of ctx.policy_properties, in that order.
+    policies: Vec<HashMap<String, String>>,
+}
+
+impl DatasetValues {
+    fn fetch(ctx: &Context, source: &SnapshotSource) -> Result<Self> {
+        // A listing has none of these; see SnapshotSource::policy and blend.
+        if let SnapshotSource::Listing { .. } = source {
+            return Ok(DatasetValues::default());
+        }
+        let values = |property: &str| zfs::list_property_values(&ctx.runner, property);
+        Ok(DatasetValues {
+            tz: values(&ctx.properties.tz)?,
+            maxage: values(&ctx.properties.maxage)?,
+            policies: ctx
+                .policy_properties
+                .iter()
+                .map(|property| values(property))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    fn tz(&self, dataset: &str) -> Result<FixedOffset> {
+        parse_utc_offset(self.tz.get(dataset).map_or("-", String::as_str))
+    }
+
+    fn maxage(&self, ctx: &Context, dataset: &str) -> Result<Option<Duration>> {
+        match self.maxage.get(dataset).map(String::as_str) {
+            None | Some("") => Ok(None),
+            Some(x) => Ok(Some(parse_duration(x).map_err(|e| {
+                format!("dataset {}: {}: {}", dataset, ctx.properties.maxage, e)
+            })?)),
+        }
+    }
+}
+
+// Where gc_find gets the snapshots, and their datasets' retention policies, from.
+enum SnapshotSource {
+    // The running system.
+    Zfs,
+    // A saved zfs list listing (see zfs::parse_snapshot_listing), with each dataset's
+    // policy taken from its snapshots' snapkeep column, and no timezone (UTC).
+    Listing {
+        snapshots: Vec<(SnapshotMetadata, String)>,
+        snapkeep: HashMap<String, String>,
+    },
+}
+
+impl SnapshotSource {
+    fn from_listing(ctx: &Context, text: &str) -> Result<Self> {
+        let snapshots = zfs::parse_snapshot_listing(text, ctx.runner.lenient_used)?;
+        // Snapshots can override their dataset's policy, so the listing alone doesn't say
+        // which value is the dataset's; go with the one most of its snapshots have.
+        let mut counts = HashMap::<(&str, &str), usize>::new();
+        for (snapshot, value) in snapshots.iter() {
+            *counts.entry((snapshot.dataset(), value)).or_default() += 1;
         }
+        // Collecting into a map keeps the last value per dataset, so put the most
+        // common ones last.
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_unstable_by_key(|&((_, value), count)| (count, value));
+        let snapkeep = counts
+            .into_iter()
+            .map(|((dataset, value), _)| (dataset.to_string(), value.to_string()))
+            .collect();
+        Ok(SnapshotSource::Listing {
+            snapshots,
+            snapkeep,
+        })
     }
+
+    fn for_each_group<F>(&self, ctx: &Context, f: F) -> Result<()>
+    where
+        F: FnMut(Vec<(SnapshotMetadata, String)>) -> Result<()>,
+    {
+        // Hand over the snapshots one dataset at a time.
+        match self {
+            SnapshotSource::Zfs => {
+                zfs::for_each_snapshot_group(&ctx.runner, &ctx.properties, &ctx.policy_map, f)
+            }
+            SnapshotSource::Listing { snapshots, .. } => {
+                let mut by_dataset = HashMap::<&str, Vec<(SnapshotMetadata, String)>>::new();
+                for (snapshot, value) in snapshots.iter() {
+                    let group = by_dataset.entry(snapshot.dataset()).or_default();
+                    group.push((snapshot.clone(), value.clone()));
+                }
+                by_dataset.into_values().try_for_each(f)
+            }
+        }
+    }
+
+    fn policy(
+        &self,
+        ctx: &Context,
+        values: &DatasetValues,
+        dataset: &str,
+    ) -> Result<(RetentionPolicy, FixedOffset)> {
+        match self {
+            SnapshotSource::Zfs => Ok((dataset_policy(ctx, dataset)?, values.tz(dataset)?)),
+            SnapshotSource::Listing { snapkeep, .. } => {
+                Ok((parse_policy(ctx, &snapkeep[dataset])?, FixedOffset::east(0)))
+            }
+        }
+    }
+
+    fn blend(
+        &self,
+        ctx: &Context,
+        values: &DatasetValues,
+        dataset: &str,
+        policy: RetentionPolicy,
+    ) -> Result<RetentionPolicy> {
+        // Merge in the policies from ctx.policy_properties; a listing only carries
+        // snapkeep, so there's nothing to merge there.
+        if let SnapshotSource::Listing { .. } = self {
+            return Ok(policy);
+        }
+        let mut blended = policy;
+        for (property, policies) in ctx.policy_properties.iter().zip(&values.policies) {
+            let value = match policies.get(dataset) {
+                Some(value) if !is_unmanaged(value) => value,
+                _ => continue,
+            };
+            let other =
+                parse_policy(ctx, value).map_err(|e| format!("{} {}: {}", dataset, property, e))?;
+            blended = blended.merge(&other);
+        }
+        Ok(blended)
+    }
+}
+
+fn gc_find(ctx: &Context, source: &SnapshotSource) -> Result<AgeCheckResult> {
+    // Aggregate gc_find_each into the final result, which can be presented to the user
+    // (do_status()) or the garbage collector (do_gc()).
     let mut keep = vec![];
     let mut delete = vec![];
-    for (key, group) in by_dataset.iter_mut() {
-        let policy = RetentionPolicy::from_str(&zfs::get_property(key, PROPERTY_SNAPKEEP)?)
-            .map_err(|()| "unable to parse retention policy")?;
-        let check = policy.check_age(group);
+    gc_find_each(ctx, source, |check| {
         keep.extend(check.keep);
         delete.extend(check.delete);
-    }
+        Ok(())
+    })?;
     Ok(AgeCheckResult { keep, delete })
 }
 
+fn trace_phase(ctx: &Context, what: &str, started: Instant) {
+    // With --trace, how long each step took on our side, along with the zfs commands'.
+    if ctx.runner.trace {
+        eprintln!("trace: {} took {:.1?}", what, started.elapsed());
+    }
+}
+
+fn gc_find_each<F>(ctx: &Context, source: &SnapshotSource, mut f: F) -> Result<()>
+where
+    F: FnMut(AgeCheckResult) -> Result<()>,
+{
+    // Go over all snapshots we're interested in, one dataset at a time, and check them
+    // against their parent dataset's retention policy.
+    let origins: HashMap<String, String> = match source {
+        SnapshotSource::Zfs => zfs::list_clones(&ctx.runner)?
+            .into_iter()
+            .map(|(clone, origin)| (origin, clone))
+            .collect(),
+        SnapshotSource::Listing { .. } => HashMap::new(),
+    };
+    let excluded = match source {
+        SnapshotSource::Zfs => {
+            zfs::list_excluded_datasets(&ctx.runner, &ctx.properties, &ctx.policy_map)?
+        }
+        SnapshotSource::Listing { .. } => HashSet::new(),
+    };
+    let bookmarks: HashMap<String, String> = match source {
+        SnapshotSource::Zfs if ctx.keep_bookmarked => {
+            zfs::list_bookmarks(&ctx.runner)?.into_iter().collect()
+        }
+        _ => HashMap::new(),
+    };
+    let values = DatasetValues::fetch(ctx, source)?;
+    source.for_each_group(ctx, |group| {
+        let key = group[0].0.dataset().to_string();
+        if excluded.contains(&key) {
+            return Ok(());
+        }
+        let started = Instant::now();
+        let (policy, tz) = source.policy(ctx, &values, &key)?;
+        // A snapshot whose snapkeep differs from its dataset's has its own policy set,
+        // which adds to the dataset's.
+        let mut parsed = HashMap::<&str, RetentionPolicy>::new();
+        let mut overrides = Vec::with_capacity(group.len());
+        for (snapshot, value) in group.iter() {
+            if !parsed.contains_key(value.as_str()) {
+                let p = parse_policy(ctx, value)
+                    .map_err(|e| format!("snapshot {}: {}", snapshot.name, e))?;
+                parsed.insert(value, p);
+            }
+            let own = &parsed[value.as_str()];
+            overrides.push(if *own != policy {
+                Some(own.clone())
+            } else {
+                None
+            });
+        }
+        let snapshots: Vec<SnapshotMetadata> = group.into_iter().map(|(s, _)| s).collect();
+        let policy = source.blend(ctx, &values, &key, policy)?;
+        let checking = Instant::now();
+        let mut check = policy.check_age_with_overrides(&snapshots, &overrides, &tz);
+        trace_phase(ctx, &format!("check_age on {}", key), checking);
+        if let Some(period) = ctx.only_period {
+            policy.only_period(period, &mut check, &tz);
+        }
+        // A listing has no maxage column either (see below). With --only, the run is about
+        // that period alone, so maxage waits for a plain gc.
+        if matches!(source, SnapshotSource::Zfs) && ctx.only_period.is_none() {
+            if let Some(maxage) = values.maxage(ctx, &key)? {
+                check.delete_older_than(Utc::now() - maxage);
+            }
+        }
+        if !ctx.allow_delete_latest {
+            check.keep_latest();
+        }
+        // Destroying these would fail, or take the clones down with them.
+        for name in check.keep_matching(|s| origins.contains_key(&s.name)) {
+            eprintln!(
+                "warning: keeping {}, the origin of {}",
+                name, origins[&name]
+            );
+        }
+        // Keep the anchors for incremental sends (zfs send -i tank#x ...), so that the
+        // bookmark still has its snapshot to go along with.
+        for name in check.keep_matching(|s| bookmarks.contains_key(&s.name)) {
+            eprintln!("keeping {}, bookmarked as {}", name, bookmarks[&name]);
+        }
+        // A listing has no snaptype column, so there's nothing to go by there.
+        if !ctx.keep_types.is_empty() && matches!(source, SnapshotSource::Zfs) {
+            let types =
+                zfs::list_snapshot_values(&ctx.runner, &ctx.properties.snaptype, &check.delete)?;
+            check.keep_types(&types, &ctx.keep_types);
+        }
+        trace_phase(ctx, &format!("checking {}", key), started);
+        f(check)
+    })
+}
+
+fn find_expiry(
+    ctx: &Context,
+    source: &SnapshotSource,
+    check: &AgeCheckResult,
+) -> Result<HashMap<String, DateTime<Utc>>> {
+    // Roughly when each kept snapshot will go (see RetentionPolicy::expiry_in), as far as
+    // its dataset's policy goes.
+    let all = check
+        .keep
+        .iter()
+        .chain(check.delete.iter())
+        .cloned()
+        .collect();
+    let values = DatasetValues::fetch(ctx, source)?;
+    let mut expiry = HashMap::new();
+    for (key, group) in group_by_dataset(all) {
+        let (policy, tz) = source.policy(ctx, &values, &key)?;
+        let policy = source.blend(ctx, &values, &key, policy)?;
+        for (s, t) in group.iter().zip(policy.expiry_in(&group, &tz)) {
+            if let Some(t) = t {
+                expiry.insert(s.name.clone(), t);
+            }
+        }
+    }
+    Ok(expiry)
+}
+
+fn show_size(ctx: &Context, size: Byte) -> String {
+    if ctx.parseable {
+        size.get_bytes().to_string()
+    } else {
+        size.get_appropriate_unit(true).to_string()
+    }
+}
+
+fn total_used(snapshots: &[SnapshotMetadata]) -> Byte {
+    Byte::from_bytes(snapshots.iter().map(|s| s.used.get_bytes()).sum::<u128>())
+}
+
+fn find_empty(ctx: &Context, check: &AgeCheckResult) -> Result<Vec<String>> {
+    // Which managed datasets have no (managed) snapshots at all? gc_find can't tell, since
+    // it only ever sees datasets through their snapshots; but it's worth knowing, since
+    // it usually means that snap isn't running.
+    let seen: HashSet<&str> = check
+        .keep
+        .iter()
+        .chain(check.delete.iter())
+        .map(|s| s.dataset())
+        .collect();
+    Ok(
+        zfs::list_managed_datasets(&ctx.runner, &ctx.properties, &ctx.policy_map)?
+            .into_iter()
+            .filter(|dataset| !dataset.excluded)
+            .map(|dataset| dataset.name)
+            .filter(|dataset| !seen.contains(dataset.as_str()))
+            .collect(),
+    )
+}
+
 fn do_help() {
     println!("Usage:");
-    println!("    zfs-autosnap <status | snap | gc | help | version>");
+    println!("    zfs-autosnap [options] <status | snap | gc | cycle | daemon> [command options]");
+    println!("    zfs-autosnap [options] <stats | holds | doctor | help>");
+    println!(
+        "    zfs-autosnap [options] pin [--hourly] [--daily] [--weekly] [--monthly] [--yearly]"
+    );
+    println!("    zfs-autosnap [options] set DATASET POLICY");
+    println!("    zfs-autosnap version [--json]");
+    println!("    zfs-autosnap [options] policy diff OLD NEW");
+    println!("    zfs-autosnap [options] policy lint POLICY");
+    println!("    zfs-autosnap [options] policy backtest [--policy POLICY] --at TIME");
+    println!("    zfs-autosnap [options] audit --require POLICY");
+    println!("Options (for all commands):");
+    println!(
+        "    --no-op           print zfs commands that would change anything, don't run them."
+    );
+    println!("    --emit-script     print the zfs commands that would change anything, as a");
+    println!("                      shell script, and don't run them (snap, gc, cycle).");
+    println!("    --namespace NS    use NS:snapkeep, etc. (default: at.rollc.at).");
+    println!("                      also: $ZFS_AUTOSNAP_NAMESPACE.");
+    println!("    --week-start D    weeks begin on D: mon (default, as in ISO 8601), or sun.");
+    println!("    --allow-delete-latest");
+    println!("                      let the policy delete the newest snapshot of a dataset.");
+    println!("    --max-depth N     only manage datasets up to N levels below the pools.");
+    println!("    --host H          run zfs on H (e.g. user@backup) over ssh.");
+    println!("    --trace           print every zfs command (and timings) to stderr.");
+    println!("                      also: $ZFS_AUTOSNAP_TRACE=1.");
+    println!("    --autosnap-only   ignore the snapshots we didn't take (by their names).");
+    println!("    --time-from-name  go by the time in our snapshots' names, not their creation.");
+    println!("    --zfs-auto-snapshot LABEL");
+    println!("                      name snapshots like zfs-auto-snapshot does, e.g.");
+    println!("                      zfs-auto-snap_LABEL-2021-10-02-0959, and count its as ours.");
+    println!("    --timeout D       kill zfs commands that take longer than D (e.g. 30s).");
+    println!("                      also: $ZFS_AUTOSNAP_TIMEOUT.");
+    println!("    --lenient-used    warn about (rather than fail on) bad snapshot sizes.");
+    println!("    --anchors FILE    also keep the newest snapshot before each time in FILE.");
+    println!(
+        "    --state-file P    where snap and gc note when they last succeeded (default: {}).",
+        DEFAULT_STATE_FILE
+    );
+    println!("    --keep-going      carry on past datasets snap/gc failed on, sum up at the end.");
+    println!("    --keep-type T,..  keep the snapshots of types T,.. (see snap --type).");
+    println!("    --gc-only         never take snapshots (snap does nothing), only gc them.");
+    println!("    --keep-bookmarked keep the snapshots that have a bookmark of the same name.");
+    println!("    --policy-map FILE where snapkeep isn't set, use FILE's (GLOB => POLICY).");
+    println!("    --exclude-property P");
+    println!("                      leave alone the datasets where property P is on.");
+    println!("    --policy-properties P,..");
+    println!("                      also keep what the policies in properties P,.. would.");
+    println!("    -p, --parseable   print sizes as exact numbers of bytes.");
+    println!("    --output F        print status and stats as a table, csv, tsv, or json.");
+    println!("Status options:");
+    println!("    --histogram       show how old each dataset's snapshots are.");
+    println!("    --show-empty      list managed datasets that have no snapshots.");
+    println!("    --group-by P      sum up the snapshots by the value of property P.");
+    println!("    --show-last-run   list when snap and gc last succeeded on each dataset.");
+    println!("    --limit N         list at most N snapshots to keep, and N to delete.");
+    println!("    --all             list them all, even with --limit.");
+    println!("    --estimate-reclaim");
+    println!("                      add what deleting would reclaim, by zfs destroy -nv (slow).");
+    println!(
+        "    --from-stdin      read zfs list -Hp -t snapshot -o name,creation,used,<snapkeep>"
+    );
+    println!("                      output from stdin, instead of asking zfs.");
+    println!("    --output-file P   write to P (may contain strftime patterns), not stdout.");
+    println!("    --show-expiry     show roughly when each kept snapshot is due to go.");
+    println!("    --show-comment    show the snapshots' comments (see snap --comment).");
+    println!("    --keep-only       only list the snapshots to keep.");
+    println!("    --delete-only     only list the snapshots to delete.");
+    println!("    --min-age D       only list the snapshots at least D (e.g. 30d) old.");
+    println!("    --max-age D       only list the snapshots at most D old.");
+    println!("    --cache-ttl D     reuse the snapshot listing from a run less than D ago.");
+    println!("    --cache-dir P     where to keep it (default: /var/cache/zfs-autosnap).");
+    println!("    -0, --null        print only snapshot names, NUL-terminated (for xargs -0).");
+    println!("Stats options:");
+    println!("    --used-by-snapshots");
+    println!("                      add each dataset's usedbysnapshots, next to the sum of used.");
+    println!("    --exclude-empty-datasets");
+    println!("                      don't count the datasets that have no snapshots.");
+    println!("    --only-empty-datasets");
+    println!("                      only list the datasets that have no snapshots.");
+    println!("Gc options:");
+    println!("    -y, --assume-yes  don't ask before destroying, even on a terminal.");
+    println!("    --stream          destroy as we go, one dataset at a time, to save memory.");
+    println!("    --limit-bytes S   stop once about S (e.g. 50G) is reclaimed.");
+    println!("    --by-written      with --limit-bytes, go for the most written snapshots first.");
+    println!("    --max-destroy N   refuse to destroy more than N snapshots, unless --force.");
+    println!("    --ignore-missing  don't fail on snapshots that are gone by then.");
+    println!(
+        "    --deadline D      stop destroying after D (e.g. 5m), leaving the rest for later."
+    );
+    println!("    --newest-first    destroy the newest snapshots first, not the oldest.");
+    println!("    --only P          only destroy what falls out of period P, e.g. hourly.");
+    println!("    --show-diff-summary");
+    println!("                      count the files each doomed snapshot changed (slow).");
+    println!("Cycle options (and those of snap and gc):");
+    println!("    --fail-fast       don't gc if snap failed.");
+    println!("Daemon options:");
+    println!("    --snap-every D    take snapshots every D (default: 1h), e.g. 15m, 1h, 1d.");
+    println!("    --gc-every D      collect garbage every D (default: 1d).");
+    println!("    --listen ADDR     serve /status (JSON) and /metrics over HTTP on ADDR.");
+    println!("Snap options:");
+    println!("    --group DS,...    snapshot just these datasets, atomically.");
+    println!("    --tag-policy      end snapshot names with the policy, e.g. -autosnap-h24d30.");
+    println!("    --comment TEXT    set the comment property (at.rollc.at:comment) to TEXT.");
+    println!("    --type T          mark the snapshots as taken for T, e.g. pre-deploy.");
+    println!("    --require-healthy skip the datasets on pools that aren't ONLINE.");
+    println!("    --max N           take at most N snapshots, carrying on from there next time.");
+    println!(
+        "    --cursor-file P   where --max keeps its place (default: {}).",
+        DEFAULT_CURSOR_FILE
+    );
+    println!("    --children-first  snapshot child datasets before their parents, not after.");
+    println!("    --property-source S,...");
+    println!(
+        "                      only where snapkeep is e.g. local or received (not inherited)."
+    );
     println!("Tips:");
     println!("    use 'zfs set at.rollc.at:snapkeep=h24d30w8m6y1 some/dataset' to enable.");
     println!("    use 'zfs set at.rollc.at:snapkeep=- some/dataset@some-snap' to retain.");
+    println!("    use 'zfs set at.rollc.at:snapkeep=off some/dataset/child' to opt out.");
+    println!("    with --namespace NS (or $ZFS_AUTOSNAP_NAMESPACE), it's NS:snapkeep instead.");
     println!("    add 'zfs-autosnap snap' to cron.hourly.");
     println!("    add 'zfs-autosnap gc'   to cron.daily.");
-    do_version();
+    do_version(false);
 }
 
-fn do_version() {
+fn do_version(json: bool) {
+    if json {
+        // For tooling; the version is plain x.y.z, so there's nothing to escape.
+        println!(
+            "{{\"name\": \"zfs-autosnap\", \"version\": \"{}\"}}",
+            VERSION
+        );
+        return;
+    }
     println!(
         "zfs-autosnap v{} <https://github.com/rollcat/zfs-autosnap>",
         VERSION
     );
 }
 
-fn do_status() -> Result<()> {
+fn do_status(ctx: &Context, opts: &StatusOptions) -> Result<()> {
     // Present a nice summary to the user.
-    let check = gc_find()?;
-    if !check.keep.is_empty() {
-        println!(
-            "keep: {}",
-            Byte::from_bytes(check.keep.iter().map(|s| s.used.get_bytes()).sum::<u128>())
-                .get_appropriate_unit(true)
+    if opts.null && (opts.histogram || opts.show_empty) {
+        return Err("--null can't be combined with --histogram or --show-empty".into());
+    }
+    if opts.keep_only && opts.delete_only {
+        return Err("--keep-only and --delete-only are mutually exclusive".into());
+    }
+    if ctx.output.is_some() && (opts.null || opts.histogram || opts.show_empty) {
+        return Err("--output can't be combined with --null, --histogram or --show-empty".into());
+    }
+    if opts.show_comment && opts.from_stdin {
+        return Err("--show-comment can't be combined with --from-stdin".into());
+    }
+    if opts.show_empty && opts.from_stdin {
+        // Which datasets are empty would come from zfs, not from the listing.
+        return Err("--show-empty can't be combined with --from-stdin".into());
+    }
+    if opts.group_by.is_some() && (opts.from_stdin || opts.null || ctx.output.is_some()) {
+        return Err("--group-by can't be combined with --from-stdin, --null or --output".into());
+    }
+    if opts.estimate_reclaim && (opts.from_stdin || opts.null || ctx.output.is_some()) {
+        return Err(
+            "--estimate-reclaim can't be combined with --from-stdin, --null or --output".into(),
         );
-        for s in check.keep {
-            println!(
-                "keep: {}\t{}\t{}",
-                s.name,
-                s.created.to_rfc3339_opts(SecondsFormat::Secs, true),
-                s.used.get_appropriate_unit(true)
+    }
+    if opts.limit.is_some() && (opts.null || ctx.output.is_some()) {
+        return Err("--limit can't be combined with --null or --output".into());
+    }
+    if opts.show_last_run && (opts.null || ctx.output.is_some()) {
+        return Err("--show-last-run can't be combined with --null or --output".into());
+    }
+    let source = if opts.from_stdin {
+        SnapshotSource::from_listing(ctx, &std::io::read_to_string(std::io::stdin())?)?
+    } else {
+        SnapshotSource::Zfs
+    };
+    let check = gc_find(ctx, &source)?;
+    // What to list, after the filters; the histogram, etc. still see everything.
+    let now = Utc::now();
+    let listed = |snapshots: &[SnapshotMetadata], hidden: bool| -> Vec<SnapshotMetadata> {
+        if hidden {
+            return vec![];
+        }
+        snapshots
+            .iter()
+            .filter(|s| {
+                let age = now - s.created;
+                opts.min_age.is_none_or(|min| age >= min)
+                    && opts.max_age.is_none_or(|max| age <= max)
+            })
+            .cloned()
+            .collect()
+    };
+    let keep = listed(&check.keep, opts.delete_only);
+    let delete = listed(&check.delete, opts.keep_only);
+    let (keep, delete) = (&keep[..], &delete[..]);
+    let limit = opts.limit.unwrap_or(usize::MAX);
+    let comments = if opts.show_comment {
+        let listed: Vec<SnapshotMetadata> = keep.iter().chain(delete.iter()).cloned().collect();
+        zfs::list_comments(&ctx.runner, &ctx.properties, &listed)?
+    } else {
+        HashMap::new()
+    };
+    let comment = |s: &SnapshotMetadata| match comments.get(&s.name) {
+        Some(comment) => format!("\t# {}", comment),
+        None => String::new(),
+    };
+    let expiry = if opts.show_expiry {
+        find_expiry(ctx, &source, &check)?
+    } else {
+        HashMap::new()
+    };
+    let mut out = String::new();
+    if let Some(format) = ctx.output {
+        let mut columns = vec!["action", "name", "created", "used"];
+        if opts.show_expiry {
+            columns.push("expires");
+        }
+        if opts.show_comment {
+            columns.push("comment");
+        }
+        let mut f = Formatter::new(&columns);
+        let rows = keep
+            .iter()
+            .map(|s| ("keep", s))
+            .chain(delete.iter().map(|s| ("delete", s)));
+        for (action, s) in rows {
+            let mut row = vec![
+                Cell::Text(action.to_string()),
+                Cell::Text(s.name.clone()),
+                Cell::Time(s.created),
+                Cell::Size(s.used),
+            ];
+            if opts.show_expiry {
+                row.push(Cell::Text(match (action, expiry.get(&s.name)) {
+                    ("keep", Some(t)) => t.format("%Y-%m-%d").to_string(),
+                    _ => "-".to_string(),
+                }));
+            }
+            if opts.show_comment {
+                row.push(Cell::Text(
+                    comments.get(&s.name).cloned().unwrap_or_default(),
+                ));
+            }
+            f.push(row);
+        }
+        out = f.render(format, ctx.parseable);
+    } else if opts.null {
+        for s in keep.iter().chain(delete.iter()) {
+            out.push_str(&s.name);
+            out.push('\0');
+        }
+    } else {
+        if opts.histogram {
+            // How old are the snapshots of each dataset, at a glance.
+            let mut by_dataset = BTreeMap::<&str, Vec<SnapshotMetadata>>::new();
+            for s in check.keep.iter().chain(check.delete.iter()) {
+                by_dataset.entry(s.dataset()).or_default().push(s.clone());
+            }
+            for (dataset, group) in by_dataset {
+                let counts: Vec<String> = age_histogram(&group, now)
+                    .into_iter()
+                    .filter(|&(_, count)| count > 0)
+                    .map(|(label, count)| format!("{}: {}", label, count))
+                    .collect();
+                writeln!(out, "histogram: {}\t{}", dataset, counts.join(", "))?;
+            }
+        }
+        if let Some(property) = &opts.group_by {
+            // How many snapshots, and how much space, each group keeps and would free;
+            // like the histogram, before any filters.
+            let values = zfs::list_all_snapshot_values(&ctx.runner, property)?;
+            let mut by_value = BTreeMap::<&str, [Vec<SnapshotMetadata>; 2]>::new();
+            let all = check
+                .keep
+                .iter()
+                .map(|s| (0, s))
+                .chain(check.delete.iter().map(|s| (1, s)));
+            for (i, s) in all {
+                let value = values.get(&s.name).map_or("-", String::as_str);
+                by_value.entry(value).or_default()[i].push(s.clone());
+            }
+            for (value, [keep, delete]) in by_value {
+                writeln!(
+                    out,
+                    "group: {}\tkeep {}, {}\tdelete {}, {}",
+                    value,
+                    keep.len(),
+                    show_size(ctx, total_used(&keep)),
+                    delete.len(),
+                    show_size(ctx, total_used(&delete))
+                )?;
+            }
+        }
+        if opts.show_last_run {
+            let ago = |t: Option<DateTime<Utc>>| match t {
+                Some(t) => format!("{} ago", show_duration(now - t)),
+                None => String::from("never"),
+            };
+            for (dataset, last) in read_state(ctx).datasets {
+                writeln!(
+                    out,
+                    "last run: {}\tsnap {}\tgc {}",
+                    dataset,
+                    ago(last.snap),
+                    ago(last.gc)
+                )?;
+            }
+        }
+        if opts.show_empty {
+            for dataset in find_empty(ctx, &check)? {
+                writeln!(out, "empty: {}\tno snapshots", dataset)?;
+            }
+        }
+        if !keep.is_empty() {
+            writeln!(out, "keep: {}", show_size(ctx, total_used(keep)))?;
+            for s in keep.iter().take(limit) {
+                write!(
+                    out,
+                    "keep: {}\t{}\t{}",
+                    s.name,
+                    s.created.to_rfc3339_opts(SecondsFormat::Secs, true),
+                    show_size(ctx, s.used)
+                )?;
+                if opts.show_expiry {
+                    match expiry.get(&s.name) {
+                        Some(t) => write!(out, "\texpires ~{}", t.format("%Y-%m-%d"))?,
+                        // Kept for some other reason than the policy's periods.
+                        None => write!(out, "\texpires -")?,
+                    }
+                }
+                writeln!(out, "{}", comment(s))?;
+            }
+            if keep.len() > limit {
+                writeln!(out, "keep: ... and {} more", keep.len() - limit)?;
+            }
+        }
+        if !delete.is_empty() {
+            write!(out, "delete: {}", show_size(ctx, total_used(delete)))?;
+            if opts.estimate_reclaim {
+                let estimate = zfs::estimate_reclaim(&ctx.runner, delete)?;
+                write!(out, "\treclaims {}", show_size(ctx, estimate))?;
+            }
+            writeln!(out)?;
+            for s in delete.iter().take(limit) {
+                writeln!(
+                    out,
+                    "delete: {}\t{}\t{}{}",
+                    s.name,
+                    s.created.to_rfc3339_opts(SecondsFormat::Secs, true),
+                    show_size(ctx, s.used),
+                    comment(s)
+                )?;
+            }
+            if delete.len() > limit {
+                writeln!(out, "delete: ... and {} more", delete.len() - limit)?;
+            }
+        }
+    }
+    match &opts.output_file {
+        Some(path) => write_report(path, &out),
+        None => {
+            print!("{}", out);
+            Ok(())
+        }
+    }
+}
+
+fn write_report(path: &str, report: &str) -> Result<()> {
+    // Write the report to a file rather than stdout, e.g. to keep cron from mailing it.
+    // The path may contain strftime(3) patterns, e.g. status-%Y%m%d.txt, to keep a history.
+    if StrftimeItems::new(path).any(|item| item == Item::Error) {
+        return Err(format!("invalid pattern in output file name: {}", path).into());
+    }
+    let path = PathBuf::from(Utc::now().format(path).to_string());
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("unable to create {}: {}", parent.display(), e))?;
+    }
+    fs::write(&path, report).map_err(|e| format!("unable to write {}: {}", path.display(), e))?;
+    Ok(())
+}
+
+fn do_snap(ctx: &Context, opts: &SnapOptions) -> Result<SnapSummary> {
+    if ctx.gc_only {
+        return Ok(SnapSummary {
+            created: vec![],
+            outcomes: RunSummary::default(),
+        });
+    }
+    let mut user_properties: Vec<(&str, &str)> = vec![];
+    if let Some(comment) = &opts.comment {
+        user_properties.push((&ctx.properties.comment, comment));
+    }
+    if let Some(snap_type) = &opts.snap_type {
+        user_properties.push((&ctx.properties.snaptype, snap_type));
+    }
+    let tag = |dataset: &str| -> Result<Option<String>> {
+        let mut policy = None;
+        if opts.tag_policy {
+            // Always in the compact form: = and , can't be part of a snapshot name.
+            match zfs::get_snapkeep(&ctx.runner, &ctx.properties, &ctx.policy_map, dataset)?
+                .as_str()
+            {
+                x if is_unmanaged(x) => {}
+                x => policy = Some(parse_policy(ctx, x)?.compact()),
+            }
+        }
+        Ok(zfs::snapshot_tag(
+            opts.snap_type.as_deref(),
+            policy.as_deref(),
+        ))
+    };
+    // Whether each pool is ONLINE, as we come across it; so we ask (and warn) only once.
+    let mut health = HashMap::<String, bool>::new();
+    let mut healthy = |dataset: &str| -> Result<bool> {
+        if !opts.require_healthy {
+            return Ok(true);
+        }
+        let pool = dataset.split('/').next().unwrap_or(dataset);
+        if let Some(&ok) = health.get(pool) {
+            return Ok(ok);
+        }
+        let state = zfs::zpool_health(&ctx.runner, pool)?;
+        if state != "ONLINE" {
+            eprintln!(
+                "warning: not snapshotting datasets on {}: pool is {}",
+                pool, state
             );
         }
+        health.insert(pool.to_string(), state == "ONLINE");
+        Ok(state == "ONLINE")
+    };
+    if opts.group.is_some() && opts.max.is_some() {
+        return Err("--max can't be combined with --group".into());
     }
-    if !check.delete.is_empty() {
-        println!(
-            "delete: {}",
-            Byte::from_bytes(
-                check
-                    .delete
-                    .iter()
-                    .map(|s| s.used.get_bytes())
-                    .sum::<u128>()
+    if let Some(group) = &opts.group {
+        // Snapshot just the given datasets, all at once, e.g. for a database spread over
+        // several datasets, that needs to be consistent.
+        let datasets: Vec<&str> = group.split(',').filter(|ds| !ds.is_empty()).collect();
+        // All or nothing, as always with a group.
+        let mut outcomes = RunSummary::default();
+        for ds in datasets.iter() {
+            if !healthy(ds)? {
+                for ds in datasets.iter() {
+                    outcomes.record(ds, Outcome::Skipped("pool isn't ONLINE".into()));
+                }
+                return Ok(SnapSummary {
+                    created: vec![],
+                    outcomes,
+                });
+            }
+        }
+        let tags = datasets
+            .iter()
+            .map(|ds| tag(ds))
+            .collect::<Result<Vec<_>>>()?;
+        let tagged: Vec<(&str, Option<&str>)> = datasets
+            .iter()
+            .zip(tags.iter())
+            .map(|(&ds, tag)| (ds, tag.as_deref()))
+            .collect();
+        let created = zfs::snapshot_many_tagged(&ctx.runner, &tagged, &user_properties)?;
+        for ds in datasets.iter() {
+            outcomes.record(ds, Outcome::Ok);
+        }
+        record_runs(ctx, &outcomes.succeeded(), |last, t| last.snap = Some(t));
+        return Ok(SnapSummary { created, outcomes });
+    }
+    // Perform a snapshot of each managed dataset.
+    let mut datasets = zfs::list_datasets_for_snapshot(
+        &ctx.runner,
+        &ctx.properties,
+        &ctx.policy_map,
+        &opts.property_source,
+    )?;
+    let mut outcomes = RunSummary::default();
+    // Some datasets only want a snapshot every so often (see is_due).
+    let every = zfs::list_property_values(&ctx.runner, &ctx.properties.snapevery)?;
+    if !every.is_empty() {
+        let mut newest = HashMap::<String, DateTime<Utc>>::new();
+        for s in zfs::list_snapshots(&ctx.runner, &ctx.properties, &ctx.policy_map)? {
+            let t = newest.entry(s.dataset().to_string()).or_insert(s.created);
+            *t = (*t).max(s.created);
+        }
+        let now = Utc::now();
+        datasets.retain(
+            |dataset| match every.get(dataset).map(|x| parse_duration(x)) {
+                None => true,
+                Some(Ok(every)) => {
+                    let due = is_due(newest.get(dataset).copied(), now, every);
+                    if !due {
+                        outcomes.record(dataset, Outcome::Skipped("not due yet".into()));
+                    }
+                    due
+                }
+                Some(Err(e)) => {
+                    eprintln!("warning: ignoring snapevery on {}: {}", dataset, e);
+                    true
+                }
+            },
+        );
+    }
+    // With --max, where the next run carries on from: the batch's last in name order, as
+    // next_batch goes by, whatever order they're then taken in.
+    let mut next_cursor = None;
+    if let Some(max) = opts.max {
+        let cursor = fs::read_to_string(&opts.cursor_file).ok();
+        datasets = next_batch(&datasets, cursor.as_deref().map(str::trim), max);
+        next_cursor = datasets.last().cloned();
+    }
+    // Whatever order zfs listed them in, go through them in a predictable one.
+    sort_by_hierarchy(&mut datasets, opts.children_first);
+    let mut created = vec![];
+    for dataset in &datasets {
+        let result = healthy(dataset).and_then(|ok| match ok {
+            false => Ok(None),
+            true => {
+                let tag = tag(dataset)?;
+                zfs::snapshot_many_tagged(
+                    &ctx.runner,
+                    &[(dataset, tag.as_deref())],
+                    &user_properties,
+                )
+                .map(Some)
+            }
+        });
+        match result {
+            Ok(Some(snapshots)) => {
+                created.extend(snapshots);
+                outcomes.record(dataset, Outcome::Ok);
+            }
+            Ok(None) => outcomes.record(dataset, Outcome::Skipped("pool isn't ONLINE".into())),
+            // Carry on with the other datasets; report_snap tells about it.
+            Err(e) if ctx.keep_going => {
+                eprintln!("snapshot of {} failed: {}", dataset, e);
+                outcomes.record(dataset, Outcome::Failed(e.to_string()));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if let Some(last) = &next_cursor {
+        if !ctx.runner.no_op {
+            write_cursor(&opts.cursor_file, last)?;
+        }
+    }
+    record_runs(ctx, &outcomes.succeeded(), |last, t| last.snap = Some(t));
+    Ok(SnapSummary { created, outcomes })
+}
+
+fn read_state(ctx: &Context) -> RunState {
+    // A state file that's missing is as good as empty; one we can't make sense of is
+    // worth a warning, but no more, as it's only ever for reporting.
+    let path = &ctx.state_file;
+    match fs::read_to_string(path) {
+        Ok(text) => RunState::parse(&text).unwrap_or_else(|e| {
+            eprintln!("warning: ignoring {}: {}", path.display(), e);
+            RunState::default()
+        }),
+        Err(_) => RunState::default(),
+    }
+}
+
+fn record_runs(ctx: &Context, datasets: &[&str], update: fn(&mut LastRun, DateTime<Utc>)) {
+    // Note that snap or gc just succeeded on these datasets. The state file is only ever
+    // replaced whole, by renaming, so a concurrent status sees either the old or the new
+    // one; a concurrent snap or gc waits on the lock file, so that neither loses what the
+    // other just noted. Failing to save it doesn't undo the snap or gc, so is only worth a
+    // warning.
+    if ctx.runner.no_op || datasets.is_empty() {
+        return;
+    }
+    let path = &ctx.state_file;
+    let tmp = path.with_extension(format!("tmp.{}", std::process::id()));
+    let saved = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| lock_file(&path.with_extension("lock")))
+        .and_then(|_lock| {
+            let mut state = read_state(ctx);
+            let now = Utc::now();
+            for dataset in datasets {
+                update(state.datasets.entry(dataset.to_string()).or_default(), now);
+            }
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(state.to_json().as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&tmp, path)
+        });
+    if let Err(e) = saved {
+        let _ = fs::remove_file(&tmp);
+        eprintln!("warning: unable to save {}: {}", path.display(), e);
+    }
+}
+
+fn lock_file(path: &std::path::Path) -> std::io::Result<fs::File> {
+    // Held until the file is closed (i.e. dropped).
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    match unsafe { libc::flock(std::os::unix::io::AsRawFd::as_raw_fd(&file), libc::LOCK_EX) } {
+        0 => Ok(file),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+fn write_cursor(path: &str, dataset: &str) -> Result<()> {
+    let path = PathBuf::from(path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("unable to create {}: {}", parent.display(), e))?;
+    }
+    fs::write(&path, format!("{}\n", dataset))
+        .map_err(|e| format!("unable to write {}: {}", path.display(), e).into())
+}
+
+fn say(ctx: &Context, line: &str) {
+    // Report on what snap or gc did; with --emit-script, as comments in the script.
+    match ctx.runner.emit_script {
+        true => println!("# {}", line),
+        false => println!("{}", line),
+    }
+}
+
+fn report_snap(ctx: &Context, summary: &SnapSummary) -> Result<()> {
+    for s in summary.created.iter() {
+        say(ctx, &format!("snapshot: {}", s.name));
+    }
+    report_outcomes(ctx, &summary.outcomes)
+}
+
+fn report_outcomes(ctx: &Context, outcomes: &RunSummary) -> Result<()> {
+    // With --keep-going, one failure doesn't stop the run; so sum up how it went, and
+    // fail at the end if anything did.
+    if !ctx.keep_going {
+        return Ok(());
+    }
+    let lines = outcomes.lines();
+    for line in lines.iter() {
+        say(ctx, &format!("summary: {}", line));
+    }
+    match outcomes.failed() {
+        0 => Ok(()),
+        n => Err(format!("failed on {} of {} datasets", n, lines.len()).into()),
+    }
+}
+
+fn do_gc(ctx: &Context, opts: &GcOptions) -> Result<GcSummary> {
+    // Garbage collection. Find all snapshots to delete, and delete them without asking
+    // twice (unless we're on a terminal). If you need to only check the status, use
+    // do_status.
+    let interactive = !opts.assume_yes && !ctx.runner.no_op && std::io::stdin().is_terminal();
+    let deadline = opts.deadline.map(|d| Instant::now() + d);
+    if opts.by_written && opts.limit_bytes.is_none() {
+        return Err("--by-written needs --limit-bytes".into());
+    }
+    if opts.stream && opts.limit_bytes.is_some() {
+        return Err("--limit-bytes can't be combined with --stream".into());
+    }
+    if opts.stream && opts.max_destroy.is_some() {
+        return Err("--max-destroy can't be combined with --stream".into());
+    }
+    if opts.stream {
+        // There's no telling how much we'd destroy in total, before we're done.
+        if interactive {
+            return Err("--stream can't ask before destroying, pass --assume-yes".into());
+        }
+        let mut summary = GcSummary::default();
+        let mut checked = RunSummary::default();
+        let past_deadline = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        let result = gc_find_each(ctx, &SnapshotSource::Zfs, |check| {
+            note_checked(&mut checked, &check);
+            // Past the deadline, there's no point in going through the rest of the datasets;
+            // this one's were found in time, so those at least are told about.
+            if past_deadline() {
+                check.delete.iter().for_each(|s| summary.defer(s));
+                summary.unchecked = true;
+                return Err("stopped".into());
+            }
+            report_reclaim(ctx, &check.delete);
+            if opts.show_diff_summary {
+                report_diffs(ctx, &check.delete)?;
+            }
+            let mut delete = check.delete;
+            sort_for_destroy(&mut delete, opts.newest_first);
+            summary.extend(gc_destroy(
+                ctx,
+                delete,
+                false,
+                opts.ignore_missing,
+                deadline,
+            )?);
+            // Don't go on to the next dataset after a failure (unless keeping going), nor
+            // once past the deadline; report_gc tells about it.
+            if past_deadline() {
+                summary.unchecked = true;
+                return Err("stopped".into());
+            }
+            match ctx.keep_going || summary.failed.is_empty() {
+                true => Ok(()),
+                false => Err("stopped".into()),
+            }
+        });
+        if summary.failed.is_empty() && !summary.unchecked {
+            result?;
+        }
+        note_failed(&mut checked, &summary);
+        record_runs(ctx, &checked.succeeded(), |last, t| last.gc = Some(t));
+        return Ok(summary);
+    }
+    let check = gc_find(ctx, &SnapshotSource::Zfs)?;
+    let mut delete = check.delete.clone();
+    if let Some(limit) = &opts.limit_bytes {
+        delete = limit_reclaim(ctx, delete, limit, opts.by_written)?;
+    }
+    match opts.max_destroy {
+        Some(max) if delete.len() > max && !opts.force => {
+            return Err(format!(
+                "refusing to destroy {} snapshots, more than --max-destroy {}; \
+                 check the policies, and pass --force if this is right",
+                delete.len(),
+                max
             )
-            .get_appropriate_unit(true)
+            .into());
+        }
+        _ => {}
+    }
+    report_reclaim(ctx, &delete);
+    if opts.show_diff_summary {
+        report_diffs(ctx, &delete)?;
+    }
+    if !delete.is_empty() && interactive {
+        // Someone's running us by hand; make sure they know what's coming.
+        print!(
+            "destroy {} snapshots ({})? [y/N] ",
+            delete.len(),
+            show_size(ctx, total_used(&delete))
         );
-        for s in check.delete {
-            println!(
-                "delete: {}\t{}\t{}",
-                s.name,
-                s.created.to_rfc3339_opts(SecondsFormat::Secs, true),
-                s.used.get_appropriate_unit(true)
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            return Err("aborted, nothing was destroyed".into());
+        }
+    }
+    sort_for_destroy(&mut delete, opts.newest_first);
+    let summary = gc_destroy(ctx, delete, true, opts.ignore_missing, deadline)?;
+    let mut checked = RunSummary::default();
+    note_checked(&mut checked, &check);
+    note_failed(&mut checked, &summary);
+    record_runs(ctx, &checked.succeeded(), |last, t| last.gc = Some(t));
+    Ok(summary)
+}
+
+fn note_checked(outcomes: &mut RunSummary, check: &AgeCheckResult) {
+    // For the state file, gc went fine on every dataset it looked at, unless it failed to
+    // destroy one of its snapshots.
+    for s in check.keep.iter().chain(check.delete.iter()) {
+        outcomes.record(s.dataset(), Outcome::Ok);
+    }
+}
+
+fn note_failed(outcomes: &mut RunSummary, summary: &GcSummary) {
+    for (s, e) in summary.failed.iter() {
+        outcomes.record(s.dataset(), Outcome::Failed(e.clone()));
+    }
+    // Not done with those yet, either.
+    for dataset in summary.deferred_in.iter() {
+        outcomes.record(dataset, Outcome::Failed("past the deadline".into()));
+    }
+}
+
+fn report_reclaim(ctx: &Context, delete: &[SnapshotMetadata]) {
+    // What's about to go from each dataset, at a glance, before the destroying starts;
+    // there's still time for a ^C.
+    let mut by_dataset = BTreeMap::<&str, (usize, u128)>::new();
+    for s in delete {
+        let (count, bytes) = by_dataset.entry(s.dataset()).or_default();
+        *count += 1;
+        *bytes += s.used.get_bytes();
+    }
+    for (dataset, (count, bytes)) in by_dataset {
+        say(
+            ctx,
+            &format!(
+                "reclaim: {}\t{} snapshots, {}",
+                dataset,
+                count,
+                show_size(ctx, Byte::from_bytes(bytes))
+            ),
+        );
+    }
+}
+
+fn report_diffs(ctx: &Context, delete: &[SnapshotMetadata]) -> Result<()> {
+    // What each snapshot about to go captured: its changes since the snapshot before it,
+    // whether that one stays or goes too, and whether or not it's one of ours. A diff
+    // that fails is told about, and the rest go on; they're only for show.
+    let mut datasets: Vec<&str> = delete.iter().map(|s| s.dataset()).collect();
+    datasets.sort_unstable();
+    datasets.dedup();
+    let all = zfs::list_all_snapshots(&ctx.runner, &datasets)?;
+    for s in delete {
+        let group = all.get(s.dataset()).map_or(&[][..], Vec::as_slice);
+        let i = group.iter().position(|g| g.name == s.name).unwrap_or(0);
+        if i == 0 {
+            say(ctx, &format!("diff: {}\tno earlier snapshot", s.name));
+            continue;
+        }
+        match zfs::diff_summary(&ctx.runner, &group[i - 1].name, &s.name) {
+            Ok(diff) => say(
+                ctx,
+                &format!(
+                    "diff: {}\t{} modified, {} added, {} removed, {} renamed",
+                    s.name, diff.modified, diff.added, diff.removed, diff.renamed
+                ),
+            ),
+            Err(e) => say(ctx, &format!("diff: {}\t{}", s.name, e)),
+        }
+    }
+    Ok(())
+}
+
+fn report_gc(ctx: &Context, summary: &GcSummary) -> Result<()> {
+    // Each one destroyed was told about as it went, by gc_destroy; this sums them up.
+    if summary.deleted > 0 {
+        say(
+            ctx,
+            &format!(
+                "delete: {}",
+                show_size(ctx, Byte::from_bytes(summary.bytes_reclaimed))
+            ),
+        );
+    }
+    if summary.deferred > 0 || summary.unchecked {
+        say(
+            ctx,
+            &format!(
+                "deadline: stopped after {} snapshots, deferring {} ({}){}",
+                summary.deleted + summary.failed.len(),
+                summary.deferred,
+                show_size(ctx, Byte::from_bytes(summary.deferred_bytes)),
+                match summary.unchecked {
+                    true => ", and any datasets not checked yet",
+                    false => "",
+                }
+            ),
+        );
+    }
+    if ctx.keep_going {
+        let mut outcomes = RunSummary::default();
+        for dataset in summary.deleted_in.iter() {
+            outcomes.record(dataset, Outcome::Ok);
+        }
+        for (s, e) in summary.failed.iter() {
+            outcomes.record(s.dataset(), Outcome::Failed(format!("{}: {}", s.name, e)));
+        }
+        return report_outcomes(ctx, &outcomes);
+    }
+    match summary.failed.first() {
+        Some((s, e)) => Err(format!("failed to destroy {}: {}", s.name, e).into()),
+        None => Ok(()),
+    }
+}
+
+fn limit_reclaim(
+    ctx: &Context,
+    mut delete: Vec<SnapshotMetadata>,
+    limit: &Byte,
+    by_written: bool,
+) -> Result<Vec<SnapshotMetadata>> {
+    // Pick the snapshots to destroy, until we expect to have reclaimed enough. Either
+    // measure is a guess: destroying one snapshot changes what the others would reclaim.
+    let sizes: HashMap<String, Byte> = if by_written {
+        zfs::list_written(&ctx.runner, &delete)?
+    } else {
+        delete.iter().map(|s| (s.name.clone(), s.used)).collect()
+    };
+    let size = |s: &SnapshotMetadata| sizes.get(&s.name).map_or(0, |b| b.get_bytes());
+    if by_written {
+        delete.sort_by_key(|s| std::cmp::Reverse(size(s)));
+    }
+    let mut reclaimed = 0;
+    let mut picked = vec![];
+    for s in delete {
+        if reclaimed >= limit.get_bytes() {
+            break;
+        }
+        reclaimed += size(&s);
+        picked.push(s);
+    }
+    Ok(picked)
+}
+
+fn gc_destroy(
+    ctx: &Context,
+    snapshots: Vec<SnapshotMetadata>,
+    progress: bool,
+    ignore_missing: bool,
+    deadline: Option<Instant>,
+) -> Result<GcSummary> {
+    // Destroy the snapshots, stopping at the first one that fails. With progress (and on
+    // a terminal, for real), keep a status line going meanwhile. Once past the deadline,
+    // the rest are deferred; a destroy already under way is let finish.
+    let progress = progress && !ctx.runner.no_op && std::io::stdout().is_terminal();
+    let total = snapshots.len();
+    let started = Instant::now();
+    let mut summary = GcSummary::default();
+    for (i, s) in snapshots.into_iter().enumerate() {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            summary.defer(&s);
+            continue;
+        }
+        // Under systemd, also in systemctl status; each one done shows we're not stuck.
+        sd_notify(&format!(
+            "STATUS=destroying {}/{} snapshots\nWATCHDOG=1",
+            i + 1,
+            total
+        ));
+        if progress {
+            print!(
+                "\r\x1b[Kdestroying {}/{} ({} reclaimed)",
+                i + 1,
+                total,
+                show_size(ctx, Byte::from_bytes(summary.bytes_reclaimed))
             );
+            std::io::stdout().flush()?;
+        }
+        let destroyed = match ignore_missing {
+            true => zfs::destroy_snapshot_if_exists(&ctx.runner, s.clone()),
+            false => zfs::destroy_snapshot(&ctx.runner, s.clone()).map(|_| true),
+        };
+        match destroyed {
+            Ok(true) => {
+                if progress {
+                    print!("\r\x1b[K");
+                }
+                say(
+                    ctx,
+                    &format!(
+                        "delete: {}\t{}\t{}",
+                        s.name,
+                        s.created.to_rfc3339_opts(SecondsFormat::Secs, true),
+                        show_size(ctx, s.used)
+                    ),
+                );
+                summary.deleted += 1;
+                summary.deleted_in.insert(s.dataset().to_string());
+                summary.bytes_reclaimed += s.used.get_bytes();
+            }
+            Ok(false) => eprintln!("{} was already destroyed", s.name),
+            Err(e) => {
+                summary.failed.push((s, e.to_string()));
+                if !ctx.keep_going {
+                    break;
+                }
+            }
+        }
+    }
+    if progress && total > 0 {
+        print!("\r\x1b[K");
+        std::io::stdout().flush()?;
+    }
+    trace_phase(ctx, &format!("destroying {} snapshots", total), started);
+    Ok(summary)
+}
+
+fn do_cycle(ctx: &Context, snap: &SnapOptions, gc: &GcOptions, fail_fast: bool) -> Result<()> {
+    // Snapshot, then collect garbage, for a single cron entry. Unless failing fast, one
+    // failing doesn't stop the other from running.
+    let mut failed = vec![];
+    if let Err(e) = do_snap(ctx, snap).and_then(|summary| report_snap(ctx, &summary)) {
+        eprintln!("snap failed: {}", e);
+        if fail_fast {
+            return Err("cycle failed: snap".into());
         }
+        failed.push("snap");
+    }
+    if let Err(e) = do_gc(ctx, gc).and_then(|summary| report_gc(ctx, &summary)) {
+        eprintln!("gc failed: {}", e);
+        failed.push("gc");
+    }
+    if !failed.is_empty() {
+        return Err(format!("cycle failed: {}", failed.join(", ")).into());
     }
     Ok(())
 }
 
-fn do_snap() -> Result<()> {
-    // Perform a snapshot of each managed dataset.
-    for dataset in &zfs::list_datasets_for_snapshot()? {
-        let s = zfs::snapshot(dataset)?;
-        println!("snapshot: {}", s.name);
+fn sd_notify(state: &str) {
+    // Tell systemd how we're doing (see sd_notify(3)), if it's listening: READY=1 once
+    // we're up, STATUS=... for systemctl status, WATCHDOG=1 to show we're not stuck. Best
+    // effort; none of this is worth failing over.
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(path) if !path.is_empty() => path,
+        _ => return,
+    };
+    let socket = match UnixDatagram::unbound() {
+        Ok(socket) => socket,
+        Err(_) => return,
+    };
+    if let Some(name) = path.strip_prefix('@') {
+        // An abstract socket; these only exist on Linux.
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            if let Ok(addr) = std::os::unix::net::SocketAddr::from_abstract_name(name) {
+                let _ = socket.send_to_addr(state.as_bytes(), &addr);
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = name;
+        return;
+    }
+    let _ = socket.send_to(state.as_bytes(), &path);
+}
+
+// Set by the SIGINT/SIGTERM handler; the daemon finishes what it's doing, and quits.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_shutdown_signal(_: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+fn do_daemon(
+    ctx: &Context,
+    snap_every: Duration,
+    gc_every: Duration,
+    listen: Option<&str>,
+) -> Result<()> {
+    // Stay resident, taking snapshots and collecting garbage on schedule, in place of cron.
+    // Runs are aligned to the wall clock (e.g. the top of the hour); if both are due at the
+    // same time, snap goes first. A failed run is reported, and retried on the next one.
+    if snap_every < Duration::seconds(1) || gc_every < Duration::seconds(1) {
+        return Err("daemon intervals must be at least 1s".into());
+    }
+    let handler = on_shutdown_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+    let listener = match listen {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr)
+                .map_err(|e| format!("unable to listen on {}: {}", addr, e))?;
+            listener.set_nonblocking(true)?;
+            Some(listener)
+        }
+        None => None,
+    };
+    let mut next_snap = next_run(Utc::now(), snap_every);
+    let mut next_gc = next_run(Utc::now(), gc_every);
+    let waiting = |next_snap: DateTime<Utc>, next_gc: DateTime<Utc>| {
+        sd_notify(&format!(
+            "STATUS=next snap at {}, gc at {}",
+            next_snap.to_rfc3339_opts(SecondsFormat::Secs, true),
+            next_gc.to_rfc3339_opts(SecondsFormat::Secs, true)
+        ));
+    };
+    // What /status and /metrics answer with: the totals as of the last run, since working
+    // them out afresh (i.e. listing every snapshot) for each request would be too slow.
+    let mut totals = listener.as_ref().map(|_| current_totals(ctx));
+    sd_notify("READY=1");
+    waiting(next_snap, next_gc);
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        // A run that hangs (e.g. on a stuck pool) stops these, and systemd can step in.
+        sd_notify("WATCHDOG=1");
+        let now = Utc::now();
+        if now >= next_snap {
+            sd_notify("STATUS=taking snapshots");
+            if let Err(e) = do_snap(
+                ctx,
+                &SnapOptions {
+                    group: None,
+                    property_source: vec![],
+                    tag_policy: false,
+                    comment: None,
+                    snap_type: None,
+                    require_healthy: false,
+                    max: None,
+                    cursor_file: String::from(DEFAULT_CURSOR_FILE),
+                    children_first: false,
+                },
+            )
+            .and_then(|summary| report_snap(ctx, &summary))
+            {
+                eprintln!("snap failed: {}", e);
+            }
+            if totals.is_some() {
+                totals = Some(current_totals(ctx));
+            }
+            next_snap = next_run(Utc::now(), snap_every);
+            waiting(next_snap, next_gc);
+        } else if now >= next_gc {
+            sd_notify("STATUS=collecting garbage");
+            if let Err(e) = do_gc(
+                ctx,
+                &GcOptions {
+                    assume_yes: true,
+                    stream: false,
+                    limit_bytes: None,
+                    by_written: false,
+                    show_diff_summary: false,
+                    ignore_missing: false,
+                    max_destroy: None,
+                    force: false,
+                    newest_first: false,
+                    deadline: None,
+                },
+            )
+            .and_then(|summary| report_gc(ctx, &summary))
+            {
+                eprintln!("gc failed: {}", e);
+            }
+            if totals.is_some() {
+                totals = Some(current_totals(ctx));
+            }
+            next_gc = next_run(Utc::now(), gc_every);
+            waiting(next_snap, next_gc);
+        } else {
+            if let (Some(listener), Some(totals)) = (&listener, &totals) {
+                serve_requests(ctx, listener, totals);
+            }
+            // Sleep in small steps, to notice signals (and requests) in good time.
+            let wait = (next_snap.min(next_gc) - now).min(Duration::seconds(1));
+            std::thread::sleep(wait.to_std()?);
+        }
     }
+    sd_notify("STOPPING=1");
     Ok(())
 }
 
-fn do_gc() -> Result<()> {
-    // Garbage collection. Find all snapshots to delete, and delete them without asking
-    // twice. If you need to only check the status, use do_status.
-    let check = gc_find()?;
-    if !check.delete.is_empty() {
-        println!(
-            "delete: {}",
-            Byte::from_bytes(
-                check
-                    .delete
-                    .iter()
-                    .map(|s| s.used.get_bytes())
-                    .sum::<u128>()
+type Totals = std::result::Result<BTreeMap<String, DatasetTotals>, String>;
+
+fn current_totals(ctx: &Context) -> Totals {
+    gc_find(ctx, &SnapshotSource::Zfs)
+        .map(|check| dataset_totals(&check))
+        .map_err(|e| e.to_string())
+}
+
+fn serve_requests(ctx: &Context, listener: &TcpListener, totals: &Totals) {
+    // Answer whoever's waiting to GET /status or /metrics, one at a time, in between runs;
+    // only a few at a time, so that a flood of them can't hold up snap or gc. Best effort:
+    // a client that went away, or a bad request, is only worth a warning.
+    for _ in 0..MAX_REQUESTS_PER_TICK {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = serve_request(ctx, stream, totals) {
+                    eprintln!("warning: http: {}", e);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return,
+            Err(e) => {
+                eprintln!("warning: http: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+fn serve_request(ctx: &Context, stream: TcpStream, totals: &Totals) -> Result<()> {
+    // Just enough HTTP/1.0 for a scraper or curl: the request line, and past the headers.
+    // A client that's slow to send or take the page is dropped, rather than waited on.
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(std::time::Duration::from_secs(1)))?;
+    stream.set_write_timeout(Some(std::time::Duration::from_secs(1)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && !line.trim().is_empty() {
+        line.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("").split('?').next().unwrap_or("");
+    let page = match (method, path) {
+        ("GET", "/status") => totals.as_ref().map(|totals| {
+            let mut f =
+                Formatter::new(&["dataset", "keep", "keep_bytes", "delete", "delete_bytes"]);
+            for (dataset, t) in totals {
+                f.push(vec![
+                    Cell::Text(dataset.clone()),
+                    Cell::Count(t.keep),
+                    Cell::Size(Byte::from_bytes(t.keep_bytes)),
+                    Cell::Count(t.delete),
+                    Cell::Size(Byte::from_bytes(t.delete_bytes)),
+                ]);
+            }
+            (
+                "200 OK",
+                "application/json",
+                f.render(OutputFormat::Json, true),
             )
-            .get_appropriate_unit(true)
+        }),
+        ("GET", "/metrics") => totals.as_ref().map(|totals| {
+            let metrics = render_metrics(totals, &read_state(ctx));
+            ("200 OK", "text/plain; version=0.0.4", metrics)
+        }),
+        ("GET", _) => Ok(("404 Not Found", "text/plain", String::from("not found\n"))),
+        _ => Ok((
+            "405 Method Not Allowed",
+            "text/plain",
+            String::from("only GET is supported\n"),
+        )),
+    };
+    let (status, content_type, body) = page.unwrap_or_else(|e| {
+        (
+            "500 Internal Server Error",
+            "text/plain",
+            format!("{}\n", e),
+        )
+    });
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+fn do_doctor(ctx: &Context) -> Result<()> {
+    // Check that everything is in place for snap & gc to work, and say what isn't.
+    let mut failed = 0;
+    let mut check = |passed: bool, what: String| {
+        println!("{}: {}", if passed { "ok" } else { "FAIL" }, what);
+        if !passed {
+            failed += 1;
+        }
+    };
+    match zfs::list_pools(&ctx.runner) {
+        Ok(pools) => check(
+            true,
+            format!("{} runs, pools: {}", ctx.runner.program, pools.join(", ")),
+        ),
+        Err(e) => {
+            // Nothing else would work either.
+            check(false, format!("{} doesn't run: {}", ctx.runner.program, e));
+            return Err("some checks failed".into());
+        }
+    }
+    if let Some(host) = &ctx.runner.host {
+        // Whoever we log in as over there; we can't tell who that is from here.
+        println!(
+            "warn: running on {} over ssh; make sure 'zfs allow' grants {}",
+            host,
+            zfs::PERMISSIONS
+        );
+    } else if unsafe { libc::geteuid() } == 0 {
+        check(true, String::from("running as root"));
+    } else {
+        // Not necessarily a problem, but we have no good way of telling.
+        println!(
+            "warn: not running as root; make sure 'zfs allow' grants {}",
+            zfs::PERMISSIONS
+        );
+    }
+    let datasets = zfs::list_managed_datasets(&ctx.runner, &ctx.properties, &ctx.policy_map)?;
+    check(
+        !datasets.is_empty(),
+        format!(
+            "{} datasets have {} set",
+            datasets.len(),
+            ctx.properties.snapkeep
+        ),
+    );
+    for dataset in datasets {
+        match dataset_policy(ctx, &dataset.name).and_then(|_| dataset_tz(ctx, &dataset.name)) {
+            Ok(_) => check(true, format!("{}: policy is valid", dataset.name)),
+            Err(e) => check(false, format!("{}: {}", dataset.name, e)),
+        }
+    }
+    // Another scheduler (or a second installation) going by the same property makes for
+    // snapshots named in more than one way, and each collecting the other's.
+    let snapshots = zfs::list_snapshots(&ctx.runner, &ctx.properties, &ctx.policy_map)?;
+    let compat = ctx.runner.compat_label.is_some();
+    for (dataset, schemes) in mixed_naming_schemes(&snapshots, compat) {
+        let schemes: Vec<String> = schemes
+            .iter()
+            .map(|(scheme, count)| format!("{} ({})", scheme, count))
+            .collect();
+        println!(
+            "warn: {}: snapshots named in several ways: {}; is another tool using {}?",
+            dataset,
+            schemes.join(", "),
+            ctx.properties.snapkeep
+        );
+    }
+    if failed > 0 {
+        return Err("some checks failed".into());
+    }
+    Ok(())
+}
+
+fn do_stats(ctx: &Context, opts: &StatsOptions) -> Result<()> {
+    // A one-screen summary of everything we manage.
+    if opts.used_by_snapshots && ctx.output.is_some() {
+        return Err("--used-by-snapshots can't be combined with --output".into());
+    }
+    if opts.exclude_empty && opts.only_empty {
+        return Err(
+            "--exclude-empty-datasets and --only-empty-datasets are mutually exclusive".into(),
+        );
+    }
+    if opts.only_empty && (opts.used_by_snapshots || ctx.output.is_some()) {
+        return Err(
+            "--only-empty-datasets can't be combined with --used-by-snapshots or --output".into(),
+        );
+    }
+    let mut datasets = zfs::list_managed_datasets(&ctx.runner, &ctx.properties, &ctx.policy_map)?;
+    let check = gc_find(ctx, &SnapshotSource::Zfs)?;
+    if opts.exclude_empty || opts.only_empty {
+        let empty = find_empty(ctx, &check)?;
+        if opts.only_empty {
+            // Most likely, snap isn't running for these; as status --show-empty lists them.
+            for dataset in empty {
+                println!("empty: {}\tno snapshots", dataset);
+            }
+            return Ok(());
+        }
+        datasets.retain(|dataset| !empty.contains(&dataset.name));
+    }
+    let snapshots: Vec<SnapshotMetadata> = check
+        .keep
+        .iter()
+        .chain(check.delete.iter())
+        .cloned()
+        .collect();
+    let oldest = snapshots.iter().min_by_key(|s| s.created);
+    let newest = snapshots.iter().max_by_key(|s| s.created);
+    if let Some(format) = ctx.output {
+        let mut f = Formatter::new(&["stat", "value"]);
+        let mut stat = |name: &str, value: Cell| f.push(vec![Cell::Text(name.to_string()), value]);
+        stat("datasets", Cell::Count(datasets.len()));
+        stat("snapshots", Cell::Count(snapshots.len()));
+        stat("used", Cell::Size(total_used(&snapshots)));
+        for (name, s) in [("oldest", oldest), ("newest", newest)] {
+            if let Some(s) = s {
+                stat(name, Cell::Text(s.name.clone()));
+                stat(&format!("{}_created", name), Cell::Time(s.created));
+            }
+        }
+        stat("reclaimable", Cell::Size(total_used(&check.delete)));
+        stat("reclaimable_snapshots", Cell::Count(check.delete.len()));
+        print!("{}", f.render(format, ctx.parseable));
+        return Ok(());
+    }
+    println!("datasets: {}", datasets.len());
+    println!("snapshots: {}", snapshots.len());
+    println!("used: {}", show_size(ctx, total_used(&snapshots)));
+    if let Some(oldest) = oldest {
+        println!(
+            "oldest: {}\t{}",
+            oldest.name,
+            oldest.created.to_rfc3339_opts(SecondsFormat::Secs, true)
         );
     }
-    for s in check.delete {
+    if let Some(newest) = newest {
         println!(
-            "delete: {}\t{}\t{}",
-            s.name,
-            s.created.to_rfc3339_opts(SecondsFormat::Secs, true),
-            s.used.get_appropriate_unit(true)
+            "newest: {}\t{}",
+            newest.name,
+            newest.created.to_rfc3339_opts(SecondsFormat::Secs, true)
         );
-        zfs::destroy_snapshot(s)?;
+    }
+    println!(
+        "reclaimable: {}\t{} snapshots",
+        show_size(ctx, total_used(&check.delete)),
+        check.delete.len()
+    );
+    if opts.used_by_snapshots {
+        // Each snapshot's used only counts what no other snapshot shares; zfs keeps the
+        // real total of the dataset's snapshots in usedbysnapshots.
+        let totals = dataset_totals(&check);
+        let names: Vec<&str> = totals.keys().map(String::as_str).collect();
+        let used_by_snapshots = zfs::list_used_by_snapshots(&ctx.runner, &names)?;
+        for (dataset, totals) in totals.iter() {
+            let sum = Byte::from_bytes(totals.keep_bytes + totals.delete_bytes);
+            let by_snapshots = *used_by_snapshots
+                .get(dataset)
+                .ok_or_else(|| format!("no usedbysnapshots for {}", dataset))?;
+            println!(
+                "dataset: {}\tused {}\tusedbysnapshots {}",
+                dataset,
+                show_size(ctx, sum),
+                show_size(ctx, by_snapshots)
+            );
+        }
+    }
+    Ok(())
+}
+
+fn do_policy_diff(ctx: &Context, old: &str, new: &str) -> Result<()> {
+    // What would happen to the current snapshots, if we switched from one policy to
+    // another (on every managed dataset)?
+    let (old, new) = (parse_policy(ctx, old)?, parse_policy(ctx, new)?);
+    let snapshots = zfs::list_snapshots(&ctx.runner, &ctx.properties, &ctx.policy_map)?;
+    let mut by_dataset: Vec<_> = group_by_dataset(snapshots).into_iter().collect();
+    by_dataset.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    for (key, group) in by_dataset {
+        let diff = old.diff(&new, &group, &dataset_tz(ctx, &key)?);
+        for s in diff.now_deleted {
+            println!(
+                "keep->delete: {}\t{}",
+                s.name,
+                s.created.to_rfc3339_opts(SecondsFormat::Secs, true)
+            );
+        }
+        for s in diff.now_kept {
+            println!(
+                "delete->keep: {}\t{}",
+                s.name,
+                s.created.to_rfc3339_opts(SecondsFormat::Secs, true)
+            );
+        }
+    }
+    Ok(())
+}
+
+fn do_policy_backtest(ctx: &Context, policy: Option<&str>, at: DateTime<Utc>) -> Result<()> {
+    // What the policy (or each dataset's own) would have kept, of the snapshots we still
+    // have that were there at the time. Those that have since been destroyed are gone
+    // from the listing, so this can only ever show fewer snapshots than there were.
+    let policy = policy.map(|x| parse_policy(ctx, x)).transpose()?;
+    let snapshots = zfs::list_snapshots(&ctx.runner, &ctx.properties, &ctx.policy_map)?
+        .into_iter()
+        .filter(|s| s.created <= at)
+        .collect();
+    let mut by_dataset: Vec<_> = group_by_dataset(snapshots).into_iter().collect();
+    by_dataset.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    for (key, mut group) in by_dataset {
+        let policy = match &policy {
+            Some(policy) => policy.clone(),
+            None => dataset_policy(ctx, &key)?,
+        };
+        let check = policy.check_age_in(&mut group, &dataset_tz(ctx, &key)?);
+        let all = check
+            .keep
+            .iter()
+            .map(|s| ("keep", s))
+            .chain(check.delete.iter().map(|s| ("delete", s)));
+        for (action, s) in all {
+            println!(
+                "{}: {}\t{}",
+                action,
+                s.name,
+                s.created.to_rfc3339_opts(SecondsFormat::Secs, true)
+            );
+        }
+    }
+    Ok(())
+}
+
+fn do_policy_lint(ctx: &Context, policy: &str) -> Result<()> {
+    // Advice on a policy, before putting it to use.
+    let advice = parse_policy(ctx, policy)?.lint();
+    if advice.is_empty() {
+        println!("ok: {}", policy);
+    }
+    for line in advice {
+        println!("warning: {}", line);
+    }
+    Ok(())
+}
+
+fn do_set(ctx: &Context, dataset: &str, policy: &str) -> Result<()> {
+    // zfs set, with the policy checked first and stored in the compact form; a typo in a
+    // hand-set snapkeep would otherwise only show when gc keeps the wrong snapshots.
+    let policy = RetentionPolicy::parse_strict(policy)
+        .map_err(|e| format!("refusing to set an invalid policy: {}", e))?;
+    let value = policy.to_string();
+    zfs::set_property(&ctx.runner, dataset, &ctx.properties.snapkeep, &value)?;
+    println!("set: {}\t{}", dataset, value);
+    Ok(())
+}
+
+fn do_audit(ctx: &Context, require: &str) -> Result<()> {
+    // Check every managed dataset against a minimum history requirement (e.g. m84: a
+    // snapshot in each of the last 84 months), both as things are now and as they would be
+    // after gc. Periods missing right away are gaps; those only gc would empty are gc-gaps.
+    let required = parse_policy(ctx, require)?;
+    let now = Utc::now();
+    let mut failed = vec![];
+    let mut audit = |dataset: &str, all: &[SnapshotMetadata], kept: &[SnapshotMetadata]| {
+        let tz = dataset_tz(ctx, dataset)?;
+        let gaps = required.gaps_in(all, &now, &tz);
+        for (rule, period) in gaps.iter() {
+            println!("gap: {}\t{} {}", dataset, rule, period);
+        }
+        let after_gc = required.gaps_in(kept, &now, &tz);
+        let gc_gaps: Vec<_> = after_gc.iter().filter(|g| !gaps.contains(g)).collect();
+        for (rule, period) in gc_gaps.iter() {
+            println!("gc-gap: {}\t{} {}", dataset, rule, period);
+        }
+        if after_gc.is_empty() {
+            println!("pass: {}", dataset);
+        } else {
+            println!("fail: {}", dataset);
+            failed.push(dataset.to_string());
+        }
+        Result::Ok(())
+    };
+    let mut seen = HashSet::new();
+    gc_find_each(ctx, &SnapshotSource::Zfs, |check| {
+        let mut all = check.keep.clone();
+        all.extend(check.delete.iter().cloned());
+        let dataset = all[0].dataset().to_string();
+        audit(&dataset, &all, &check.keep)?;
+        seen.insert(dataset);
+        Ok(())
+    })?;
+    for dataset in zfs::list_managed_datasets(&ctx.runner, &ctx.properties, &ctx.policy_map)? {
+        if !seen.contains(&dataset.name) {
+            audit(&dataset.name, &[], &[])?;
+        }
+    }
+    if !failed.is_empty() {
+        return Err(format!("audit failed for {}", failed.join(", ")).into());
+    }
+    Ok(())
+}
+
+fn do_pin(ctx: &Context, periods: &[(&str, Period)]) -> Result<()> {
+    // Mark the snapshot standing for each of these periods that's over (see
+    // pin_candidates) to be retained, with snapkeep=-; so it stays, whatever becomes of
+    // the policy later on.
+    if periods.is_empty() {
+        return Err("pin needs --hourly, --daily, --weekly, --monthly or --yearly".into());
+    }
+    let pinned = group_by_dataset(zfs::list_pinned(&ctx.runner, &ctx.properties)?);
+    let now = Utc::now();
+    zfs::for_each_snapshot_group(&ctx.runner, &ctx.properties, &ctx.policy_map, |group| {
+        let key = group[0].0.dataset().to_string();
+        let tz = dataset_tz(ctx, &key)?;
+        let snapshots: Vec<SnapshotMetadata> = group.into_iter().map(|(s, _)| s).collect();
+        let mut done = pinned.get(&key).cloned().unwrap_or_default();
+        for (name, period) in periods {
+            for s in pin_candidates(*period, &snapshots, &done, now, ctx.week_start, &tz) {
+                zfs::set_property(&ctx.runner, &s.name, &ctx.properties.snapkeep, "-")?;
+                println!("pin: {}\t{}", s.name, name);
+                done.push(s);
+            }
+        }
+        Ok(())
+    })
+}
+
+fn do_holds(ctx: &Context) -> Result<()> {
+    // Show user holds on managed snapshots; these block gc from destroying them.
+    let snapshots = zfs::list_snapshots(&ctx.runner, &ctx.properties, &ctx.policy_map)?;
+    for hold in zfs::list_holds(&ctx.runner, &snapshots)? {
+        println!("hold: {}\t{}", hold.snapshot, hold.tag);
     }
     Ok(())
 }
 
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    // Remove every occurrence of a flag from the arguments; tell if there were any.
+    let before = args.len();
+    args.retain(|arg| arg != name);
+    args.len() != before
+}
+
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>> {
+    // Remove a flag and its value ("--name value" or "--name=value") from the arguments.
+    let prefix = format!("{}=", name);
+    let mut value = None;
+    let mut i = 0;
+    while i < args.len() {
+        if args[i] == name {
+            if i + 1 == args.len() {
+                return Err(format!("missing value for {}", name).into());
+            }
+            value = Some(args.remove(i + 1));
+            args.remove(i);
+        } else if let Some(v) = args[i].strip_prefix(&prefix) {
+            value = Some(v.to_string());
+            args.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+    Ok(value)
+}
+
+fn take_snap_options(args: &mut Vec<String>) -> Result<SnapOptions> {
+    Ok(SnapOptions {
+        group: take_option(args, "--group")?,
+        property_source: match take_option(args, "--property-source")? {
+            Some(x) => parse_property_source(&x)?,
+            None => vec![],
+        },
+        tag_policy: take_flag(args, "--tag-policy"),
+        comment: take_option(args, "--comment")?,
+        snap_type: match take_option(args, "--type")? {
+            Some(x) => Some(zfs::parse_snap_type(&x)?),
+            None => None,
+        },
+        require_healthy: take_flag(args, "--require-healthy"),
+        max: match take_option(args, "--max")? {
+            Some(x) => Some(x.parse().map_err(|_| format!("invalid count '{}'", x))?),
+            None => None,
+        },
+        cursor_file: take_option(args, "--cursor-file")?
+            .unwrap_or_else(|| String::from(DEFAULT_CURSOR_FILE)),
+        children_first: take_flag(args, "--children-first"),
+    })
+}
+
+fn take_gc_options(args: &mut Vec<String>) -> Result<GcOptions> {
+    Ok(GcOptions {
+        assume_yes: take_flag(args, "--assume-yes") | take_flag(args, "-y"),
+        stream: take_flag(args, "--stream"),
+        limit_bytes: match take_option(args, "--limit-bytes")? {
+            Some(x) => Some(parse_size(&x)?),
+            None => None,
+        },
+        by_written: take_flag(args, "--by-written"),
+        show_diff_summary: take_flag(args, "--show-diff-summary"),
+        ignore_missing: take_flag(args, "--ignore-missing"),
+        max_destroy: match take_option(args, "--max-destroy")? {
+            Some(x) => Some(x.parse().map_err(|_| format!("invalid count '{}'", x))?),
+            None => None,
+        },
+        force: take_flag(args, "--force"),
+        newest_first: take_flag(args, "--newest-first"),
+        deadline: match take_option(args, "--deadline")? {
+            Some(x) => Some(parse_duration(&x)?.to_std()?),
+            None => None,
+        },
+    })
+}
+
+fn take_only_period(args: &mut Vec<String>) -> Result<Option<Period>> {
+    // A gc option, but it's for gc_find to go by.
+    match take_option(args, "--only")? {
+        Some(x) => Ok(Some(Period::from_str(&x)?)),
+        None => Ok(None),
+    }
+}
+
+fn check_no_args(args: &[String]) -> Result<()> {
+    // Anything left over after the action and its flags is a mistake; better to stop
+    // than to e.g. run gc while ignoring a misspelled flag.
+    match args.get(1) {
+        Some(arg) => Err(format!("unexpected argument: {}", arg).into()),
+        None => Ok(()),
+    }
+}
+
 fn main() -> Result<()> {
-    let args: Vec<String> = std::env::args().collect();
-    let action = &args.get(1).map(|s| s.as_str());
-    match action {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let namespace = match take_option(&mut args, "--namespace")? {
+        Some(namespace) => namespace,
+        None => std::env::var("ZFS_AUTOSNAP_NAMESPACE")
+            .unwrap_or_else(|_| DEFAULT_NAMESPACE.to_string()),
+    };
+    let timeout = match take_option(&mut args, "--timeout")? {
+        Some(timeout) => Some(timeout),
+        None => std::env::var("ZFS_AUTOSNAP_TIMEOUT").ok(),
+    };
+    let emit_script = take_flag(&mut args, "--emit-script");
+    let ctx = Context {
+        runner: zfs::Runner {
+            no_op: emit_script || take_flag(&mut args, "--no-op"),
+            emit_script,
+            max_depth: match take_option(&mut args, "--max-depth")? {
+                Some(x) => Some(x.parse().map_err(|_| format!("invalid depth '{}'", x))?),
+                None => None,
+            },
+            lenient_used: take_flag(&mut args, "--lenient-used"),
+            trace: take_flag(&mut args, "--trace")
+                || matches!(std::env::var("ZFS_AUTOSNAP_TRACE").as_deref(), Ok(x) if !x.is_empty() && x != "0"),
+            host: take_option(&mut args, "--host")?,
+            autosnap_only: take_flag(&mut args, "--autosnap-only"),
+            timeout: match timeout {
+                Some(x) => Some(parse_duration(&x)?.to_std()?),
+                None => None,
+            },
+            exclude_property: take_option(&mut args, "--exclude-property")?,
+            time_from_name: take_flag(&mut args, "--time-from-name"),
+            compat_label: match take_option(&mut args, "--zfs-auto-snapshot")? {
+                Some(x) => {
+                    Some(zfs::parse_snap_type(&x).map_err(|_| format!("invalid label '{}'", x))?)
+                }
+                None => None,
+            },
+            ..Default::default()
+        },
+        properties: Properties::new(&namespace)?,
+        policy_map: match take_option(&mut args, "--policy-map")? {
+            Some(path) => PolicyMap::parse(
+                &fs::read_to_string(&path)
+                    .map_err(|e| format!("unable to read {}: {}", path, e))?,
+            )?,
+            None => PolicyMap::default(),
+        },
+        week_start: match take_option(&mut args, "--week-start")? {
+            Some(day) => WeekStart::from_str(&day)?,
+            None => WeekStart::default(),
+        },
+        allow_delete_latest: take_flag(&mut args, "--allow-delete-latest"),
+        parseable: take_flag(&mut args, "--parseable") | take_flag(&mut args, "-p"),
+        keep_going: take_flag(&mut args, "--keep-going"),
+        keep_bookmarked: take_flag(&mut args, "--keep-bookmarked"),
+        keep_types: match take_option(&mut args, "--keep-type")? {
+            Some(x) => x.split(',').map(str::to_string).collect(),
+            None => vec![],
+        },
+        output: match take_option(&mut args, "--output")? {
+            Some(x) => Some(OutputFormat::from_str(&x)?),
+            None => None,
+        },
+        policy_properties: match take_option(&mut args, "--policy-properties")? {
+            Some(x) => x.split(',').map(str::to_string).collect(),
+            None => vec![],
+        },
+        only_period: None,
+        gc_only: take_flag(&mut args, "--gc-only"),
+        state_file: PathBuf::from(
+            take_option(&mut args, "--state-file")?
+                .unwrap_or_else(|| String::from(DEFAULT_STATE_FILE)),
+        ),
+        anchors: match take_option(&mut args, "--anchors")? {
+            Some(path) => parse_anchors(
+                &fs::read_to_string(&path)
+                    .map_err(|e| format!("unable to read {}: {}", path, e))?,
+            )?,
+            None => vec![],
+        },
+    };
+    let action = args.first().cloned();
+    if emit_script {
+        if !matches!(action.as_deref(), Some("snap" | "gc" | "cycle")) {
+            return Err("--emit-script only goes with snap, gc or cycle".into());
+        }
+        // Runnable as is, stopping at the first command that fails, as gc would.
+        println!("#!/bin/sh\nset -e");
+    }
+    match action.as_deref() {
         None | Some("help" | "-h" | "--help") => {
             do_help();
             Ok(())
         }
         Some("version" | "-v" | "--version") => {
-            do_version();
+            let json = take_flag(&mut args, "--json");
+            check_no_args(&args)?;
+            do_version(json);
             Ok(())
         }
-        Some("status") => do_status(),
-        Some("snap") => do_snap(),
-        Some("gc") => do_gc(),
+        Some("status") => {
+            // Only status may go by a cached listing: it's for looking, and gc had better
+            // decide on what's there now.
+            let ctx = Context {
+                runner: zfs::Runner {
+                    cache_ttl: match take_option(&mut args, "--cache-ttl")? {
+                        Some(x) => Some(parse_duration(&x)?.to_std()?),
+                        None => None,
+                    },
+                    cache_dir: match take_option(&mut args, "--cache-dir")? {
+                        Some(path) => PathBuf::from(path),
+                        None => ctx.runner.cache_dir.clone(),
+                    },
+                    ..ctx.runner.clone()
+                },
+                ..ctx
+            };
+            let opts = StatusOptions {
+                histogram: take_flag(&mut args, "--histogram"),
+                show_empty: take_flag(&mut args, "--show-empty"),
+                from_stdin: take_flag(&mut args, "--from-stdin"),
+                output_file: take_option(&mut args, "--output-file")?,
+                null: take_flag(&mut args, "--null") | take_flag(&mut args, "-0"),
+                keep_only: take_flag(&mut args, "--keep-only"),
+                delete_only: take_flag(&mut args, "--delete-only"),
+                show_expiry: take_flag(&mut args, "--show-expiry"),
+                show_comment: take_flag(&mut args, "--show-comment"),
+                min_age: match take_option(&mut args, "--min-age")? {
+                    Some(x) => Some(parse_duration(&x)?),
+                    None => None,
+                },
+                max_age: match take_option(&mut args, "--max-age")? {
+                    Some(x) => Some(parse_duration(&x)?),
+                    None => None,
+                },
+                group_by: take_option(&mut args, "--group-by")?,
+                show_last_run: take_flag(&mut args, "--show-last-run"),
+                estimate_reclaim: take_flag(&mut args, "--estimate-reclaim"),
+                limit: match take_option(&mut args, "--limit")? {
+                    // --all undoes a --limit set earlier, e.g. in an alias.
+                    _ if take_flag(&mut args, "--all") => None,
+                    Some(x) => Some(x.parse().map_err(|_| format!("invalid limit '{}'", x))?),
+                    None => None,
+                },
+            };
+            check_no_args(&args)?;
+            do_status(&ctx, &opts)
+        }
+        Some("snap") => {
+            let opts = take_snap_options(&mut args)?;
+            check_no_args(&args)?;
+            report_snap(&ctx, &do_snap(&ctx, &opts)?)
+        }
+        Some("gc") => {
+            let ctx = Context {
+                only_period: take_only_period(&mut args)?,
+                ..ctx
+            };
+            let opts = take_gc_options(&mut args)?;
+            check_no_args(&args)?;
+            report_gc(&ctx, &do_gc(&ctx, &opts)?)
+        }
+        Some("cycle") => {
+            let ctx = Context {
+                only_period: take_only_period(&mut args)?,
+                ..ctx
+            };
+            let snap = take_snap_options(&mut args)?;
+            let gc = take_gc_options(&mut args)?;
+            let fail_fast = take_flag(&mut args, "--fail-fast");
+            check_no_args(&args)?;
+            do_cycle(&ctx, &snap, &gc, fail_fast)
+        }
+        Some("daemon") => {
+            let snap_every = take_option(&mut args, "--snap-every")?;
+            let gc_every = take_option(&mut args, "--gc-every")?;
+            let listen = take_option(&mut args, "--listen")?;
+            check_no_args(&args)?;
+            do_daemon(
+                &ctx,
+                parse_duration(snap_every.as_deref().unwrap_or("1h"))?,
+                parse_duration(gc_every.as_deref().unwrap_or("1d"))?,
+                listen.as_deref(),
+            )
+        }
+        Some("doctor") => {
+            check_no_args(&args)?;
+            do_doctor(&ctx)
+        }
+        Some("stats") => {
+            let opts = StatsOptions {
+                used_by_snapshots: take_flag(&mut args, "--used-by-snapshots"),
+                exclude_empty: take_flag(&mut args, "--exclude-empty-datasets"),
+                only_empty: take_flag(&mut args, "--only-empty-datasets"),
+            };
+            check_no_args(&args)?;
+            do_stats(&ctx, &opts)
+        }
+        Some("policy") => match args.get(1).map(|s| s.as_str()) {
+            Some("diff") if args.len() == 4 => do_policy_diff(&ctx, &args[2], &args[3]),
+            Some("lint") if args.len() == 3 => do_policy_lint(&ctx, &args[2]),
+            Some("backtest") => {
+                let policy = take_option(&mut args, "--policy")?;
+                let at = take_option(&mut args, "--at")?
+                    .ok_or("usage: zfs-autosnap policy backtest [--policy POLICY] --at TIME")?;
+                check_no_args(&args[1..])?;
+                do_policy_backtest(&ctx, policy.as_deref(), parse_time(&at)?)
+            }
+            _ => Err("usage: zfs-autosnap policy <diff OLD NEW | lint POLICY | backtest>".into()),
+        },
+        Some("audit") => {
+            let require = take_option(&mut args, "--require")?
+                .ok_or("usage: zfs-autosnap audit --require POLICY")?;
+            check_no_args(&args)?;
+            do_audit(&ctx, &require)
+        }
+        Some("set") if args.len() == 3 => do_set(&ctx, &args[1], &args[2]),
+        Some("set") => Err("usage: zfs-autosnap set DATASET POLICY".into()),
+        Some("pin") => {
+            let periods: Vec<(&str, Period)> = ["hourly", "daily", "weekly", "monthly", "yearly"]
+                .iter()
+                .copied()
+                .filter(|name| take_flag(&mut args, &format!("--{}", name)))
+                .map(|name| Ok((name, Period::from_str(name)?)))
+                .collect::<Result<_>>()?;
+            check_no_args(&args)?;
+            do_pin(&ctx, &periods)
+        }
+        Some("holds") => {
+            check_no_args(&args)?;
+            do_holds(&ctx)
+        }
         _ => {
             do_help();
             std::process::exit(111);
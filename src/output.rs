@@ -0,0 +1,184 @@
+use byte_unit::Byte;
+use chrono::{DateTime, Utc};
+use std::str::FromStr;
+use zfs_autosnap::json_string;
+
+// How status and stats lay out their results, with --output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    // Aligned columns, for people.
+    Table,
+    Csv,
+    Tsv,
+    // An array of objects, one per row.
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(x: &str) -> std::result::Result<Self, Self::Err> {
+        match x {
+            "table" => Ok(OutputFormat::Table),
+            "csv" => Ok(OutputFormat::Csv),
+            "tsv" => Ok(OutputFormat::Tsv),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("invalid output format '{}'", x)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Cell {
+    Text(String),
+    Time(DateTime<Utc>),
+    Size(Byte),
+    Count(usize),
+}
+
+// Rows of cells under named columns, rendered in any of the OutputFormats.
+#[derive(Debug)]
+pub struct Formatter {
+    pub columns: Vec<&'static str>,
+    pub rows: Vec<Vec<Cell>>,
+}
+
+impl Formatter {
+    pub fn new(columns: &[&'static str]) -> Self {
+        Formatter {
+            columns: columns.to_vec(),
+            rows: vec![],
+        }
+    }
+
+    pub fn push(&mut self, row: Vec<Cell>) {
+        self.rows.push(row);
+    }
+
+    // Sizes are always exact numbers of bytes, except in a table, unless parseable.
+    pub fn render(&self, format: OutputFormat, parseable: bool) -> String {
+        let show = |cell: &Cell| match cell {
+            Cell::Text(x) => x.clone(),
+            Cell::Time(t) => t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            Cell::Size(size) if format == OutputFormat::Table && !parseable => {
+                size.get_appropriate_unit(true).to_string()
+            }
+            Cell::Size(size) => size.get_bytes().to_string(),
+            Cell::Count(n) => n.to_string(),
+        };
+        let mut out = String::new();
+        match format {
+            OutputFormat::Table => {
+                let cells: Vec<Vec<String>> = self
+                    .rows
+                    .iter()
+                    .map(|row| row.iter().map(show).collect())
+                    .collect();
+                let mut widths: Vec<usize> = self.columns.iter().map(|c| c.len()).collect();
+                for row in &cells {
+                    for (width, cell) in widths.iter_mut().zip(row) {
+                        *width = (*width).max(cell.chars().count());
+                    }
+                }
+                let header: Vec<String> = self.columns.iter().map(|c| c.to_uppercase()).collect();
+                for row in std::iter::once(&header).chain(cells.iter()) {
+                    let line: Vec<String> = row
+                        .iter()
+                        .zip(&widths)
+                        .map(|(cell, width)| format!("{:1$}", cell, width))
+                        .collect();
+                    out.push_str(line.join("  ").trim_end());
+                    out.push('\n');
+                }
+            }
+            OutputFormat::Csv | OutputFormat::Tsv => {
+                // zfs names can't have tabs or newlines, but CSV also has to mind commas
+                // and quotes, which can be in comments.
+                let (separator, quote): (&str, fn(&str) -> String) = match format {
+                    OutputFormat::Csv => (",", csv_quote),
+                    _ => ("\t", |x| x.replace(['\t', '\n'], " ")),
+                };
+                let header: Vec<String> = self.columns.iter().map(|c| quote(c)).collect();
+                out.push_str(&header.join(separator));
+                out.push('\n');
+                for row in &self.rows {
+                    let line: Vec<String> = row.iter().map(|cell| quote(&show(cell))).collect();
+                    out.push_str(&line.join(separator));
+                    out.push('\n');
+                }
+            }
+            OutputFormat::Json => {
+                let objects: Vec<String> = self
+                    .rows
+                    .iter()
+                    .map(|row| {
+                        let fields: Vec<String> = self
+                            .columns
+                            .iter()
+                            .zip(row)
+                            .map(|(column, cell)| {
+                                let value = match cell {
+                                    Cell::Size(_) | Cell::Count(_) => show(cell),
+                                    _ => json_string(&show(cell)),
+                                };
+                                format!("{}: {}", json_string(column), value)
+                            })
+                            .collect();
+                        format!("{{{}}}", fields.join(", "))
+                    })
+                    .collect();
+                out.push_str(&format!("[{}]\n", objects.join(",\n ")));
+            }
+        }
+        out
+    }
+}
+
+fn csv_quote(x: &str) -> String {
+    // As in RFC 4180: quoted only if need be, with any quotes doubled.
+    if x.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", x.replace('"', "\"\""))
+    } else {
+        x.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formatter() {
+        let mut f = Formatter::new(&["name", "used", "comment"]);
+        f.push(vec![
+            Cell::Text("tank@a".to_string()),
+            Cell::Size(Byte::from_bytes(2048)),
+            Cell::Text("before \"upgrade\", v2".to_string()),
+        ]);
+        f.push(vec![
+            Cell::Text("tank/home@b".to_string()),
+            Cell::Size(Byte::from_bytes(0)),
+            Cell::Text(String::new()),
+        ]);
+        assert_eq!(
+            f.render(OutputFormat::Csv, false),
+            "name,used,comment\ntank@a,2048,\"before \"\"upgrade\"\", v2\"\ntank/home@b,0,\n"
+        );
+        assert_eq!(
+            f.render(OutputFormat::Tsv, false),
+            "name\tused\tcomment\ntank@a\t2048\tbefore \"upgrade\", v2\ntank/home@b\t0\t\n"
+        );
+        assert_eq!(
+            f.render(OutputFormat::Table, false),
+            "NAME         USED      COMMENT\n\
+             tank@a       2.00 KiB  before \"upgrade\", v2\n\
+             tank/home@b  0 B\n"
+        );
+        assert_eq!(
+            f.render(OutputFormat::Json, false),
+            "[{\"name\": \"tank@a\", \"used\": 2048, \"comment\": \"before \\\"upgrade\\\", v2\"},\n \
+             {\"name\": \"tank/home@b\", \"used\": 0, \"comment\": \"\"}]\n"
+        );
+        assert!(OutputFormat::from_str("xml").is_err());
+    }
+}
@@ -1,7 +1,18 @@
 use byte_unit::Byte;
 use chrono::prelude::*;
 
-use crate::{Result, PROPERTY_SNAPKEEP};
+use crate::{is_unmanaged, parse_size, PolicyMap, Properties, Result, RetentionPolicy};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use subprocess::ExitStatus;
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct SnapshotMetadata {
@@ -10,56 +21,587 @@ pub struct SnapshotMetadata {
     pub used: Byte,
 }
 
-pub fn snapshot(dataset: &str) -> Result<SnapshotMetadata> {
+impl SnapshotMetadata {
+    // E.g. for fixtures: SnapshotMetadata::new("tank@first", created, 0).
+    pub fn new(name: &str, created: DateTime<Utc>, used_bytes: u128) -> Self {
+        SnapshotMetadata {
+            name: name.to_string(),
+            created,
+            used: Byte::from_bytes(used_bytes),
+        }
+    }
+
+    // The name of the dataset this is a snapshot of.
+    pub fn dataset(&self) -> &str {
+        self.name.split('@').next().unwrap_or(&self.name)
+    }
+}
+
+// Everything that affects how zfs(8) gets invoked (and how its output is read), shared by
+// all the commands below.
+#[derive(Debug, Clone)]
+pub struct Runner {
+    // The zfs(8) binary to run.
+    pub program: String,
+    // The zpool(8) binary, likewise.
+    pub zpool: String,
+    // Log the commands that would change anything (snapshot, destroy), instead of
+    // running them; commands that only read are still run.
+    pub no_op: bool,
+    // With no_op, print those commands to stdout as lines of a shell script, quoted as
+    // need be, rather than logging them.
+    pub emit_script: bool,
+    // Only look this many levels deep below the pools (0 for just the pools themselves),
+    // when listing datasets and snapshots.
+    pub max_depth: Option<u32>,
+    // Warn about, rather than fail on, a snapshot's used size we can't make sense of
+    // (e.g. some future zfs output format), and count it as 0.
+    pub lenient_used: bool,
+    // Print every zfs command to stderr before running it, and how long it took; the
+    // command line tool also times gc's own steps by it.
+    pub trace: bool,
+    // Run zfs on this host (e.g. user@backup) over ssh(1), rather than locally.
+    pub host: Option<String>,
+    // Only consider the snapshots we took (by their names, see is_autosnap_name), leaving
+    // out the rest as early as possible; for pools with lots of other snapshots.
+    pub autosnap_only: bool,
+    // Kill any zfs command that takes longer than this (e.g. on a stuck pool), and fail.
+    pub timeout: Option<std::time::Duration>,
+    // Reuse a snapshot listing taken less than this long ago, saved in cache_dir; e.g. for
+    // a monitoring agent that runs status every so often.
+    pub cache_ttl: Option<std::time::Duration>,
+    pub cache_dir: PathBuf,
+    // Leave alone the datasets where this (boolean) property is on, e.g. at.rollc.at:skip,
+    // as if they weren't managed: neither snap nor gc touches them.
+    pub exclude_property: Option<String>,
+    // Name new snapshots the way zfs-auto-snapshot(8) does, with this label, e.g.
+    // zfs-auto-snap_hourly-2021-10-02-0959; and with autosnap_only, count its snapshots
+    // as ours. For moving over from it, without losing track of what it took.
+    pub compat_label: Option<String>,
+    // Go by the time in our own snapshots' names (see name_timestamp), rather than their
+    // creation property; the others still go by the property. With compat_label, that
+    // includes the zfs-auto-snap_* ones.
+    pub time_from_name: bool,
+}
+
+impl Default for Runner {
+    fn default() -> Self {
+        Runner {
+            program: String::from("zfs"),
+            zpool: String::from("zpool"),
+            no_op: false,
+            emit_script: false,
+            max_depth: None,
+            lenient_used: false,
+            trace: false,
+            host: None,
+            autosnap_only: false,
+            timeout: None,
+            cache_ttl: None,
+            cache_dir: PathBuf::from("/var/cache/zfs-autosnap"),
+            exclude_property: None,
+            compat_label: None,
+            time_from_name: false,
+        }
+    }
+}
+
+impl Runner {
+    // The zfs list -d arguments for max_depth. A dataset's snapshots are one level below
+    // the dataset itself, so pass 1 for extra when listing snapshots.
+    fn depth_args(&self, extra: u32) -> Vec<String> {
+        match self.max_depth {
+            Some(depth) => vec![String::from("-d"), (depth + extra).to_string()],
+            None => vec![],
+        }
+    }
+
+    // Whether we took this snapshot, going by its name (see autosnap_only).
+    fn is_ours(&self, name: &str) -> bool {
+        is_autosnap_name(name) || (self.compat_label.is_some() && is_zfs_auto_snap_name(name))
+    }
+}
+
+// Where the policy map's policies go: wherever snapkeep isn't set at all, neither on the
+// dataset (nor inherited from a parent) nor on the snapshot itself. A snapkeep=- that is
+// set somewhere (e.g. by pin) still means to keep the snapshot, map or not.
+#[derive(Debug, Default)]
+struct PolicyDefaults<'a> {
+    map: Option<&'a PolicyMap>,
+    // The datasets that snapkeep isn't set for, from anywhere.
+    unset: HashSet<String>,
+    // The snapshots that have a snapkeep of their own.
+    set_on_snapshot: HashSet<String>,
+}
+
+impl<'a> PolicyDefaults<'a> {
+    fn new(runner: &Runner, properties: &Properties, map: &'a PolicyMap) -> Result<Self> {
+        // Without a map, there's nothing to fill in, so no need to ask.
+        if *map == PolicyMap::default() {
+            return Ok(PolicyDefaults::default());
+        }
+        let datasets = call_property_sources(runner, "filesystem,volume", &[], properties)?;
+        let snapshots =
+            call_property_sources(runner, "snapshot", &["-s", "local,received"], properties)?;
+        Ok(PolicyDefaults {
+            map: Some(map),
+            unset: unset_names(&datasets),
+            set_on_snapshot: snapshots.into_iter().map(|line| line[0].clone()).collect(),
+        })
+    }
+
+    // Fill in the map's policy for an unset snapkeep value (e.g. in a zfs list column) of
+    // the dataset; for a snapshot, that of its dataset.
+    fn fill(&self, name: &str, snapkeep: &mut String) {
+        let map = match self.map {
+            Some(map) if snapkeep == "-" => map,
+            _ => return,
+        };
+        let dataset = name.split('@').next().unwrap_or(name);
+        if !self.unset.contains(dataset) || self.set_on_snapshot.contains(name) {
+            return;
+        }
+        if let Some(policy) = map.lookup(dataset) {
+            *snapkeep = policy.to_string();
+        }
+    }
+}
+
+fn call_property_sources(
+    runner: &Runner,
+    types: &str,
+    sources: &[&str],
+    properties: &Properties,
+) -> Result<Vec<Vec<String>>> {
+    // zfs get -H -t $types [-s $sources] -o name,value,source at.rollc.at:snapkeep
+    let depth = runner.depth_args(if types == "snapshot" { 1 } else { 0 });
+    let mut args = vec!["-t", types];
+    args.extend(sources);
+    args.extend(["-o", "name,value,source", &properties.snapkeep]);
+    args.extend(depth.iter().map(String::as_str));
+    let lines = call_read(runner, "get", &args)?;
+    if lines.iter().any(|line| line.len() != 3) {
+        return Err("get property sources parse error".into());
+    }
+    Ok(lines)
+}
+
+fn unset_names(lines: &[Vec<String>]) -> HashSet<String> {
+    // The names whose property is set nowhere: zfs says "-" for where it comes from.
+    lines
+        .iter()
+        .filter(|line| line[2] == "-")
+        .map(|line| line[0].clone())
+        .collect()
+}
+
+pub fn snapshot(runner: &Runner, dataset: &str) -> Result<SnapshotMetadata> {
     // Take a snapshot of the given dataset, with an auto-generated name.
+    Ok(snapshot_many(runner, &[dataset])?.remove(0))
+}
+
+pub fn snapshot_many(runner: &Runner, datasets: &[&str]) -> Result<Vec<SnapshotMetadata>> {
+    // Take a snapshot of each of the given datasets, all with the same auto-generated name.
+    // Since they're all passed to a single zfs snapshot command, they're created atomically.
+    let datasets: Vec<(&str, Option<&str>)> = datasets.iter().map(|&ds| (ds, None)).collect();
+    snapshot_many_tagged(runner, &datasets, &[])
+}
+
+pub fn snapshot_many_tagged(
+    runner: &Runner,
+    datasets: &[(&str, Option<&str>)],
+    user_properties: &[(&str, &str)],
+) -> Result<Vec<SnapshotMetadata>> {
+    // Like snapshot_many, but each name may end with a tag (e.g. the dataset's policy), for
+    // whoever's browsing zfs list; and the user properties are set on the new snapshots,
+    // as they're created.
+    // zfs snapshot -o at.rollc.at:comment=... a@...-autosnap b@...-autosnap-h24d30 ...
+    if datasets.is_empty() {
+        return Err("no datasets to snapshot".into());
+    }
     let now = Utc::now();
-    let name = format!(
-        "{}@{}-autosnap",
-        dataset,
-        now.to_rfc3339_opts(SecondsFormat::Secs, true)
-    );
-    call_do("snap", &[&name])?;
-    Ok(SnapshotMetadata {
-        name: name.clone(),
-        created: now,
-        used: parse_used(&get_property(&name, "used")?)?,
-    })
-}
-
-pub fn list_snapshots() -> Result<Vec<SnapshotMetadata>> {
-    // List all snapshots under our control.
-    // zfs list -H -t snapshot -o name,creation,used,at.rollc.at:snapkeep
-    let lines = call_read(
-        "list",
-        &[
-            "-t",
-            "snapshot",
-            "-o",
-            &format!("name,creation,used,{}", PROPERTY_SNAPKEEP),
-        ],
-    )?;
-    parse_snapshots(lines)
+    let names: Vec<String> = datasets
+        .iter()
+        .map(|(dataset, tag)| {
+            let mut name = match &runner.compat_label {
+                // Down to the minute only, as zfs-auto-snapshot does; but in UTC.
+                Some(label) => format!(
+                    "{}@zfs-auto-snap_{}-{}",
+                    dataset,
+                    label,
+                    now.format("%Y-%m-%d-%H%M")
+                ),
+                None => format!(
+                    "{}@{}-autosnap",
+                    dataset,
+                    now.to_rfc3339_opts(SecondsFormat::Secs, true)
+                ),
+            };
+            if let Some(tag) = tag {
+                name.push('-');
+                name.push_str(tag);
+            }
+            name
+        })
+        .collect();
+    let mut names = names;
+    if runner.compat_label.is_some() {
+        // Those names only go down to the minute, so a second run within the same one
+        // would ask for a snapshot that's already there; and zfs snapshot would then take
+        // none of them. The one that's there will do.
+        let existing = existing_snapshots(runner, &names)?;
+        names.retain(|name| match existing.contains(name) {
+            true => {
+                eprintln!("warning: {} already exists, not taking it again", name);
+                false
+            }
+            false => true,
+        });
+        if names.is_empty() {
+            return Ok(vec![]);
+        }
+    }
+    let options: Vec<String> = user_properties
+        .iter()
+        .map(|(property, value)| format!("{}={}", property, value))
+        .collect();
+    let mut args = vec![];
+    for option in options.iter() {
+        args.extend(["-o", option.as_str()]);
+    }
+    args.extend(names.iter().map(|name| name.as_str()));
+    call_do(runner, "snap", &args)?;
+    names
+        .into_iter()
+        .map(|name| {
+            let used = if runner.no_op {
+                // There's nothing to ask about.
+                Byte::from_bytes(0)
+            } else {
+                parse_used(&get_property(runner, &name, "used")?)?
+            };
+            Ok(SnapshotMetadata {
+                name,
+                created: now,
+                used,
+            })
+        })
+        .collect()
+}
+
+pub fn list_snapshots(
+    runner: &Runner,
+    properties: &Properties,
+    map: &PolicyMap,
+) -> Result<Vec<SnapshotMetadata>> {
+    // List all snapshots under our control, as told by the snapkeep property; newest
+    // first, as zfs sorts them (snapshots created within the same second may come in any
+    // order, though).
+    parse_snapshots(
+        call_list_snapshots(runner, properties, map)?,
+        runner.lenient_used,
+    )
+}
+
+pub fn list_snapshots_with_snapkeep(
+    runner: &Runner,
+    properties: &Properties,
+    map: &PolicyMap,
+) -> Result<Vec<(SnapshotMetadata, String)>> {
+    // Like list_snapshots, but also returns each snapshot's snapkeep value: usually
+    // inherited from its dataset, but it can be set on the snapshot itself.
+    parse_snapshots_with_snapkeep(
+        call_list_snapshots(runner, properties, map)?,
+        runner.lenient_used,
+    )
+}
+
+fn call_list_snapshots(
+    runner: &Runner,
+    properties: &Properties,
+    map: &PolicyMap,
+) -> Result<Vec<Vec<String>>> {
+    // zfs list -H -p -S creation -t snapshot -o name,creation,used,at.rollc.at:snapkeep,...
+    // With -p, creation is in seconds since the epoch (rather than the default, which has no
+    // seconds), and used is in bytes. Having zfs sort them newest first, as check_age
+    // would, makes that sort next to free on pools with lots of snapshots.
+    let columns = snapshot_columns(properties);
+    let depth = runner.depth_args(1);
+    let mut args = vec!["-p", "-S", "creation", "-t", "snapshot", "-o", &columns];
+    args.extend(depth.iter().map(String::as_str));
+    let mut lines = call_read_cached(runner, "list", &args)?;
+    if runner.autosnap_only {
+        lines.retain(|line| line.first().is_some_and(|name| runner.is_ours(name)));
+    }
+    let defaults = PolicyDefaults::new(runner, properties, map)?;
+    for line in lines.iter_mut() {
+        fill_snapkeep_column(&defaults, line, 3);
+        fill_created_column(runner, line);
+    }
+    Ok(lines)
+}
+
+pub fn is_autosnap_name(name: &str) -> bool {
+    // Whether a snapshot is named the way snapshot_many_tagged names them:
+    // dataset@<timestamp>-autosnap, optionally followed by -<tag>.
+    match name.split_once('@') {
+        Some((_, snapshot)) => snapshot.ends_with("-autosnap") || snapshot.contains("-autosnap-"),
+        None => false,
+    }
+}
+
+fn existing_snapshots(runner: &Runner, names: &[String]) -> Result<HashSet<String>> {
+    // Which of these snapshots there are already. zfs list complains about (and exits
+    // with an error for) each one that isn't, which is what's expected here; so its
+    // complaints are left out, and only what it does list counts.
+    let mut args = vec!["-H", "-o", "name", "-t", "snapshot"];
+    args.extend(names.iter().map(String::as_str));
+    let p = command(runner, "list", &args)
+        .stdout(subprocess::Redirection::Pipe)
+        .stderr(subprocess::Redirection::Pipe)
+        .popen()?;
+    let (_, output) = finish(runner, "list", p, |p, _| p.communicate(None))?;
+    let (stdout, _) = output?;
+    Ok(stdout
+        .unwrap_or_default()
+        .lines()
+        .map(String::from)
+        .collect())
+}
+
+pub fn parse_snap_type(x: &str) -> Result<String> {
+    // A snapshot type (snap --type) goes into the snapshot names, so it's kept to what
+    // won't look out of place there.
+    let valid = |ch: char| ch.is_ascii_lowercase() || ch.is_ascii_digit() || ch == '-';
+    if x.is_empty() || !x.chars().all(valid) {
+        return Err(format!("invalid snapshot type '{}'", x).into());
+    }
+    Ok(x.to_string())
+}
+
+pub fn snapshot_tag(snap_type: Option<&str>, policy: Option<&str>) -> Option<String> {
+    // What goes at the end of a new snapshot's name (see snapshot_many_tagged): the type
+    // first, then the policy, as in ...-autosnap-pre-deploy-h24d30.
+    let tags: Vec<&str> = snap_type.into_iter().chain(policy).collect();
+    match tags.is_empty() {
+        true => None,
+        false => Some(tags.join("-")),
+    }
+}
+
+pub fn is_zfs_auto_snap_name(name: &str) -> bool {
+    // Whether a snapshot is named the way zfs-auto-snapshot names them (by default):
+    // dataset@zfs-auto-snap_<label>-<timestamp>.
+    match name.split_once('@') {
+        Some((_, snapshot)) => snapshot.starts_with("zfs-auto-snap_"),
+        None => false,
+    }
+}
+
+pub fn for_each_snapshot_group<F>(
+    runner: &Runner,
+    properties: &Properties,
+    map: &PolicyMap,
+    f: F,
+) -> Result<()>
+where
+    F: FnMut(Vec<(SnapshotMetadata, String)>) -> Result<()>,
+{
+    // Like list_snapshots_with_snapkeep, but read the listing as zfs writes it, and hand
+    // over the snapshots one dataset at a time; so only one dataset's worth of snapshots
+    // is in memory at once, even with 100k+ snapshots on the pool. Sorting by name keeps
+    // each dataset's snapshots together.
+    let columns = snapshot_columns(properties);
+    let depth = runner.depth_args(1);
+    let mut args = vec!["-p", "-s", "name", "-t", "snapshot", "-o", &columns];
+    args.extend(depth.iter().map(String::as_str));
+    let defaults = PolicyDefaults::new(runner, properties, map)?;
+    if runner.cache_ttl.is_some() {
+        // The cache has the whole listing anyway, so there's nothing to stream.
+        let mut lines = call_read_cached(runner, "list", &args)?;
+        if runner.autosnap_only {
+            lines.retain(|line| line.first().is_some_and(|name| runner.is_ours(name)));
+        }
+        for line in lines.iter_mut() {
+            fill_snapkeep_column(&defaults, line, 3);
+            fill_created_column(runner, line);
+        }
+        return group_snapshots(lines.into_iter().map(Ok), runner.lenient_used, f);
+    }
+    args.insert(0, "-H");
+    let p = command(runner, "list", &args)
+        .stdout(subprocess::Redirection::Pipe)
+        .popen()?;
+    let mut f = f;
+    let (status, result) = finish(runner, "list", p, |p, clock| {
+        let stdout = BufReader::new(p.stdout.take().ok_or("zfs command error")?);
+        let lines = stdout
+            .lines()
+            .filter(|line| match line {
+                Ok(l) if l.is_empty() => false,
+                Ok(l) if runner.autosnap_only => runner.is_ours(l.split('\t').next().unwrap_or(l)),
+                _ => true,
+            })
+            .map(|line| {
+                let mut line: Vec<String> = line?.split('\t').map(|ss| ss.to_string()).collect();
+                fill_snapkeep_column(&defaults, &mut line, 3);
+                fill_created_column(runner, &mut line);
+                Ok(line)
+            });
+        // The clock's stopped while f works (e.g. destroying), since that's not on zfs list.
+        group_snapshots(lines, runner.lenient_used, |group| clock.pause(|| f(group)))
+    })?;
+    if !status.success() && result.is_ok() {
+        return Err("zfs command error".into());
+    }
+    result
+}
+
+fn group_snapshots<I, F>(lines: I, lenient_used: bool, mut f: F) -> Result<()>
+where
+    I: Iterator<Item = Result<Vec<String>>>,
+    F: FnMut(Vec<(SnapshotMetadata, String)>) -> Result<()>,
+{
+    // Collect consecutive snapshots of the same dataset, and pass them on as soon as the
+    // next dataset begins. If a dataset turns up again later on, the listing wasn't
+    // sorted, and we'd have made our decisions on only part of its snapshots.
+    let mut seen = HashSet::new();
+    let mut group: Vec<(SnapshotMetadata, String)> = vec![];
+    for line in lines {
+        for (snapshot, snapkeep) in parse_snapshots_with_snapkeep(vec![line?], lenient_used)? {
+            if group
+                .first()
+                .is_some_and(|(s, _)| s.dataset() != snapshot.dataset())
+            {
+                f(std::mem::take(&mut group))?;
+            }
+            if group.is_empty() && !seen.insert(snapshot.dataset().to_string()) {
+                return Err(format!(
+                    "snapshots of {} are not listed together",
+                    snapshot.dataset()
+                )
+                .into());
+            }
+            group.push((snapshot, snapkeep));
+        }
+    }
+    if !group.is_empty() {
+        f(group)?;
+    }
+    Ok(())
+}
+
+fn fill_snapkeep_column(defaults: &PolicyDefaults, line: &mut [String], column: usize) {
+    if let [name, ..] = line {
+        let name = name.clone();
+        if let Some(snapkeep) = line.get_mut(column) {
+            defaults.fill(&name, snapkeep);
+        }
+    }
+}
+
+fn fill_created_column(runner: &Runner, line: &mut [String]) {
+    // With time_from_name, swap the creation column of a line from the snapshot listing
+    // (see snapshot_columns) for the time in the name, as zfs list -p would have it.
+    if !runner.time_from_name {
+        return;
+    }
+    if let [name, created, ..] = line {
+        if let Some(t) = name_timestamp(name, runner.compat_label.is_some()) {
+            *created = t.timestamp().to_string();
+        }
+    }
+}
+
+pub fn name_timestamp(name: &str, compat: bool) -> Option<DateTime<Utc>> {
+    // When a snapshot named by snapshot_many_tagged was taken, by its name; for anything
+    // else, None. With compat, that's also the zfs-auto-snap_<label>-%Y-%m-%d-%H%M names,
+    // taken to be in UTC, as those snapshot_many_tagged makes are.
+    let (_, snapshot) = name.split_once('@')?;
+    if let Some(rest) = snapshot.strip_prefix("zfs-auto-snap_") {
+        if !compat {
+            return None;
+        }
+        // Both the label and any tag after the time may have dashes of their own.
+        return rest.match_indices('-').find_map(|(i, _)| {
+            let time = rest.get(i + 1..i + 16)?;
+            NaiveDateTime::parse_from_str(time, "%Y-%m-%d-%H%M")
+                .ok()
+                .map(|t| DateTime::from_utc(t, Utc))
+        });
+    }
+    let (time, _) = snapshot.split_once("-autosnap")?;
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+fn snapshot_columns(properties: &Properties) -> String {
+    format!(
+        "name,creation,used,{},{}",
+        properties.snapkeep, properties.protect
+    )
+}
+
+pub fn parse_snapshot_listing(
+    text: &str,
+    lenient_used: bool,
+) -> Result<Vec<(SnapshotMetadata, String)>> {
+    // Parse the output of the same zfs list command as list_snapshots uses, but e.g. saved
+    // from another system, for offline analysis. Also returns each snapshot's snapkeep
+    // value, since that's all there's to know about the policy.
+    parse_snapshots_with_snapkeep(parse_table(text), lenient_used)
 }
 
-fn parse_snapshots(lines: Vec<Vec<String>>) -> Result<Vec<SnapshotMetadata>> {
+pub(crate) fn parse_snapshots(
+    lines: Vec<Vec<String>>,
+    lenient_used: bool,
+) -> Result<Vec<SnapshotMetadata>> {
+    Ok(parse_snapshots_with_snapkeep(lines, lenient_used)?
+        .into_iter()
+        .map(|(snapshot, _)| snapshot)
+        .collect())
+}
+
+fn parse_snapshots_with_snapkeep(
+    lines: Vec<Vec<String>>,
+    lenient_used: bool,
+) -> Result<Vec<(SnapshotMetadata, String)>> {
     let mut snapshots = Vec::with_capacity(lines.len());
     for line in lines {
         // Skip snapshots that don't have the 'at.rollc.at:snapkeep' property.
         // This works both for datasets where a snapshot did not inherit the property
         // (which means the dataset should not be managed), and for explicitly marking a
-        // snapshot to be retained / opted out.
+        // snapshot to be retained / opted out. The same goes for snapshots with the protect
+        // property (the optional fifth column) set, e.g. those some other tool manages.
         match line.as_slice() {
-            [_, _, _, snapkeep] if snapkeep == "-" => continue,
-            [name, created, used, _] => {
+            [_, _, _, snapkeep] | [_, _, _, snapkeep, _] if is_unmanaged(snapkeep) => continue,
+            [_, _, _, _, protect] if parse_bool(protect) => continue,
+            [name, created, used, snapkeep] | [name, created, used, snapkeep, _] => {
                 let metadata = SnapshotMetadata {
                     name: name.to_string(),
-                    created: chrono::DateTime::from_utc(
-                        chrono::NaiveDateTime::parse_from_str(created, "%a %b %e %H:%M %Y")?,
-                        chrono::Utc,
-                    ),
-                    used: parse_used(used)?,
+                    created: parse_creation(created).map_err(|e| {
+                        format!(
+                            "failed to parse creation '{}' for snapshot {}: {}",
+                            created, name, e
+                        )
+                    })?,
+                    used: match parse_used(used) {
+                        Ok(used) => used,
+                        Err(e) => {
+                            let e = format!(
+                                "failed to parse used '{}' for snapshot {}: {}",
+                                used, name, e
+                            );
+                            if !lenient_used {
+                                return Err(e.into());
+                            }
+                            eprintln!("warning: {}, counting it as 0", e);
+                            Byte::from_bytes(0)
+                        }
+                    },
                 };
-                snapshots.push(metadata)
+                snapshots.push((metadata, snapkeep.to_string()))
             }
             _ => return Err("list snapshots parse error".into()),
         }
@@ -67,35 +609,529 @@ fn parse_snapshots(lines: Vec<Vec<String>>) -> Result<Vec<SnapshotMetadata>> {
     Ok(snapshots)
 }
 
-pub fn get_property(dataset: &str, property: &str) -> Result<String> {
+fn parse_creation(x: &str) -> Result<DateTime<Utc>> {
+    // Seconds since the epoch (zfs list -p), or the default format, which is accurate only
+    // to the minute, and in local time (as zfs prints it).
+    parse_creation_in(x, &Local)
+}
+
+fn parse_creation_in<Tz: TimeZone>(x: &str, tz: &Tz) -> Result<DateTime<Utc>> {
+    if !x.is_empty() && x.chars().all(|ch| ch.is_ascii_digit()) {
+        return Ok(Utc
+            .timestamp_opt(x.parse()?, 0)
+            .single()
+            .ok_or("out of range")?);
+    }
+    let local = chrono::NaiveDateTime::parse_from_str(x, "%a %b %e %H:%M %Y")?;
+    // When the clocks go back, an hour happens twice; there's no telling which one it was.
+    Ok(tz
+        .from_local_datetime(&local)
+        .earliest()
+        .ok_or("no such local time")?
+        .with_timezone(&Utc))
+}
+
+pub fn get_property(runner: &Runner, dataset: &str, property: &str) -> Result<String> {
     // Get a single named property on given dataset.
     // zfs get -H -o value $property $dataset
-    Ok(call_read("get", &["-o", "value", property, dataset])?
-        .get(0)
-        .unwrap()[0]
-        .clone())
+    call_read(runner, "get", &["-o", "value", property, dataset])?
+        .first()
+        .and_then(|line| line.first())
+        .cloned()
+        .ok_or_else(|| format!("zfs get printed no {} for {}", property, dataset).into())
+}
+
+pub fn set_property(runner: &Runner, name: &str, property: &str, value: &str) -> Result<()> {
+    // Set a single property on the given dataset or snapshot.
+    // zfs set $property=$value $name
+    call_do(runner, "set", &[&format!("{}={}", property, value), name])
+}
+
+pub fn list_pinned(runner: &Runner, properties: &Properties) -> Result<Vec<SnapshotMetadata>> {
+    // The snapshots marked to be retained, with snapkeep=- set on the snapshot itself;
+    // list_snapshots leaves them out. A - that's only inherited (e.g. on a dataset that
+    // gets its policy from the policy map) doesn't count.
+    // zfs list -H -p -t snapshot -o name,creation,used,at.rollc.at:snapkeep
+    let set = call_property_sources(runner, "snapshot", &["-s", "local,received"], properties)?;
+    let set: HashSet<String> = set.into_iter().map(|line| line[0].clone()).collect();
+    let columns = format!("name,creation,used,{}", properties.snapkeep);
+    let depth = runner.depth_args(1);
+    let mut args = vec!["-p", "-t", "snapshot", "-o", &columns];
+    args.extend(depth.iter().map(String::as_str));
+    parse_pinned(call_read(runner, "list", &args)?, &set)
+}
+
+fn parse_pinned(lines: Vec<Vec<String>>, set: &HashSet<String>) -> Result<Vec<SnapshotMetadata>> {
+    let mut pinned = vec![];
+    for line in lines {
+        match line.as_slice() {
+            [name, created, used, snapkeep] if snapkeep == "-" && set.contains(name) => pinned
+                .push(SnapshotMetadata {
+                    name: name.to_string(),
+                    created: parse_creation(created)?,
+                    used: parse_used(used)?,
+                }),
+            [_, _, _, _] => continue,
+            _ => return Err("list snapshots parse error".into()),
+        }
+    }
+    Ok(pinned)
+}
+
+pub fn get_snapkeep(
+    runner: &Runner,
+    properties: &Properties,
+    map: &PolicyMap,
+    dataset: &str,
+) -> Result<String> {
+    // The dataset's snapkeep; or if it's not set (nor inherited), the policy map's.
+    // zfs get -H -o name,value,source at.rollc.at:snapkeep $dataset
+    if *map == PolicyMap::default() {
+        return get_property(runner, dataset, &properties.snapkeep);
+    }
+    let lines = call_read(
+        runner,
+        "get",
+        &["-o", "name,value,source", &properties.snapkeep, dataset],
+    )?;
+    match lines.as_slice() {
+        [line] if line.len() == 3 => {
+            let defaults = PolicyDefaults {
+                map: Some(map),
+                unset: unset_names(&lines),
+                ..Default::default()
+            };
+            let mut snapkeep = line[1].clone();
+            defaults.fill(dataset, &mut snapkeep);
+            Ok(snapkeep)
+        }
+        _ => Err("get property sources parse error".into()),
+    }
+}
+
+pub fn zpool_health(runner: &Runner, pool: &str) -> Result<String> {
+    // The pool's health, e.g. ONLINE, DEGRADED, or FAULTED.
+    // zpool list -H -o health $pool
+    let zpool = Runner {
+        program: runner.zpool.clone(),
+        ..runner.clone()
+    };
+    parse_health(call_read(&zpool, "list", &["-o", "health", pool])?)
+}
+
+fn parse_health(lines: Vec<Vec<String>>) -> Result<String> {
+    match lines.as_slice() {
+        [line] if line.len() == 1 => Ok(line[0].clone()),
+        _ => Err("zpool health parse error".into()),
+    }
+}
+
+pub fn list_pools(runner: &Runner) -> Result<Vec<String>> {
+    // The pools, or rather their root datasets.
+    // zfs list -H -d 0 -o name
+    Ok(call_read(runner, "list", &["-d", "0", "-o", "name"])?
+        .into_iter()
+        .map(|line| line[0].clone())
+        .collect())
+}
+
+// A dataset that has the snapkeep property set.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ManagedDataset {
+    pub name: String,
+    // The snapkeep value, as set (or inherited).
+    pub snapkeep: String,
+    // Set via the snapdisable property: gc still manages the existing snapshots, but snap
+    // doesn't take any new ones.
+    pub snapdisable: bool,
+    // Set via the runner's exclude_property: neither snap nor gc should touch it.
+    pub excluded: bool,
+}
+
+pub fn list_managed_datasets(
+    runner: &Runner,
+    properties: &Properties,
+    map: &PolicyMap,
+) -> Result<Vec<ManagedDataset>> {
+    // Which datasets are under our control?
+    // zfs list -H -t filesystem,volume -o name,at.rollc.at:snapkeep,at.rollc.at:snapdisable
+    let mut columns = format!("name,{},{}", properties.snapkeep, properties.snapdisable);
+    if let Some(exclude) = &runner.exclude_property {
+        columns.push(',');
+        columns.push_str(exclude);
+    }
+    let depth = runner.depth_args(0);
+    let mut args = vec!["-t", "filesystem,volume", "-o", &columns];
+    args.extend(depth.iter().map(String::as_str));
+    let mut lines = call_read(runner, "list", &args)?;
+    let defaults = PolicyDefaults::new(runner, properties, map)?;
+    for line in lines.iter_mut() {
+        fill_snapkeep_column(&defaults, line, 1);
+    }
+    parse_managed_datasets(lines)
+}
+
+fn parse_managed_datasets(lines: Vec<Vec<String>>) -> Result<Vec<ManagedDataset>> {
+    let mut datasets = Vec::with_capacity(lines.len());
+    for line in lines {
+        // The exclude_property column is there only if asked for.
+        match line.as_slice() {
+            [_, snapkeep, _, ..] if is_unmanaged(snapkeep) => continue,
+            [name, snapkeep, snapdisable, exclude @ ..] if exclude.len() <= 1 => {
+                datasets.push(ManagedDataset {
+                    name: name.to_string(),
+                    snapkeep: snapkeep.to_string(),
+                    snapdisable: parse_bool(snapdisable),
+                    excluded: exclude.first().is_some_and(|x| parse_bool(x)),
+                })
+            }
+            _ => return Err("list datasets parse error".into()),
+        }
+    }
+    Ok(datasets)
+}
+
+pub fn list_datasets_for_snapshot(
+    runner: &Runner,
+    properties: &Properties,
+    map: &PolicyMap,
+    sources: &[String],
+) -> Result<Vec<String>> {
+    // Which datasets should get a snapshot? If sources is not empty, only those where
+    // snapkeep comes from one of the given sources (e.g. "local", "received"); so a policy
+    // set on a parent doesn't sweep in all of its children.
+    let mut datasets = list_managed_datasets(runner, properties, map)?;
+    if !sources.is_empty() {
+        let found: HashMap<String, String> = list_property_sources(runner, &properties.snapkeep)?
+            .into_iter()
+            .collect();
+        datasets.retain(|dataset| {
+            found
+                .get(&dataset.name)
+                .and_then(|source| source.split_whitespace().next())
+                .is_some_and(|source| sources.iter().any(|s| s == source))
+        });
+    }
+    Ok(snapshot_candidates(datasets))
+}
+
+pub fn list_excluded_datasets(
+    runner: &Runner,
+    properties: &Properties,
+    map: &PolicyMap,
+) -> Result<HashSet<String>> {
+    // The managed datasets that the exclude_property says to leave alone, if any.
+    if runner.exclude_property.is_none() {
+        return Ok(HashSet::new());
+    }
+    Ok(list_managed_datasets(runner, properties, map)?
+        .into_iter()
+        .filter(|dataset| dataset.excluded)
+        .map(|dataset| dataset.name)
+        .collect())
+}
+
+fn snapshot_candidates(datasets: Vec<ManagedDataset>) -> Vec<String> {
+    // Leave out the datasets with snapshots disabled, or excluded; and those with a policy
+    // we can't parse, since gc couldn't do anything with their snapshots but let them
+    // pile up.
+    datasets
+        .into_iter()
+        .filter(|dataset| !dataset.snapdisable && !dataset.excluded)
+        .filter(
+            |dataset| match RetentionPolicy::from_str(&dataset.snapkeep) {
+                Ok(_) => true,
+                Err(e) => {
+                    eprintln!(
+                        "warning: not snapshotting {}: unable to parse retention policy: {}",
+                        dataset.name, e
+                    );
+                    false
+                }
+            },
+        )
+        .map(|dataset| dataset.name)
+        .collect()
+}
+
+pub fn list_property_values(runner: &Runner, property: &str) -> Result<HashMap<String, String>> {
+    // The property's value on every dataset where it's set (or inherited).
+    // zfs get -H -t filesystem,volume -o name,value at.rollc.at:snapevery
+    parse_property_values(call_read(
+        runner,
+        "get",
+        &["-t", "filesystem,volume", "-o", "name,value", property],
+    )?)
 }
 
-pub fn list_datasets_for_snapshot() -> Result<Vec<String>> {
-    // Which datasets should get a snapshot?
-    // zfs get -H -t filesystem,volume -o name,value at.rollc.at:snapkeep
-    Ok(call_read(
+fn parse_property_values(lines: Vec<Vec<String>>) -> Result<HashMap<String, String>> {
+    let mut values = HashMap::with_capacity(lines.len());
+    for line in lines {
+        match line.as_slice() {
+            [_, value] if value == "-" => continue,
+            [name, value] => {
+                values.insert(name.to_string(), value.to_string());
+            }
+            _ => return Err("get property values parse error".into()),
+        }
+    }
+    Ok(values)
+}
+
+fn list_property_sources(runner: &Runner, property: &str) -> Result<Vec<(String, String)>> {
+    // Where each dataset's property comes from: "local", "received", "inherited from
+    // tank", etc.
+    // zfs get -H -t filesystem,volume -o name,value,source at.rollc.at:snapkeep
+    parse_property_sources(call_read(
+        runner,
         "get",
         &[
             "-t",
             "filesystem,volume",
             "-o",
-            "name,value",
-            PROPERTY_SNAPKEEP,
+            "name,value,source",
+            property,
         ],
-    )?
-    .iter()
-    .filter(|kv| kv[1] != "-")
-    .map(|kv| kv[0].clone())
-    .collect())
+    )?)
+}
+
+fn parse_property_sources(lines: Vec<Vec<String>>) -> Result<Vec<(String, String)>> {
+    let mut sources = Vec::with_capacity(lines.len());
+    for line in lines {
+        match line.as_slice() {
+            [_, value, _] if is_unmanaged(value) => continue,
+            [name, _, source] => sources.push((name.to_string(), source.to_string())),
+            _ => return Err("get property sources parse error".into()),
+        }
+    }
+    Ok(sources)
+}
+
+// What changed between two snapshots of a dataset, by number of files.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DiffSummary {
+    pub modified: usize,
+    pub added: usize,
+    pub removed: usize,
+    pub renamed: usize,
+}
+
+pub fn diff_summary(runner: &Runner, older: &str, newer: &str) -> Result<DiffSummary> {
+    // This walks the changes file by file, so it can take a while on a busy dataset. A
+    // diff that fails part way (e.g. without the diff permission) would look like one
+    // with fewer changes, so this one goes by the exit status.
+    // zfs diff -H $older $newer
+    let args = ["-H", older, newer];
+    let (status, text) = call_read_status(runner, "diff", &args)?;
+    if !status.success() {
+        return Err(format!("zfs diff {} {} failed", older, newer).into());
+    }
+    parse_diff(parse_table(&text))
+}
+
+fn parse_diff(lines: Vec<Vec<String>>) -> Result<DiffSummary> {
+    let mut summary = DiffSummary::default();
+    for line in lines {
+        match line.first().map(String::as_str) {
+            Some("M") => summary.modified += 1,
+            Some("+") => summary.added += 1,
+            Some("-") => summary.removed += 1,
+            Some("R") => summary.renamed += 1,
+            _ => return Err("zfs diff parse error".into()),
+        }
+    }
+    Ok(summary)
+}
+
+pub fn list_clones(runner: &Runner) -> Result<Vec<(String, String)>> {
+    // Which datasets are clones, and of which snapshot? Such an origin snapshot can't be
+    // destroyed without (-R) destroying its clones, too.
+    // zfs list -H -t filesystem,volume -o name,origin
+    parse_clones(call_read(
+        runner,
+        "list",
+        &["-t", "filesystem,volume", "-o", "name,origin"],
+    )?)
+}
+
+fn parse_clones(lines: Vec<Vec<String>>) -> Result<Vec<(String, String)>> {
+    let mut clones = Vec::with_capacity(lines.len());
+    for line in lines {
+        match line.as_slice() {
+            [_, origin] if origin == "-" => continue,
+            [name, origin] => clones.push((name.to_string(), origin.to_string())),
+            _ => return Err("list clones parse error".into()),
+        }
+    }
+    Ok(clones)
+}
+
+pub fn list_bookmarks(runner: &Runner) -> Result<Vec<(String, String)>> {
+    // Which snapshots have a bookmark (see zfs-bookmark(8)) of the same name, i.e.
+    // tank@x for tank#x? Those are usually the anchors of incremental sends, as pairs of
+    // (snapshot, bookmark).
+    // zfs list -H -t bookmark -o name
+    parse_bookmarks(call_read(
+        runner,
+        "list",
+        &["-t", "bookmark", "-o", "name"],
+    )?)
 }
 
-pub fn destroy_snapshot(snapshot: SnapshotMetadata) -> Result<()> {
+fn parse_bookmarks(lines: Vec<Vec<String>>) -> Result<Vec<(String, String)>> {
+    let mut bookmarks = Vec::with_capacity(lines.len());
+    for line in lines {
+        match line.as_slice() {
+            [name] => match name.split_once('#') {
+                Some((dataset, short)) => {
+                    bookmarks.push((format!("{}@{}", dataset, short), name.to_string()))
+                }
+                None => return Err("list bookmarks parse error".into()),
+            },
+            _ => return Err("list bookmarks parse error".into()),
+        }
+    }
+    Ok(bookmarks)
+}
+
+fn parse_bool(x: &str) -> bool {
+    // For our own boolean properties; anything else, including unset ("-"), is false.
+    matches!(x, "on" | "true" | "yes" | "1")
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Hold {
+    pub snapshot: String,
+    pub tag: String,
+}
+
+pub fn list_holds(runner: &Runner, snapshots: &[SnapshotMetadata]) -> Result<Vec<Hold>> {
+    // List ZFS user holds (see zfs-hold(8)) on the given snapshots. These are unrelated
+    // to our snapkeep=- marker, but will equally prevent the snapshot from being destroyed.
+    // zfs holds -H ...@... ...@...
+    if snapshots.is_empty() {
+        return Ok(vec![]);
+    }
+    let names: Vec<&str> = snapshots.iter().map(|s| s.name.as_str()).collect();
+    parse_holds(call_read(runner, "holds", &names)?)
+}
+
+pub fn list_written(
+    runner: &Runner,
+    snapshots: &[SnapshotMetadata],
+) -> Result<HashMap<String, Byte>> {
+    // How much was written to each snapshot's dataset since the previous snapshot; with
+    // blocks shared between snapshots, a better guess than used at what destroying it
+    // would reclaim.
+    // zfs list -H -p -o name,written ...@... ...@...
+    if snapshots.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let mut args = vec!["-p", "-o", "name,written"];
+    args.extend(snapshots.iter().map(|s| s.name.as_str()));
+    parse_sizes(call_read(runner, "list", &args)?, "written")
+}
+
+pub fn list_used_by_snapshots(runner: &Runner, datasets: &[&str]) -> Result<HashMap<String, Byte>> {
+    // How much space each dataset's snapshots take together, blocks shared among them
+    // included (unlike the sum of their used).
+    // zfs get -H -p -o name,value usedbysnapshots ... ...
+    if datasets.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let mut args = vec!["-p", "-o", "name,value", "usedbysnapshots"];
+    args.extend(datasets.iter().copied());
+    parse_sizes(call_read(runner, "get", &args)?, "usedbysnapshots")
+}
+
+fn parse_sizes(lines: Vec<Vec<String>>, property: &str) -> Result<HashMap<String, Byte>> {
+    let mut sizes = HashMap::with_capacity(lines.len());
+    for line in lines {
+        match line.as_slice() {
+            [name, value] => {
+                let value = parse_used(value).map_err(|e| {
+                    format!(
+                        "failed to parse {} '{}' for {}: {}",
+                        property, value, name, e
+                    )
+                })?;
+                sizes.insert(name.to_string(), value);
+            }
+            _ => return Err(format!("list {} parse error", property).into()),
+        }
+    }
+    Ok(sizes)
+}
+
+pub fn list_comments(
+    runner: &Runner,
+    properties: &Properties,
+    snapshots: &[SnapshotMetadata],
+) -> Result<HashMap<String, String>> {
+    // The comments set on the snapshots (see snap --comment), if any.
+    list_snapshot_values(runner, &properties.comment, snapshots)
+}
+
+pub fn list_snapshot_values(
+    runner: &Runner,
+    property: &str,
+    snapshots: &[SnapshotMetadata],
+) -> Result<HashMap<String, String>> {
+    // The given property of each of the snapshots, where it's set.
+    // zfs list -H -o name,$property ...@... ...@...
+    if snapshots.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let columns = format!("name,{}", property);
+    let mut args = vec!["-o", &columns];
+    args.extend(snapshots.iter().map(|s| s.name.as_str()));
+    parse_snapshot_values(call_read(runner, "list", &args)?)
+}
+
+pub fn list_all_snapshot_values(
+    runner: &Runner,
+    property: &str,
+) -> Result<HashMap<String, String>> {
+    // Like list_snapshot_values, but for every snapshot at once (e.g. an inherited
+    // property), rather than naming them all on the command line.
+    // zfs list -H -t snapshot -o name,$property
+    let columns = format!("name,{}", property);
+    let depth = runner.depth_args(1);
+    let mut args = vec!["-t", "snapshot", "-o", &columns];
+    args.extend(depth.iter().map(String::as_str));
+    parse_snapshot_values(call_read(runner, "list", &args)?)
+}
+
+fn parse_snapshot_values(lines: Vec<Vec<String>>) -> Result<HashMap<String, String>> {
+    let mut values = HashMap::new();
+    for line in lines {
+        match line.as_slice() {
+            [_, value] if value == "-" => {}
+            [name, value] => {
+                values.insert(name.to_string(), value.to_string());
+            }
+            _ => return Err("list snapshot values parse error".into()),
+        }
+    }
+    Ok(values)
+}
+
+fn parse_holds(lines: Vec<Vec<String>>) -> Result<Vec<Hold>> {
+    let mut holds = Vec::with_capacity(lines.len());
+    for line in lines {
+        match line.as_slice() {
+            // name, tag, timestamp
+            [snapshot, tag, _] => holds.push(Hold {
+                snapshot: snapshot.to_string(),
+                tag: tag.to_string(),
+            }),
+            _ => return Err("list holds parse error".into()),
+        }
+    }
+    Ok(holds)
+}
+
+pub fn destroy_snapshot(runner: &Runner, snapshot: SnapshotMetadata) -> Result<()> {
     // This will destroy the named snapshot. Since ZFS has a single verb for destroying
     // anything, which could cause irreparable harm, we double check that the name we
     // got passed looks like a snapshot name, and return an error otherwise.
@@ -103,50 +1139,469 @@ pub fn destroy_snapshot(snapshot: SnapshotMetadata) -> Result<()> {
         return Err("Tried to destroy something that is not a snapshot".into());
     }
     // zfs destroy -H ...@...
-    call_do("destroy", &[&snapshot.name])
+    call_do(runner, "destroy", &[&snapshot.name])
+}
+
+pub fn destroy_snapshot_if_exists(runner: &Runner, snapshot: SnapshotMetadata) -> Result<bool> {
+    // Like destroy_snapshot, but a snapshot that's already gone (e.g. some other tool got
+    // to it first) is fine; returns whether it was still there.
+    if !snapshot.name.contains('@') {
+        return Err("Tried to destroy something that is not a snapshot".into());
+    }
+    call_do_unless(runner, "destroy", &[&snapshot.name], is_missing)
 }
 
-fn call_read(action: &str, args: &[&str]) -> Result<Vec<Vec<String>>> {
+fn is_missing(stderr: &str) -> bool {
+    // What zfs destroy says about a snapshot (or dataset) that doesn't exist.
+    stderr.contains("does not exist") || stderr.contains("could not find any snapshots")
+}
+
+pub fn destroy_ranges(
+    snapshots: &[SnapshotMetadata],
+    delete: &HashSet<String>,
+    holds: &[Hold],
+) -> Vec<String> {
+    // Turn the snapshots to delete into as few arguments to zfs destroy as possible, as
+    // ranges (e.g. tank@a%tank@c, see zfs-destroy(8)) of consecutive ones. A range takes
+    // everything in between with it, so snapshots must be all of one dataset's, oldest
+    // first, including those we don't manage; anything not deleted, and anything held
+    // (which zfs won't destroy anyway), ends the range.
+    fn range(run: &[&SnapshotMetadata]) -> Option<String> {
+        match run {
+            [] => None,
+            [only] => Some(only.name.clone()),
+            [first, .., last] => {
+                let (_, last) = last.name.split_once('@')?;
+                Some(format!("{}%{}", first.name, last))
+            }
+        }
+    }
+    let held: HashSet<&str> = holds.iter().map(|h| h.snapshot.as_str()).collect();
+    let mut ranges = vec![];
+    let mut run = vec![];
+    for s in snapshots {
+        if delete.contains(&s.name) && !held.contains(s.name.as_str()) {
+            run.push(s);
+        } else {
+            ranges.extend(range(&run));
+            run.clear();
+        }
+    }
+    ranges.extend(range(&run));
+    ranges
+}
+
+fn call_read(runner: &Runner, action: &str, args: &[&str]) -> Result<Vec<Vec<String>>> {
     // Helper function to get/list datasets and their properties into a nice table.
-    Ok(subprocess::Exec::cmd("zfs")
-        .arg(action)
-        .arg("-H")
-        .args(args)
+    Ok(parse_table(&call_read_text(
+        runner,
+        action,
+        &[&["-H"], args].concat(),
+    )?))
+}
+
+fn call_read_text(runner: &Runner, action: &str, args: &[&str]) -> Result<String> {
+    // Like call_read, but for the commands that have no -H; the output, as is.
+    Ok(call_read_status(runner, action, args)?.1)
+}
+
+fn call_read_status(runner: &Runner, action: &str, args: &[&str]) -> Result<(ExitStatus, String)> {
+    // Like call_read_text, along with how the command exited.
+    let p = command(runner, action, args)
         .stdout(subprocess::Redirection::Pipe)
-        .capture()?
-        .stdout_str()
-        .lines()
+        .stderr(subprocess::Redirection::Pipe)
+        .popen()?;
+    let (status, output) = finish(runner, action, p, |p, _| p.communicate(None))?;
+    let (stdout, stderr) = output?;
+    check_stderr(&stderr.unwrap_or_default(), args)?;
+    Ok((status, stdout.unwrap_or_default()))
+}
+
+pub fn estimate_reclaim(runner: &Runner, delete: &[SnapshotMetadata]) -> Result<Byte> {
+    // What destroying all of these would reclaim, as worked out by a dry run of zfs
+    // destroy: unlike adding up their used, this counts the blocks shared only among
+    // them. One run per dataset, with the snapshots in as few ranges as possible; to
+    // tell what a range would take with it, that needs every snapshot of the dataset,
+    // not only the managed ones, oldest first.
+    // zfs list -H -t snapshot -o name -s createtxg -d 1 tank tank/home ...
+    // zfs destroy -n -v -p tank@a%c,e
+    let mut by_dataset = BTreeMap::<&str, HashSet<String>>::new();
+    for s in delete {
+        by_dataset
+            .entry(s.dataset())
+            .or_default()
+            .insert(s.name.clone());
+    }
+    if by_dataset.is_empty() {
+        return Ok(Byte::from_bytes(0));
+    }
+    let datasets: Vec<&str> = by_dataset.keys().copied().collect();
+    let all = list_all_snapshots(runner, &datasets)?;
+    let mut total = 0;
+    for (dataset, names) in by_dataset {
+        let snapshots = all.get(dataset).map_or(&[][..], Vec::as_slice);
+        let ranges = destroy_ranges(snapshots, &names, &[]);
+        if ranges.is_empty() {
+            continue;
+        }
+        let arg = batch_arg(&ranges);
+        let text = call_read_text(runner, "destroy", &["-n", "-v", "-p", &arg])?;
+        total += parse_reclaim(&text)?;
+    }
+    Ok(Byte::from_bytes(total))
+}
+
+pub fn list_all_snapshots(
+    runner: &Runner,
+    datasets: &[&str],
+) -> Result<HashMap<String, Vec<SnapshotMetadata>>> {
+    // Every snapshot of these datasets, not only the managed ones, oldest first; by name
+    // only (the rest left empty), for telling which snapshot comes before which.
+    // zfs list -H -t snapshot -o name -s createtxg -d 1 tank tank/home ...
+    let mut all = HashMap::<String, Vec<SnapshotMetadata>>::new();
+    if datasets.is_empty() {
+        return Ok(all);
+    }
+    let mut args = vec!["-t", "snapshot", "-o", "name", "-s", "createtxg", "-d", "1"];
+    args.extend(datasets.iter().copied());
+    for line in call_read(runner, "list", &args)? {
+        let name = line.first().ok_or("list snapshots parse error")?;
+        let created = Utc.timestamp(0, 0);
+        let s = SnapshotMetadata::new(name, created, 0);
+        all.entry(s.dataset().to_string()).or_default().push(s);
+    }
+    Ok(all)
+}
+
+fn batch_arg(ranges: &[String]) -> String {
+    // One dataset's snapshots (or ranges of them) as a single argument to zfs destroy,
+    // e.g. tank@a%c,e: only the first one has the dataset's name.
+    let mut arg = String::new();
+    for (i, range) in ranges.iter().enumerate() {
+        match (i, range.split_once('@')) {
+            (0, _) | (_, None) => arg.push_str(range),
+            (_, Some((_, short))) => {
+                arg.push(',');
+                arg.push_str(short);
+            }
+        }
+    }
+    arg
+}
+
+fn parse_reclaim(text: &str) -> Result<u128> {
+    // zfs destroy -nvp prints a line for each snapshot, then e.g. "reclaim\t12345".
+    for line in text.lines() {
+        if let Some(("reclaim", bytes)) = line.split_once('\t') {
+            return bytes
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid reclaim '{}'", bytes).into());
+        }
+    }
+    Err("zfs destroy -nv didn't say what it would reclaim".into())
+}
+
+fn call_read_cached(runner: &Runner, action: &str, args: &[&str]) -> Result<Vec<Vec<String>>> {
+    // Like call_read, but with a cache_ttl, a recent enough run of the same command
+    // stands in for running it again. The cache is only ever replaced whole, by renaming,
+    // so concurrent readers see either the old listing or the new one; failing to save
+    // it is only worth a warning.
+    let ttl = match runner.cache_ttl {
+        Some(ttl) => ttl,
+        None => return call_read(runner, action, args),
+    };
+    let mut hasher = DefaultHasher::new();
+    command_line(runner, action, args).hash(&mut hasher);
+    let path = runner
+        .cache_dir
+        .join(format!("{:016x}.list", hasher.finish()));
+    let fresh = fs::metadata(&path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age < ttl);
+    if fresh {
+        if let Ok(text) = fs::read_to_string(&path) {
+            return Ok(parse_table(&text));
+        }
+    }
+    let lines = call_read(runner, action, args)?;
+    let mut text = String::new();
+    for line in lines.iter() {
+        text.push_str(&line.join("\t"));
+        text.push('\n');
+    }
+    let tmp = path.with_extension(format!("tmp.{}", std::process::id()));
+    let saved = fs::create_dir_all(&runner.cache_dir)
+        .and_then(|_| fs::write(&tmp, text))
+        .and_then(|_| fs::rename(&tmp, &path));
+    if let Err(e) = saved {
+        let _ = fs::remove_file(&tmp);
+        eprintln!("warning: unable to cache in {}: {}", path.display(), e);
+    }
+    Ok(lines)
+}
+
+pub(crate) fn parse_table(text: &str) -> Vec<Vec<String>> {
+    // Split the output of zfs -H (no headers, tab-separated) into rows and columns.
+    text.lines()
         .filter(|&s| !s.is_empty())
         .map(|s| s.split('\t').map(|ss| ss.to_string()).collect())
-        .collect())
+        .collect()
 }
 
-fn call_do(action: &str, args: &[&str]) -> Result<()> {
+fn call_do(runner: &Runner, action: &str, args: &[&str]) -> Result<()> {
     // Perform a side effect, like snapshot or destroy.
-    if subprocess::Exec::cmd("zfs")
-        .arg(action)
-        .args(args)
-        .join()?
-        .success()
-    {
-        Ok(())
+    call_do_unless(runner, action, args, |_| false).map(|_| ())
+}
+
+fn call_do_unless(
+    runner: &Runner,
+    action: &str,
+    args: &[&str],
+    expected: fn(&str) -> bool,
+) -> Result<bool> {
+    // Like call_do, but a failure that zfs explains in an expected way (by stderr) isn't
+    // an error; it's not passed on either, and the result is false.
+    if runner.no_op && runner.emit_script {
+        println!("{}", script_line(runner, action, args));
+        return Ok(true);
+    }
+    if runner.no_op {
+        eprintln!("no-op: {}", script_line(runner, action, args));
+        return Ok(true);
+    }
+    let p = command(runner, action, args)
+        .stderr(subprocess::Redirection::Pipe)
+        .popen()?;
+    let (status, output) = finish(runner, action, p, |p, _| p.communicate(None))?;
+    let stderr = output?.1.unwrap_or_default();
+    if !status.success() && expected(&stderr) {
+        return Ok(false);
+    }
+    check_stderr(&stderr, args)?;
+    if status.success() {
+        Ok(true)
     } else {
         Err("zfs command error".into())
     }
 }
 
-fn parse_used(x: &str) -> Result<Byte> {
-    // The zfs(1) commandline tool says e.g. 1.2M but means 1.2MiB,
-    // so we mash it to make byte_unit parsing happy.
-    match x.chars().last() {
-        Some('K' | 'M' | 'G' | 'T' | 'P' | 'E' | 'Z') => Ok(Byte::from_str(x.to_owned() + "iB")?),
-        _ => Ok(Byte::from_str(x)?),
-    }
+fn check_stderr(stderr: &str, args: &[&str]) -> Result<()> {
+    // Pass on whatever zfs had to say; but a lack of permissions is bound to happen on the
+    // first run as a regular user, so say what to do about it.
+    eprint!("{}", stderr);
+    match permission_hint(stderr, args) {
+        Some(hint) => Err(hint.into()),
+        None => Ok(()),
+    }
+}
+
+// What to delegate with zfs allow, for snap and gc to work: destroy takes mount too;
+// userprop is for setting our properties (snap -o, set, pin).
+pub const PERMISSIONS: &str = "snapshot,destroy,mount,userprop";
+
+fn permission_hint(stderr: &str, args: &[&str]) -> Option<String> {
+    if !stderr.contains("permission denied") {
+        return None;
+    }
+    // The datasets (or snapshots) always come last, after the options and their values;
+    // the pool is where to delegate.
+    let is_dataset = |i: usize| {
+        !args[i].starts_with('-')
+            && !args[i].contains([',', '='])
+            && (i == 0 || !args[i - 1].starts_with('-'))
+    };
+    let pool = (0..args.len())
+        .rev()
+        .find(|&i| is_dataset(i))
+        .and_then(|i| args[i].split(['/', '@']).next())
+        .unwrap_or("POOL");
+    let user = std::env::var("USER").unwrap_or_else(|_| String::from("$USER"));
+    Some(format!(
+        "permission denied; run as root, or grant permissions with \
+         `zfs allow -u {} {} {}`",
+        user, PERMISSIONS, pool
+    ))
+}
+
+// How long a command's been going, leaving out the time the reader took a break from it.
+struct Clock {
+    start: Instant,
+    // How long the breaks took so far, and when the current one (if any) began.
+    paused: Mutex<(Duration, Option<Instant>)>,
+}
+
+impl Clock {
+    fn new() -> Clock {
+        Clock {
+            start: Instant::now(),
+            paused: Mutex::new((Duration::ZERO, None)),
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        let (paused, since) = *self.paused.lock().unwrap();
+        let pausing = since.map(|since| since.elapsed()).unwrap_or_default();
+        self.start.elapsed().saturating_sub(paused + pausing)
+    }
+
+    fn pause<T>(&self, f: impl FnOnce() -> T) -> T {
+        self.paused.lock().unwrap().1 = Some(Instant::now());
+        let out = f();
+        let mut paused = self.paused.lock().unwrap();
+        if let Some(since) = paused.1.take() {
+            paused.0 += since.elapsed();
+        }
+        out
+    }
+}
+
+fn finish<T, F>(
+    runner: &Runner,
+    action: &str,
+    mut p: subprocess::Popen,
+    read: F,
+) -> Result<(ExitStatus, T)>
+where
+    F: FnOnce(&mut subprocess::Popen, &Clock) -> T,
+{
+    // Let read() have the command's output, then wait for it to exit; with a timeout, a
+    // watchdog kills the command if reading takes too long (i.e. blocks), and so does
+    // waiting. The watchdog is stopped before the command is reaped, so that it can't
+    // kill some other process that happens to get the same pid. Whatever read() does
+    // under clock.pause() doesn't count, for the timeout nor the trace.
+    let clock = Arc::new(Clock::new());
+    let watchdog = match (runner.timeout, p.pid()) {
+        (Some(timeout), Some(pid)) => {
+            let (stop, stopped) = mpsc::channel::<()>();
+            let clock = Arc::clone(&clock);
+            let thread = thread::spawn(move || loop {
+                // While paused, the clock stands still; check back now and then.
+                let left = timeout.saturating_sub(clock.elapsed());
+                if left.is_zero() {
+                    unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+                    return true;
+                }
+                let wait = left.max(Duration::from_millis(10));
+                if stopped.recv_timeout(wait) != Err(mpsc::RecvTimeoutError::Timeout) {
+                    return false;
+                }
+            });
+            Some((stop, thread))
+        }
+        _ => None,
+    };
+    let out = read(&mut p, &clock);
+    let killed = match watchdog {
+        Some((stop, thread)) => {
+            drop(stop);
+            thread.join().unwrap_or(false)
+        }
+        None => false,
+    };
+    let status = match runner.timeout {
+        Some(timeout) => p.wait_timeout(timeout.saturating_sub(clock.elapsed()))?,
+        None => Some(p.wait()?),
+    };
+    if runner.trace {
+        // How long zfs took, as opposed to us; e.g. to tell where a slow gc spends its time.
+        eprintln!("trace: zfs {} took {:.1?}", action, clock.elapsed());
+    }
+    match status {
+        Some(status) if !killed => Ok((status, out)),
+        _ => {
+            p.kill().ok();
+            p.wait().ok();
+            let timeout = runner.timeout.unwrap_or_default();
+            Err(format!("zfs command timed out after {:?}", timeout).into())
+        }
+    }
+}
+
+fn command(runner: &Runner, action: &str, args: &[&str]) -> subprocess::Exec {
+    // Every zfs command we run goes through here, so that --trace can show it.
+    let argv = command_line(runner, action, args);
+    if runner.trace {
+        eprintln!("trace: {}", quoted(&argv));
+    }
+    subprocess::Exec::cmd(&argv[0]).args(&argv[1..])
+}
+
+fn command_line(runner: &Runner, action: &str, args: &[&str]) -> Vec<String> {
+    // The full command line, as run locally; with a host, via ssh(1), which hands the
+    // command over to the remote shell as a single string, so it must be quoted.
+    let mut argv = vec![runner.program.clone(), action.to_string()];
+    argv.extend(args.iter().map(|arg| arg.to_string()));
+    match &runner.host {
+        Some(host) => {
+            let remote: Vec<String> = argv.iter().map(|arg| shell_quote(arg)).collect();
+            vec![
+                String::from("ssh"),
+                String::from("--"),
+                host.clone(),
+                remote.join(" "),
+            ]
+        }
+        None => argv,
+    }
+}
+
+fn script_line(runner: &Runner, action: &str, args: &[&str]) -> String {
+    // The command, as it would be typed into a shell.
+    quoted(&command_line(runner, action, args))
+}
+
+fn quoted(argv: &[String]) -> String {
+    // Every argument is passed on to zfs as is, but names can have spaces in them; when
+    // showing a command, quote them, so it's clear where each one ends.
+    let argv: Vec<String> = argv.iter().map(|arg| shell_quote(arg)).collect();
+    argv.join(" ")
+}
+
+fn shell_quote(x: &str) -> String {
+    // Leave the harmless arguments (which is nearly all of them) readable.
+    let safe = |ch: char| ch.is_ascii_alphanumeric() || "@%+=:,./_-".contains(ch);
+    if !x.is_empty() && x.chars().all(safe) {
+        return x.to_string();
+    }
+    format!("'{}'", x.replace('\'', "'\\''"))
+}
+
+pub(crate) fn parse_used(x: &str) -> Result<Byte> {
+    // The zfs(1) commandline tool says e.g. 1.2M but means 1.2MiB; parse_size knows.
+    parse_size(x)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // A zfs that runs the script (under #!/bin/sh), in a directory of its own for the test
+    // to remove when done; the script can keep files next to itself, in $(dirname "$0").
+    fn fake_zfs(script: &str) -> (Runner, PathBuf) {
+        static FAKES: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "zfs-autosnap-fake-{}-{}",
+            std::process::id(),
+            FAKES.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let program = dir.join("zfs");
+        fs::write(&program, format!("#!/bin/sh\n{}", script)).unwrap();
+        fs::set_permissions(
+            &program,
+            std::os::unix::fs::PermissionsExt::from_mode(0o755),
+        )
+        .unwrap();
+        let runner = Runner {
+            program: program.to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        (runner, dir)
+    }
 
     #[test]
     fn test_parse_snapshots() {
@@ -165,35 +1620,84 @@ mod tests {
                 String::from("-"),
             ],
         ];
-        let snapshots = parse_snapshots(lines).unwrap();
+        let snapshots = parse_snapshots(lines, false).unwrap();
         assert_eq!(
             snapshots,
             vec![SnapshotMetadata {
                 name: String::from("first"),
-                created: chrono::DateTime::from_utc(
-                    chrono::NaiveDateTime::parse_from_str(
-                        "Sat Oct 2 09:59 2021",
-                        "%a %b %e %H:%M %Y",
-                    )
-                    .unwrap(),
-                    chrono::Utc,
-                ),
+                created: Local.ymd(2021, 10, 2).and_hms(9, 59, 0).with_timezone(&Utc),
                 used: Byte::from(13u64 * 1024 * 1024 * 1024),
             }]
         );
     }
 
+    #[test]
+    fn test_parse_creation_in() {
+        // zfs prints local time; on a host two hours ahead of UTC, that's two hours off.
+        let tz = FixedOffset::east(2 * 3600);
+        assert_eq!(
+            parse_creation_in("Sat Oct 2 09:59 2021", &tz).unwrap(),
+            Utc.ymd(2021, 10, 2).and_hms(7, 59, 0)
+        );
+        // Seconds since the epoch are the same everywhere.
+        assert_eq!(
+            parse_creation_in("1633168740", &tz).unwrap(),
+            Utc.ymd(2021, 10, 2).and_hms(9, 59, 0)
+        );
+    }
+
+    #[test]
+    fn test_parse_snapshots_protected() {
+        let text = "tank@ours\t1633168740\t0\th24\t-\n\
+                    tank@theirs\t1633168741\t0\th24\ton\n\
+                    tank@old\t1633168742\t0\th24\n";
+        let names: Vec<String> = parse_snapshots(parse_table(text), false)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, vec!["tank@ours", "tank@old"]);
+    }
+
+    #[test]
+    fn test_parse_snapshots_parsable() {
+        // As in zfs list -p: two snapshots, taken within the same minute.
+        let lines = vec![
+            vec![
+                String::from("tank@first"),
+                String::from("1633168741"),
+                String::from("14336"),
+                String::from("h24"),
+            ],
+            vec![
+                String::from("tank@second"),
+                String::from("1633168782"),
+                String::from("0"),
+                String::from("h24"),
+            ],
+        ];
+        let snapshots = parse_snapshots(lines, false).unwrap();
+        assert_eq!(snapshots[0].created, Utc.ymd(2021, 10, 2).and_hms(9, 59, 1));
+        assert_eq!(snapshots[0].used, Byte::from(14336u64));
+        assert!(snapshots[0].created < snapshots[1].created);
+
+        // So the newer one is the one that stays.
+        let policy: crate::RetentionPolicy = "h1".parse().unwrap();
+        let check = policy.check_age(&mut snapshots.clone());
+        assert_eq!(check.keep, vec![snapshots[1].clone()]);
+    }
+
     #[test]
     fn test_parse_snapshots_empty() {
         let lines = vec![];
-        let snapshots = parse_snapshots(lines).unwrap();
+        let snapshots = parse_snapshots(lines, false).unwrap();
         assert_eq!(snapshots, vec![]);
     }
 
     #[test]
     fn test_parse_snapshots_invalid_row() {
         let lines = vec![vec![String::from("unexpected")]];
-        let err = parse_snapshots(lines).unwrap_err();
+        let err = parse_snapshots(lines, false).unwrap_err();
         assert_eq!(err.to_string(), "list snapshots parse error");
     }
 
@@ -205,7 +1709,818 @@ mod tests {
             String::from("3G"),
             String::from("at.rollc.at:snapkeep=h24d30w8m6y1"),
         ]];
-        let err = parse_snapshots(lines).unwrap_err();
-        assert_eq!(err.to_string(), "input contains invalid characters");
+        let err = parse_snapshots(lines, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to parse creation '2 Oct 2021 9:52AM' for snapshot first: \
+             input contains invalid characters"
+        );
+    }
+
+    #[test]
+    fn test_parse_snapshots_invalid_used() {
+        let lines = vec![vec![
+            String::from("tank@first"),
+            String::from("Sat Oct 2 09:59 2021"),
+            String::from("lots"),
+            String::from("h24d30w8m6y1"),
+        ]];
+        let err = parse_snapshots(lines, false).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("failed to parse used 'lots' for snapshot tank@first: "));
+    }
+
+    #[test]
+    fn test_parse_snapshots_lenient_used() {
+        let lines = vec![
+            vec![
+                String::from("tank@first"),
+                String::from("1633168740"),
+                String::from("12.5 Quettabytes"),
+                String::from("h24d30w8m6y1"),
+            ],
+            vec![
+                String::from("tank@second"),
+                String::from("1633172340"),
+                String::from("1024"),
+                String::from("h24d30w8m6y1"),
+            ],
+        ];
+        let snapshots = parse_snapshots(lines, true).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].used, Byte::from_bytes(0));
+        assert_eq!(snapshots[1].used, Byte::from_bytes(1024));
+    }
+
+    #[test]
+    fn test_call_do_no_op() {
+        // If this tried to spawn anything, it would fail.
+        let runner = Runner {
+            program: String::from("/nonexistent/zfs"),
+            no_op: true,
+            ..Default::default()
+        };
+        call_do(&runner, "destroy", &["tank@first"]).unwrap();
+        let runner = Runner {
+            no_op: false,
+            ..runner
+        };
+        call_do(&runner, "destroy", &["tank@first"]).unwrap_err();
+    }
+
+    #[test]
+    fn test_script_line() {
+        let runner = Runner::default();
+        assert_eq!(
+            script_line(
+                &runner,
+                "snap",
+                &["-o", "at.rollc.at:comment=it's done", "tank@x"]
+            ),
+            r"zfs snap -o 'at.rollc.at:comment=it'\''s done' tank@x"
+        );
+        let runner = Runner {
+            host: Some(String::from("root@backup")),
+            ..runner
+        };
+        assert_eq!(
+            script_line(&runner, "destroy", &["tank/my files@x"]),
+            r"ssh -- root@backup 'zfs destroy '\''tank/my files@x'\'''"
+        );
+    }
+
+    #[test]
+    fn test_snapshot_many_tagged_no_op() {
+        let runner = Runner {
+            program: String::from("/nonexistent/zfs"),
+            no_op: true,
+            ..Default::default()
+        };
+        let snapshots = snapshot_many_tagged(
+            &runner,
+            &[("tank", Some("h24d30")), ("tank/tmp", None)],
+            &[],
+        )
+        .unwrap();
+        assert!(snapshots[0].name.ends_with("-autosnap-h24d30"));
+        assert!(snapshots[1].name.ends_with("-autosnap"));
+        assert_eq!(snapshots[0].created, snapshots[1].created);
+    }
+
+    #[test]
+    fn test_snapshot_with_spaces() {
+        // The names go to zfs as they are, one argument each, with nothing in between to
+        // split them up again.
+        let (runner, dir) =
+            fake_zfs("for arg; do echo \"$arg\"; done >> \"$(dirname \"$0\")/argv\"\necho 0\n");
+        let snapshots = snapshot_many(&runner, &["tank/my files", "tank/tmp"]).unwrap();
+        let args = fs::read_to_string(dir.join("argv")).unwrap();
+        let args: Vec<&str> = args.lines().collect();
+        assert_eq!(
+            &args[..3],
+            &["snap", &snapshots[0].name, &snapshots[1].name]
+        );
+        assert!(snapshots[0].name.starts_with("tank/my files@"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_depth_args() {
+        let runner = Runner::default();
+        assert!(runner.depth_args(1).is_empty());
+        let runner = Runner {
+            max_depth: Some(2),
+            ..runner
+        };
+        assert_eq!(runner.depth_args(0), vec!["-d", "2"]);
+        assert_eq!(runner.depth_args(1), vec!["-d", "3"]);
+    }
+
+    #[test]
+    fn test_timeout() {
+        // A zfs that hangs.
+        let (runner, dir) = fake_zfs("exec sleep 10\n");
+        let runner = Runner {
+            timeout: Some(std::time::Duration::from_millis(200)),
+            ..runner
+        };
+        let start = Instant::now();
+        let read = list_managed_datasets(&runner, &Properties::default(), &PolicyMap::default());
+        let destroy = call_do(&runner, "destroy", &["tank@first"]);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            read.unwrap_err().to_string(),
+            "zfs command timed out after 200ms"
+        );
+        assert!(destroy.is_err());
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_timeout_leaves_out_callback() {
+        // A quick zfs list, but each dataset's snapshots take long to handle; that's on
+        // us, not on zfs, and shouldn't have it killed.
+        let (runner, dir) =
+            fake_zfs("printf 'tank@a\\t1600000000\\t0\\th1\\ntank/x@a\\t1600000000\\t0\\th1\\n'\n");
+        let runner = Runner {
+            timeout: Some(std::time::Duration::from_millis(200)),
+            ..runner
+        };
+        let mut groups = 0;
+        let result = for_each_snapshot_group(
+            &runner,
+            &Properties::default(),
+            &PolicyMap::default(),
+            |_| {
+                thread::sleep(std::time::Duration::from_millis(300));
+                groups += 1;
+                Ok(())
+            },
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+        assert_eq!(groups, 2);
+    }
+
+    #[test]
+    fn test_cache() {
+        // A zfs that counts how many times it was run.
+        let (runner, dir) = fake_zfs(
+            "echo run >> \"$(dirname \"$0\")/runs\"\n\
+             printf 'tank@first\\t1633168740\\t0\\th24\\t-\\n'\n",
+        );
+        let runner = Runner {
+            cache_ttl: Some(std::time::Duration::from_secs(60)),
+            cache_dir: dir.join("cache"),
+            ..runner
+        };
+        let first = list_snapshots(&runner, &Properties::default(), &PolicyMap::default()).unwrap();
+        let mut streamed = vec![];
+        for_each_snapshot_group(
+            &runner,
+            &Properties::default(),
+            &PolicyMap::default(),
+            |group| {
+                streamed.extend(group.into_iter().map(|(s, _)| s));
+                Ok(())
+            },
+        )
+        .unwrap();
+        let second =
+            list_snapshots(&runner, &Properties::default(), &PolicyMap::default()).unwrap();
+        let runs = fs::read_to_string(dir.join("runs")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(first.len(), 1);
+        assert_eq!(first, second);
+        assert_eq!(first, streamed);
+        // Once for each of the two listings (sorted differently), then from the cache.
+        assert_eq!(runs.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_permission_hint() {
+        let stderr = "cannot create snapshot 'tank/home@x': permission denied\n";
+        let hint = permission_hint(stderr, &["-o", "a=b", "tank/home@x"]).unwrap();
+        assert!(hint.contains("snapshot,destroy,mount,userprop tank`"));
+        assert_eq!(
+            permission_hint("cannot open 'x': no such pool\n", &["x"]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_snap_type() {
+        assert_eq!(parse_snap_type("pre-deploy").unwrap(), "pre-deploy");
+        assert_eq!(parse_snap_type("v2").unwrap(), "v2");
+        for x in ["", "Pre-Deploy", "pre deploy", "a@b", "a/b", "h=24"].iter() {
+            assert_eq!(
+                parse_snap_type(x).unwrap_err().to_string(),
+                format!("invalid snapshot type '{}'", x)
+            );
+        }
+    }
+
+    #[test]
+    fn test_snapshot_tag() {
+        assert_eq!(snapshot_tag(None, None), None);
+        assert_eq!(
+            snapshot_tag(Some("pre-deploy"), None).unwrap(),
+            "pre-deploy"
+        );
+        assert_eq!(snapshot_tag(None, Some("h24d30")).unwrap(), "h24d30");
+        let tag = snapshot_tag(Some("pre-deploy"), Some("h24d30")).unwrap();
+        assert_eq!(tag, "pre-deploy-h24d30");
+        let runner = Runner {
+            program: String::from("/nonexistent/zfs"),
+            no_op: true,
+            ..Default::default()
+        };
+        let snapshots = snapshot_many_tagged(&runner, &[("tank", Some(&tag))], &[]).unwrap();
+        let name = &snapshots[0].name;
+        assert!(name.ends_with("-autosnap-pre-deploy-h24d30"), "{}", name);
+        assert!(is_autosnap_name(name));
+    }
+
+    #[test]
+    fn test_is_autosnap_name() {
+        assert!(is_autosnap_name("tank@2021-10-02T09:59:00Z-autosnap"));
+        assert!(is_autosnap_name(
+            "tank@2021-10-02T09:59:00Z-autosnap-h24d30"
+        ));
+        assert!(!is_autosnap_name("tank@before-upgrade"));
+        assert!(!is_autosnap_name("tank/autosnap-data@manual"));
+    }
+
+    #[test]
+    fn test_name_timestamp() {
+        assert_eq!(
+            name_timestamp("tank@2021-10-02T09:59:00Z-autosnap-h24d30", false),
+            Some(Utc.ymd(2021, 10, 2).and_hms(9, 59, 0))
+        );
+        assert_eq!(name_timestamp("tank@before-upgrade", false), None);
+        assert_eq!(name_timestamp("tank@yesterday-autosnap", false), None);
+        // zfs-auto-snapshot's names only with compat, as they may well be in local time.
+        let compat = "tank@zfs-auto-snap_hourly-2021-10-02-0959";
+        assert_eq!(name_timestamp(compat, false), None);
+        assert_eq!(
+            name_timestamp(compat, true),
+            Some(Utc.ymd(2021, 10, 2).and_hms(9, 59, 0))
+        );
+        assert_eq!(
+            name_timestamp("tank@zfs-auto-snap_pre-deploy-2021-10-02-0959-h24d30", true),
+            Some(Utc.ymd(2021, 10, 2).and_hms(9, 59, 0))
+        );
+        assert_eq!(
+            name_timestamp("tank@zfs-auto-snap_hourly-latest", true),
+            None
+        );
+        assert_eq!(
+            name_timestamp("tank@zfs-auto-snap_hourly-2021-13-02-0959", true),
+            None
+        );
+        let runner = Runner {
+            time_from_name: true,
+            ..Default::default()
+        };
+        let text = concat!(
+            "tank@2021-10-02T09:59:00Z-autosnap\t1\t0\th24\n",
+            "tank@manual\t2\t0\th24\n",
+            "tank@zfs-auto-snap_hourly-2021-10-02-1059\t3\t0\th24\n",
+        );
+        let created = |runner: &Runner| {
+            let mut lines = parse_table(text);
+            for line in lines.iter_mut() {
+                fill_created_column(runner, line);
+            }
+            let snapshots = parse_snapshots(lines, false).unwrap();
+            snapshots.iter().map(|s| s.created).collect::<Vec<_>>()
+        };
+        let t = Utc.ymd(2021, 10, 2).and_hms(9, 59, 0);
+        assert_eq!(
+            created(&runner),
+            vec![t, Utc.timestamp(2, 0), Utc.timestamp(3, 0)]
+        );
+        let runner = Runner {
+            compat_label: Some(String::from("hourly")),
+            ..runner
+        };
+        assert_eq!(
+            created(&runner),
+            vec![t, Utc.timestamp(2, 0), t + chrono::Duration::hours(1)]
+        );
+    }
+
+    #[test]
+    fn test_compat_label() {
+        // A zfs that has no snapshots to list.
+        let (runner, dir) = fake_zfs("exit 1\n");
+        let runner = Runner {
+            no_op: true,
+            ..runner
+        };
+        assert!(!runner.is_ours("tank@zfs-auto-snap_hourly-2021-10-02-0959"));
+        let runner = Runner {
+            compat_label: Some(String::from("hourly")),
+            ..runner
+        };
+        assert!(runner.is_ours("tank@zfs-auto-snap_hourly-2021-10-02-0959"));
+        assert!(runner.is_ours("tank@2021-10-02T09:59:00Z-autosnap"));
+        assert!(!runner.is_ours("tank@before-upgrade"));
+        let snapshots = snapshot_many_tagged(&runner, &[("tank", Some("h24"))], &[]).unwrap();
+        let name = &snapshots[0].name;
+        assert!(name.starts_with("tank@zfs-auto-snap_hourly-"), "{}", name);
+        assert!(name.ends_with("-h24"), "{}", name);
+        assert!(is_zfs_auto_snap_name(name));
+        assert!(!is_autosnap_name(name));
+
+        // Again within the same minute (unless it just turned over), it's there already.
+        fs::write(dir.join("zfs"), format!("#!/bin/sh\necho '{}'\n", name)).unwrap();
+        let again = snapshot_many_tagged(&runner, &[("tank", Some("h24"))], &[]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(again.iter().all(|s| &s.name != name));
+    }
+
+    #[test]
+    fn test_command_line() {
+        let runner = Runner::default();
+        assert_eq!(
+            command_line(&runner, "destroy", &["tank@first"]),
+            vec!["zfs", "destroy", "tank@first"]
+        );
+        assert_eq!(
+            command_line(&runner, "destroy", &["tank/my files@first"]),
+            vec!["zfs", "destroy", "tank/my files@first"]
+        );
+        let runner = Runner {
+            host: Some(String::from("root@backup")),
+            ..runner
+        };
+        assert_eq!(
+            command_line(&runner, "destroy", &["tank/it's here@first"]),
+            vec![
+                "ssh",
+                "--",
+                "root@backup",
+                "zfs destroy 'tank/it'\\''s here@first'"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_snapshot_many_no_op() {
+        let runner = Runner {
+            program: String::from("/nonexistent/zfs"),
+            no_op: true,
+            ..Default::default()
+        };
+        let snapshots = snapshot_many(&runner, &["tank/db", "tank/db/log"]).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].dataset(), "tank/db");
+        assert_eq!(snapshots[1].dataset(), "tank/db/log");
+        // One timestamp, one snapshot name for the whole group.
+        assert_eq!(
+            snapshots[0].name.split('@').nth(1),
+            snapshots[1].name.split('@').nth(1)
+        );
+        snapshot_many(&runner, &[]).unwrap_err();
+    }
+
+    #[test]
+    fn test_parse_snapshot_listing() {
+        let text = "tank@first\tSat Oct 2 09:59 2021\t13G\th24d30\n\
+                    tank@skip\tSat Oct 2 08:59 2021\t1M\t-\n\
+                    \n\
+                    tank/home@first\tSat Oct 2 09:59 2021\t0\tw4\n";
+        let rows = parse_snapshot_listing(text, false).unwrap();
+        let rows: Vec<_> = rows
+            .iter()
+            .map(|(s, snapkeep)| (s.name.as_str(), snapkeep.as_str()))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![("tank@first", "h24d30"), ("tank/home@first", "w4")]
+        );
+    }
+
+    #[test]
+    fn test_group_snapshots() {
+        let text = "tank/home@a\t1633168740\t0\tw4\n\
+                    tank/home@b\t1633172340\t0\tw4\n\
+                    tank@a\t1633168740\t0\th24\n\
+                    tank@skip\t1633168740\t0\t-\n\
+                    tank@b\t1633172340\t0\th24\n";
+        let mut groups = vec![];
+        group_snapshots(parse_table(text).into_iter().map(Ok), false, |group| {
+            groups.push(
+                group
+                    .iter()
+                    .map(|(s, _)| s.name.clone())
+                    .collect::<Vec<_>>(),
+            );
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            groups,
+            vec![vec!["tank/home@a", "tank/home@b"], vec!["tank@a", "tank@b"]]
+        );
+    }
+
+    #[test]
+    fn test_group_snapshots_unsorted() {
+        let text = "tank@a\t1633168740\t0\th24\n\
+                    tank/home@a\t1633168740\t0\tw4\n\
+                    tank@b\t1633172340\t0\th24\n";
+        let result = group_snapshots(parse_table(text).into_iter().map(Ok), false, |_| Ok(()));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "snapshots of tank are not listed together"
+        );
+    }
+
+    #[test]
+    fn test_parse_property_values() {
+        let text = "tank\t1h\ntank/archive\t1d\nother\t-\n";
+        let values = parse_property_values(parse_table(text)).unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values["tank/archive"], "1d");
+        assert!(parse_property_values(parse_table("tank\n")).is_err());
+    }
+
+    #[test]
+    fn test_parse_property_sources() {
+        let text = "tank\th24d30\tlocal\n\
+                    tank/home\th24d30\tinherited from tank\n\
+                    tank/recv\tw4\treceived\n\
+                    other\t-\t-\n";
+        assert_eq!(
+            parse_property_sources(parse_table(text)).unwrap(),
+            vec![
+                (String::from("tank"), String::from("local")),
+                (
+                    String::from("tank/home"),
+                    String::from("inherited from tank")
+                ),
+                (String::from("tank/recv"), String::from("received")),
+            ]
+        );
+        assert!(parse_property_sources(vec![vec![String::from("tank")]]).is_err());
+    }
+
+    #[test]
+    fn test_parse_clones() {
+        let text = "tank\t-\ntank/scratch\ttank/home@2021-10-02T09:59:00Z-autosnap\n";
+        assert_eq!(
+            parse_clones(parse_table(text)).unwrap(),
+            vec![(
+                String::from("tank/scratch"),
+                String::from("tank/home@2021-10-02T09:59:00Z-autosnap")
+            )]
+        );
+        assert!(parse_clones(vec![vec![String::from("tank")]]).is_err());
+    }
+
+    #[test]
+    fn test_parse_pinned() {
+        // tank@third's - is inherited, e.g. from a dataset the policy map covers.
+        let text = "tank@first\t1633168740\t0\t-\ntank@second\t1633168800\t0\th24\n\
+                    tank@third\t1633168860\t0\t-\n";
+        let set = HashSet::from(["tank@first".to_string(), "tank@second".to_string()]);
+        let pinned = parse_pinned(parse_table(text), &set).unwrap();
+        assert_eq!(pinned.len(), 1);
+        assert_eq!(pinned[0].name, "tank@first");
+        assert!(parse_pinned(parse_table("tank@first\t1633168740\n"), &set).is_err());
+    }
+
+    #[test]
+    fn test_set_property_no_op() {
+        let runner = Runner {
+            program: String::from("/nonexistent/zfs"),
+            no_op: true,
+            ..Default::default()
+        };
+        set_property(&runner, "tank@first", "at.rollc.at:snapkeep", "-").unwrap();
+    }
+
+    #[test]
+    fn test_batch_arg() {
+        let ranges = vec![String::from("tank@a%c"), String::from("tank@e")];
+        assert_eq!(batch_arg(&ranges), "tank@a%c,e");
+        assert_eq!(batch_arg(&ranges[1..]), "tank@e");
+    }
+
+    #[test]
+    fn test_parse_reclaim() {
+        let text = "destroy\ttank@a\ndestroy\ttank@b\nreclaim\t14336\n";
+        assert_eq!(parse_reclaim(text).unwrap(), 14336);
+        assert!(parse_reclaim("destroy\ttank@a\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_bookmarks() {
+        let text = "tank#2021-10-02T09:59:00Z-autosnap\ntank/home#sent\n";
+        assert_eq!(
+            parse_bookmarks(parse_table(text)).unwrap(),
+            vec![
+                (
+                    String::from("tank@2021-10-02T09:59:00Z-autosnap"),
+                    String::from("tank#2021-10-02T09:59:00Z-autosnap")
+                ),
+                (
+                    String::from("tank/home@sent"),
+                    String::from("tank/home#sent")
+                )
+            ]
+        );
+        assert!(parse_bookmarks(parse_table("tank@first\n")).is_err());
+    }
+
+    #[test]
+    fn test_parse_sizes() {
+        let text = "tank@first\t14336\ntank@second\t0\n";
+        let written = parse_sizes(parse_table(text), "written").unwrap();
+        assert_eq!(written["tank@first"], Byte::from_bytes(14336));
+        assert_eq!(written["tank@second"], Byte::from_bytes(0));
+        let err = parse_sizes(parse_table("tank\tlots\n"), "usedbysnapshots").unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("failed to parse usedbysnapshots 'lots' for tank"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_parse_snapshot_values() {
+        let text = "tank@first\tpre kernel upgrade\ntank@second\t-\n";
+        let comments = parse_snapshot_values(parse_table(text)).unwrap();
+        assert_eq!(comments["tank@first"], "pre kernel upgrade");
+        assert!(!comments.contains_key("tank@second"));
+    }
+
+    #[test]
+    fn test_parse_managed_datasets() {
+        let lines = vec![
+            // name, snapkeep, snapdisable
+            vec![
+                String::from("tank"),
+                String::from("h24d30w8m6y1"),
+                String::from("-"),
+            ],
+            vec![
+                String::from("tank/paused"),
+                String::from("h24d30w8m6y1"),
+                String::from("on"),
+            ],
+            vec![
+                String::from("tank/unmanaged"),
+                String::from("-"),
+                String::from("-"),
+            ],
+        ];
+        let datasets = parse_managed_datasets(lines).unwrap();
+        assert_eq!(
+            datasets,
+            vec![
+                ManagedDataset {
+                    name: String::from("tank"),
+                    snapkeep: String::from("h24d30w8m6y1"),
+                    snapdisable: false,
+                    excluded: false,
+                },
+                ManagedDataset {
+                    name: String::from("tank/paused"),
+                    snapkeep: String::from("h24d30w8m6y1"),
+                    snapdisable: true,
+                    excluded: false,
+                },
+            ]
+        );
+        // With an exclude_property column.
+        let text = "tank\th24\t-\t-\ntank/scratch\th24\t-\ttrue\ntank/off\t-\t-\ttrue\n";
+        let datasets = parse_managed_datasets(parse_table(text)).unwrap();
+        let excluded: Vec<(&str, bool)> = datasets
+            .iter()
+            .map(|ds| (ds.name.as_str(), ds.excluded))
+            .collect();
+        assert_eq!(excluded, vec![("tank", false), ("tank/scratch", true)]);
+        assert!(parse_managed_datasets(parse_table("tank\th24\t-\t-\t-\n")).is_err());
+    }
+
+    #[test]
+    fn test_parse_off() {
+        // The parent is managed, the child is explicitly not, and the grandchild inherits
+        // that from the child.
+        let text = "tank\th24d30\t-\ntank/scratch\toff\t-\ntank/scratch/tmp\toff\t-\n";
+        let datasets = parse_managed_datasets(parse_table(text)).unwrap();
+        let names: Vec<&str> = datasets.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, vec!["tank"]);
+        let text = "tank@a\t1633168740\t0\th24d30\ntank/scratch@a\t1633168740\t0\toff\n";
+        let snapshots = parse_snapshots(parse_table(text), false).unwrap();
+        let names: Vec<&str> = snapshots.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["tank@a"]);
+    }
+
+    #[test]
+    fn test_snapshot_candidates() {
+        let dataset = |name: &str, snapkeep: &str, snapdisable| ManagedDataset {
+            name: String::from(name),
+            snapkeep: String::from(snapkeep),
+            snapdisable,
+            excluded: false,
+        };
+        let datasets = vec![
+            dataset("tank", "h24d30w8m6y1", false),
+            dataset("tank/paused", "h24d30w8m6y1", true),
+            ManagedDataset {
+                excluded: true,
+                ..dataset("tank/skip", "h24d30w8m6y1", false)
+            },
+            dataset("tank/typo", "hourly=24,dialy=30", false),
+            dataset("tank/preset", "@daily-backup", false),
+        ];
+        assert_eq!(
+            snapshot_candidates(datasets),
+            vec![String::from("tank"), String::from("tank/preset")]
+        );
+    }
+
+    #[test]
+    fn test_parse_health() {
+        assert_eq!(parse_health(parse_table("DEGRADED\n")).unwrap(), "DEGRADED");
+        assert!(parse_health(parse_table("")).is_err());
+    }
+
+    #[test]
+    fn test_fill_snapkeep() {
+        let map = PolicyMap::parse("tank/db/* => h24d30").unwrap();
+        // Both datasets have no snapkeep of their own, nor inherited; tank/db/main@kept
+        // was marked to be retained, with snapkeep=- on the snapshot itself.
+        let datasets = parse_table("tank/db/main\t-\t-\ntank/db/set\td7\tlocal\ntank/home\t-\t-\n");
+        let defaults = PolicyDefaults {
+            map: Some(&map),
+            unset: unset_names(&datasets),
+            set_on_snapshot: HashSet::from(["tank/db/main@kept".to_string()]),
+        };
+        let mut line = parse_table("tank/db/main@first\t1633168740\t0\t-\t-\n").remove(0);
+        fill_snapkeep_column(&defaults, &mut line, 3);
+        assert_eq!(line[3], "h24d30");
+        let mut line = parse_table("tank/db/main@kept\t1633168740\t0\t-\t-\n").remove(0);
+        fill_snapkeep_column(&defaults, &mut line, 3);
+        assert_eq!(line[3], "-");
+        // A snapkeep that's set wins, even if it's off.
+        for snapkeep in ["off", "d7"] {
+            let mut line = vec![String::from("tank/db/set"), String::from(snapkeep)];
+            fill_snapkeep_column(&defaults, &mut line, 1);
+            assert_eq!(line[1], snapkeep);
+        }
+        // Set to - somewhere up the hierarchy, rather than not at all.
+        let mut line = vec![String::from("tank/db/other"), String::from("-")];
+        fill_snapkeep_column(&defaults, &mut line, 1);
+        assert_eq!(line[1], "-");
+        // Not in the map.
+        let mut line = vec![String::from("tank/home"), String::from("-")];
+        fill_snapkeep_column(&defaults, &mut line, 1);
+        assert_eq!(line[1], "-");
+    }
+
+    #[test]
+    fn test_is_missing() {
+        assert!(is_missing(
+            "could not find any snapshots to destroy; check snapshot names.\n"
+        ));
+        assert!(is_missing(
+            "cannot open 'tank/gone': dataset does not exist\n"
+        ));
+        assert!(!is_missing(
+            "cannot destroy snapshot tank@a: dataset is busy\n"
+        ));
+    }
+
+    #[test]
+    fn test_parse_diff() {
+        let text = "M\t/tank/home/\n\
+                    +\t/tank/home/notes.txt\n\
+                    M\t/tank/home/todo.txt\n\
+                    -\t/tank/home/old.txt\n\
+                    R\t/tank/home/a.txt\t/tank/home/b.txt\n";
+        assert_eq!(
+            parse_diff(parse_table(text)).unwrap(),
+            DiffSummary {
+                modified: 2,
+                added: 1,
+                removed: 1,
+                renamed: 1
+            }
+        );
+        assert_eq!(parse_diff(vec![]).unwrap(), DiffSummary::default());
+        assert!(parse_diff(parse_table("?\t/tank/x\n")).is_err());
+    }
+
+    #[test]
+    fn test_diff_summary_failed() {
+        // A zfs diff that gets some of the way, then fails.
+        let (runner, dir) = fake_zfs("printf 'M\\t/tank/a\\n'\nexit 1\n");
+        let diff = diff_summary(&runner, "tank@a", "tank@b");
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            diff.unwrap_err().to_string(),
+            "zfs diff tank@a tank@b failed"
+        );
+    }
+
+    #[test]
+    fn test_parse_holds() {
+        let lines = vec![
+            // name, tag, timestamp
+            vec![
+                String::from("tank@first"),
+                String::from("backup"),
+                String::from("Sat Oct  2 09:59 2021"),
+            ],
+            vec![
+                String::from("tank@first"),
+                String::from("keep"),
+                String::from("Sat Oct  2 10:03 2021"),
+            ],
+        ];
+        let holds = parse_holds(lines).unwrap();
+        assert_eq!(
+            holds,
+            vec![
+                Hold {
+                    snapshot: String::from("tank@first"),
+                    tag: String::from("backup"),
+                },
+                Hold {
+                    snapshot: String::from("tank@first"),
+                    tag: String::from("keep"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_destroy_ranges() {
+        let snapshots: Vec<SnapshotMetadata> = ["a", "b", "c", "d", "e", "f", "g"]
+            .iter()
+            .map(|name| SnapshotMetadata {
+                name: format!("tank@{}", name),
+                created: Utc.timestamp(0, 0),
+                used: Byte::from_bytes(0),
+            })
+            .collect();
+        let delete: HashSet<String> = ["a", "b", "c", "d", "f", "g"]
+            .iter()
+            .map(|name| format!("tank@{}", name))
+            .collect();
+        assert_eq!(
+            destroy_ranges(&snapshots, &delete, &[]),
+            vec!["tank@a%d", "tank@f%g"]
+        );
+        // A held snapshot splits the range, even though it's to be deleted.
+        let holds = vec![
+            Hold {
+                snapshot: String::from("tank@b"),
+                tag: String::from("backup"),
+            },
+            Hold {
+                snapshot: String::from("tank@g"),
+                tag: String::from("backup"),
+            },
+        ];
+        assert_eq!(
+            destroy_ranges(&snapshots, &delete, &holds),
+            vec!["tank@a", "tank@c%d", "tank@f"]
+        );
+        assert!(destroy_ranges(&snapshots, &HashSet::new(), &holds).is_empty());
+    }
+
+    #[test]
+    fn test_parse_holds_invalid_row() {
+        let lines = vec![vec![String::from("tank@first")]];
+        let err = parse_holds(lines).unwrap_err();
+        assert_eq!(err.to_string(), "list holds parse error");
     }
 }
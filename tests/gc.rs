@@ -0,0 +1,75 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+// A zfs that logs how it's called, with two datasets of two hourly snapshots each; gc
+// would destroy the older one of each.
+const FAKE_ZFS: &str = r#"#!/bin/sh
+echo "$*" >> "$(dirname "$0")/log"
+case "$*" in
+  "list -H -p -s name -t snapshot"*)
+    printf 'tank@a\t1600000000\t100\th1\t-\ntank@b\t1600003600\t0\th1\t-\n'
+    printf 'tank/x@a\t1600000000\t100\th1\t-\ntank/x@b\t1600003600\t0\th1\t-\n' ;;
+  "get -H -o value at.rollc.at:snapkeep"*) echo h1 ;;
+  "get -H -t filesystem,volume"*) ;;
+  "get"*) echo - ;;
+esac
+"#;
+
+fn gc(name: &str, args: &[&str]) -> (String, Vec<String>) {
+    // Run gc against the fake zfs; what it printed, and which zfs commands it ran.
+    let dir: PathBuf = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let program = dir.join("zfs");
+    std::fs::write(&program, FAKE_ZFS).unwrap();
+    std::fs::set_permissions(
+        &program,
+        std::os::unix::fs::PermissionsExt::from_mode(0o755),
+    )
+    .unwrap();
+    let path = format!("{}:{}", dir.display(), std::env::var("PATH").unwrap());
+    let state_file = dir.join("state.json");
+    let output = Command::new(env!("CARGO_BIN_EXE_zfs-autosnap"))
+        .env("PATH", path)
+        .arg("--state-file")
+        .arg(&state_file)
+        .arg("gc")
+        .args(args)
+        .output()
+        .unwrap();
+    let log = std::fs::read_to_string(dir.join("log")).unwrap_or_default();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    (
+        String::from_utf8(output.stdout).unwrap(),
+        log.lines().map(String::from).collect(),
+    )
+}
+
+#[test]
+fn test_gc_stream() {
+    let (out, log) = gc("gc-stream", &["--stream", "--assume-yes"]);
+    assert!(out.contains("delete: tank@a\t"));
+    assert!(out.contains("delete: tank/x@a\t"));
+    assert!(log.contains(&String::from("destroy tank@a")));
+    assert!(log.contains(&String::from("destroy tank/x@a")));
+}
+
+#[test]
+fn test_gc_stream_past_deadline() {
+    // Past the deadline, nothing's destroyed, and the datasets after the first aren't even
+    // looked at.
+    let (out, log) = gc(
+        "gc-deadline",
+        &["--stream", "--assume-yes", "--deadline", "0s"],
+    );
+    assert!(out.contains("deadline: stopped after 0 snapshots, deferring 1 (100 B)"));
+    assert!(!log.iter().any(|line| line.starts_with("destroy")));
+    assert!(!log.iter().any(|line| line.ends_with(" tank/x")));
+}
+
+#[test]
+fn test_gc_past_deadline() {
+    let (out, log) = gc("gc-deadline-all", &["--assume-yes", "--deadline", "0s"]);
+    assert!(out.contains("deadline: stopped after 0 snapshots, deferring 2 (200 B)"));
+    assert!(!log.iter().any(|line| line.starts_with("destroy")));
+}
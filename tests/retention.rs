@@ -0,0 +1,36 @@
+// Run with: cargo test --features testing
+#![cfg(feature = "testing")]
+
+use chrono::{Duration, TimeZone, Utc};
+use std::str::FromStr;
+use zfs_autosnap::prelude::*;
+use zfs_autosnap::testing;
+
+#[test]
+fn test_check_age_from_outside() {
+    let start = Utc.ymd(2021, 10, 2).and_hms(0, 0, 0);
+    let mut snapshots = testing::snapshots_every("tank", start, Duration::hours(1), 48);
+    let policy = RetentionPolicy::from_str("h24d7").unwrap();
+    let check = policy.check_age(&mut snapshots);
+    assert_eq!(check.keep.len(), 25);
+    assert_eq!(check.keep[0].name, "tank@47");
+    assert_eq!(
+        testing::rules(&policy)[0],
+        (String::from("%Y-%m-%d %H"), Some(24))
+    );
+}
+
+#[test]
+fn test_parse_snapshots_from_outside() {
+    let text = "tank@first\t1633168740\t14336\th24\ntank@kept\t1633168800\t0\t-\n";
+    let snapshots = testing::parse_snapshots(text, false).unwrap();
+    assert_eq!(
+        snapshots,
+        vec![SnapshotMetadata::new(
+            "tank@first",
+            Utc.timestamp(1633168740, 0),
+            14336
+        )]
+    );
+    assert_eq!(testing::parse_used("14K").unwrap().get_bytes(), 14336);
+}